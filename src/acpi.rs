@@ -0,0 +1,585 @@
+use core::ptr::read_unaligned;
+
+use crate::paging::{self, PageFlags};
+use crate::sync::Once;
+
+// The RSDP sits on a 16-byte boundary in the first KB of the EBDA or in the
+// BIOS area below 1 MB
+const EBDA_POINTER: usize = 0x40E;
+const BIOS_AREA_START: usize = 0xE0000;
+const BIOS_AREA_END: usize = 0x100000;
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_LEN: usize = 20;
+
+const SDT_HEADER_LEN: usize = 36;
+
+pub const MAX_CPUS: usize = 16;
+pub const MAX_IO_APICS: usize = 4;
+pub const MAX_OVERRIDES: usize = 16;
+
+// MADT entry types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS: u8 = 5;
+
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// A table listed in the RSDT/XSDT
+#[derive(Clone, Copy)]
+pub struct TableInfo {
+    pub address: u32,
+    pub header: SdtHeader,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+
+    fn parse(bytes: &[u8], offset: usize) -> Self {
+        Self {
+            address_space: field_u8(bytes, offset),
+            bit_width: field_u8(bytes, offset + 1),
+            bit_offset: field_u8(bytes, offset + 2),
+            access_size: field_u8(bytes, offset + 3),
+            address: field_u64(bytes, offset + 4),
+        }
+    }
+}
+
+/// Fixed ACPI Description Table; fields past the end of an old, short
+/// table read as zero
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub revision: u8,
+    pub dsdt: u32,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub century_register: u8,
+    pub boot_architecture_flags: u16,
+    pub flags: u32,
+    pub reset_register: GenericAddress,
+    pub reset_value: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// Maps an ISA IRQ onto a different global system interrupt
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0x3 == 0x3
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0x3 == 0x3
+    }
+}
+
+/// Multiple APIC Description Table
+#[derive(Clone, Copy)]
+pub struct Madt {
+    pub local_apic_address: u32,
+    pub flags: u32,
+    cpus: [Option<LocalApic>; MAX_CPUS],
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+}
+
+impl Madt {
+    pub const PCAT_COMPAT: u32 = 1 << 0;
+
+    pub fn cpus(&self) -> impl Iterator<Item = &LocalApic> {
+        self.cpus.iter().flatten()
+    }
+
+    pub fn io_apics(&self) -> impl Iterator<Item = &IoApic> {
+        self.io_apics.iter().flatten()
+    }
+
+    pub fn overrides(&self) -> impl Iterator<Item = &InterruptOverride> {
+        self.overrides.iter().flatten()
+    }
+
+    /// The GSI an ISA IRQ arrives on, honouring any override
+    pub fn isa_irq_to_gsi(&self, irq: u8) -> u32 {
+        self.overrides()
+            .find(|o| o.source == irq)
+            .map(|o| o.gsi)
+            .unwrap_or(irq as u32)
+    }
+}
+
+/// High Precision Event Timer description
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    pub comparator_count: u8,
+    pub counter_64bit: bool,
+    pub vendor_id: u16,
+    pub base_address: GenericAddress,
+    pub number: u8,
+    pub minimum_tick: u16,
+}
+
+#[derive(Clone, Copy)]
+pub struct RootInfo {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub address: u32,
+    pub extended: bool,
+}
+
+static ROOT: Once<RootInfo> = Once::new();
+static FADT: Once<Fadt> = Once::new();
+static MADT: Once<Madt> = Once::new();
+static HPET: Once<Hpet> = Once::new();
+
+fn checksum_ok(addr: usize, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn field_u8(bytes: &[u8], offset: usize) -> u8 {
+    bytes.get(offset).copied().unwrap_or(0)
+}
+
+fn field_u16(bytes: &[u8], offset: usize) -> u16 {
+    field_u8(bytes, offset) as u16 | (field_u8(bytes, offset + 1) as u16) << 8
+}
+
+fn field_u32(bytes: &[u8], offset: usize) -> u32 {
+    field_u16(bytes, offset) as u32 | (field_u16(bytes, offset + 2) as u32) << 16
+}
+
+fn field_u64(bytes: &[u8], offset: usize) -> u64 {
+    field_u32(bytes, offset) as u64 | (field_u32(bytes, offset + 4) as u64) << 32
+}
+
+fn scan_for_rsdp(start: usize, end: usize) -> Option<usize> {
+    let mut addr = start;
+    while addr + RSDP_V1_LEN <= end {
+        let signature = unsafe { &*(addr as *const [u8; 8]) };
+        if signature == RSDP_SIGNATURE && checksum_ok(addr, RSDP_V1_LEN) {
+            return Some(addr);
+        }
+        addr += 16;
+    }
+    None
+}
+
+fn find_rsdp() -> Option<usize> {
+    let ebda = unsafe { read_unaligned(EBDA_POINTER as *const u16) as usize } << 4;
+    if ebda != 0 {
+        if let Some(addr) = scan_for_rsdp(ebda, ebda + 1024) {
+            return Some(addr);
+        }
+    }
+    scan_for_rsdp(BIOS_AREA_START, BIOS_AREA_END)
+}
+
+/// Firmware tables usually live at the top of RAM, outside the kernel's
+/// identity map
+fn map_physical(addr: u32, len: u32) -> Result<(), &'static str> {
+    if !paging::is_enabled() {
+        return Ok(());
+    }
+    let end = addr.checked_add(len).ok_or("ACPI table out of range")?;
+    paging::identity_map(addr, end, PageFlags::KERNEL_RW)
+}
+
+/// Maps a table, checks its checksum and returns its header
+fn load_table(addr: u32) -> Result<&'static SdtHeader, &'static str> {
+    map_physical(addr, SDT_HEADER_LEN as u32)?;
+    let header = unsafe { &*(addr as *const SdtHeader) };
+    let length = header.length;
+    if (length as usize) < SDT_HEADER_LEN {
+        return Err("Truncated ACPI table");
+    }
+    map_physical(addr, length)?;
+    if !checksum_ok(addr as usize, length as usize) {
+        return Err("Bad ACPI table checksum");
+    }
+    Ok(header)
+}
+
+/// A loaded table's bytes, header included
+fn table_bytes(addr: u32) -> Result<&'static [u8], &'static str> {
+    let header = load_table(addr)?;
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, header.length as usize) })
+}
+
+pub fn root() -> Option<&'static RootInfo> {
+    ROOT.get()
+}
+
+/// Iterates over the tables listed in the RSDT or XSDT
+pub fn tables() -> Tables {
+    match root() {
+        Some(root) => {
+            let length = unsafe { (*(root.address as *const SdtHeader)).length } as usize;
+            let entry_size = if root.extended { 8 } else { 4 };
+            Tables {
+                root: root.address as usize,
+                entry_size,
+                index: 0,
+                count: (length - SDT_HEADER_LEN) / entry_size,
+            }
+        }
+        None => Tables {
+            root: 0,
+            entry_size: 4,
+            index: 0,
+            count: 0,
+        },
+    }
+}
+
+pub struct Tables {
+    root: usize,
+    entry_size: usize,
+    index: usize,
+    count: usize,
+}
+
+impl Iterator for Tables {
+    type Item = TableInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.count {
+            let entry = self.root + SDT_HEADER_LEN + self.index * self.entry_size;
+            self.index += 1;
+
+            let address = if self.entry_size == 8 {
+                unsafe { read_unaligned(entry as *const u64) }
+            } else {
+                unsafe { read_unaligned(entry as *const u32) as u64 }
+            };
+            // A 32-bit kernel cannot reach tables above 4 GiB
+            if address > u32::MAX as u64 {
+                continue;
+            }
+
+            let address = address as u32;
+            if let Ok(header) = load_table(address) {
+                return Some(TableInfo { address, header: *header });
+            }
+        }
+        None
+    }
+}
+
+/// Physical address of the first table with `signature`
+pub fn find_table(signature: &[u8; 4]) -> Option<u32> {
+    tables().find(|t| &t.header.signature == signature).map(|t| t.address)
+}
+
+fn parse_fadt(bytes: &[u8]) -> Fadt {
+    Fadt {
+        revision: field_u8(bytes, 8),
+        dsdt: field_u32(bytes, 40),
+        sci_interrupt: field_u16(bytes, 46),
+        smi_command_port: field_u32(bytes, 48),
+        acpi_enable: field_u8(bytes, 52),
+        acpi_disable: field_u8(bytes, 53),
+        pm1a_event_block: field_u32(bytes, 56),
+        pm1a_control_block: field_u32(bytes, 64),
+        pm1b_control_block: field_u32(bytes, 68),
+        pm_timer_block: field_u32(bytes, 76),
+        century_register: field_u8(bytes, 108),
+        boot_architecture_flags: field_u16(bytes, 109),
+        flags: field_u32(bytes, 112),
+        reset_register: GenericAddress::parse(bytes, 116),
+        reset_value: field_u8(bytes, 128),
+    }
+}
+
+fn parse_madt(bytes: &[u8]) -> Madt {
+    let mut madt = Madt {
+        local_apic_address: field_u32(bytes, 36),
+        flags: field_u32(bytes, 40),
+        cpus: [None; MAX_CPUS],
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; MAX_OVERRIDES],
+    };
+
+    let mut offset = 44;
+    while offset + 2 <= bytes.len() {
+        let kind = bytes[offset];
+        let len = bytes[offset + 1] as usize;
+        if len < 2 || offset + len > bytes.len() {
+            break;
+        }
+        let entry = &bytes[offset..offset + len];
+
+        match kind {
+            MADT_LOCAL_APIC => {
+                let flags = field_u32(entry, 4);
+                // Disabled entries that are not online-capable will never run
+                if flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                    let cpu = LocalApic {
+                        processor_id: field_u8(entry, 2),
+                        apic_id: field_u8(entry, 3),
+                        enabled: flags & LOCAL_APIC_ENABLED != 0,
+                    };
+                    if let Some(slot) = madt.cpus.iter_mut().find(|s| s.is_none()) {
+                        *slot = Some(cpu);
+                    }
+                }
+            }
+            MADT_IO_APIC => {
+                let io_apic = IoApic {
+                    id: field_u8(entry, 2),
+                    address: field_u32(entry, 4),
+                    gsi_base: field_u32(entry, 8),
+                };
+                if let Some(slot) = madt.io_apics.iter_mut().find(|s| s.is_none()) {
+                    *slot = Some(io_apic);
+                }
+            }
+            MADT_INTERRUPT_OVERRIDE => {
+                let over = InterruptOverride {
+                    source: field_u8(entry, 3),
+                    gsi: field_u32(entry, 4),
+                    flags: field_u16(entry, 8),
+                };
+                if let Some(slot) = madt.overrides.iter_mut().find(|s| s.is_none()) {
+                    *slot = Some(over);
+                }
+            }
+            MADT_LOCAL_APIC_ADDRESS => {
+                let address = field_u64(entry, 4);
+                if address <= u32::MAX as u64 {
+                    madt.local_apic_address = address as u32;
+                }
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    madt
+}
+
+fn parse_hpet(bytes: &[u8]) -> Hpet {
+    let block_id = field_u32(bytes, 36);
+    Hpet {
+        hardware_revision: (block_id & 0xFF) as u8,
+        comparator_count: ((block_id >> 8) & 0x1F) as u8 + 1,
+        counter_64bit: block_id & (1 << 13) != 0,
+        vendor_id: (block_id >> 16) as u16,
+        base_address: GenericAddress::parse(bytes, 40),
+        number: field_u8(bytes, 52),
+        minimum_tick: field_u16(bytes, 53),
+    }
+}
+
+/// Locates the RSDP, validates the RSDT/XSDT and parses the tables the
+/// kernel understands
+pub fn init() -> Result<(), &'static str> {
+    let rsdp_addr = find_rsdp().ok_or("No ACPI RSDP found")?;
+    let rsdp = unsafe { read_unaligned(rsdp_addr as *const Rsdp) };
+
+    // Prefer the XSDT when the extended RSDP checks out and it is reachable
+    let xsdt = rsdp.xsdt_address;
+    let extended = rsdp.revision >= 2
+        && xsdt != 0
+        && xsdt <= u32::MAX as u64
+        && checksum_ok(rsdp_addr, rsdp.length as usize);
+    let (address, signature) = if extended {
+        (xsdt as u32, b"XSDT")
+    } else {
+        (rsdp.rsdt_address, b"RSDT")
+    };
+
+    let header = load_table(address)?;
+    if &header.signature != signature {
+        return Err("RSDP does not point at a root table");
+    }
+    ROOT.call_once(|| RootInfo {
+        revision: rsdp.revision,
+        oem_id: rsdp.oem_id,
+        address,
+        extended,
+    });
+
+    let fadt = parse_fadt(table_bytes(find_table(b"FACP").ok_or("No FADT found")?)?);
+    FADT.call_once(|| fadt);
+
+    if let Some(addr) = find_table(b"APIC") {
+        let madt = parse_madt(table_bytes(addr)?);
+        MADT.call_once(|| madt);
+    }
+    if let Some(addr) = find_table(b"HPET") {
+        let hpet = parse_hpet(table_bytes(addr)?);
+        HPET.call_once(|| hpet);
+    }
+    Ok(())
+}
+
+pub fn fadt() -> Option<&'static Fadt> {
+    FADT.get()
+}
+
+pub fn madt() -> Option<&'static Madt> {
+    MADT.get()
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.get()
+}
+
+/// Sleep type values for the S5 (soft-off) state, from `\_S5_` in the DSDT
+pub fn s5_sleep_types() -> Option<(u16, u16)> {
+    let aml = &table_bytes(fadt()?.dsdt).ok()?[SDT_HEADER_LEN..];
+    parse_s5(aml)
+}
+
+/// Finds `Name(_S5_, Package() { SLP_TYPa, SLP_TYPb, ... })` without a full
+/// AML interpreter
+fn parse_s5(aml: &[u8]) -> Option<(u16, u16)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    let pos = aml.windows(4).position(|w| w == b"_S5_")?;
+    let named = pos >= 1 && aml[pos - 1] == NAME_OP
+        || pos >= 2 && aml[pos - 2] == NAME_OP && aml[pos - 1] == b'\\';
+    if !named || aml.get(pos + 4) != Some(&PACKAGE_OP) {
+        return None;
+    }
+
+    // PkgLength: the top two bits of the lead byte count the extra bytes
+    let mut i = pos + 5;
+    let lead = *aml.get(i)?;
+    i += 1 + (lead >> 6) as usize;
+    // NumElements
+    i += 1;
+
+    let mut read_value = || -> Option<u16> {
+        let mut value = *aml.get(i)?;
+        if value == BYTE_PREFIX {
+            i += 1;
+            value = *aml.get(i)?;
+        }
+        i += 1;
+        Some(value as u16)
+    };
+    let slp_typ_a = read_value()?;
+    let slp_typ_b = read_value()?;
+    Some((slp_typ_a, slp_typ_b))
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+
+    pub fn parse_s5_package() {
+        // Name (_S5_, Package (0x04) { 0x05, 0x05, Zero, Zero }) as iasl emits it
+        let aml = [
+            0x10, 0x08, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x0A, 0x04, 0x0A, 0x05, 0x0A, 0x05, 0x00,
+            0x00,
+        ];
+        assert_eq!(parse_s5(&aml), Some((5, 5)));
+
+        // Small values are encoded as ZeroOp/OneOp without a prefix
+        let aml = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(parse_s5(&aml), Some((0, 0)));
+
+        assert_eq!(parse_s5(b"no sleep states here"), None);
+    }
+
+    pub fn parse_madt_entries() {
+        let mut table = [0u8; 44 + 8 + 8 + 12 + 10];
+        table[36..40].copy_from_slice(&0xFEE0_0000u32.to_le_bytes());
+        table[40..44].copy_from_slice(&Madt::PCAT_COMPAT.to_le_bytes());
+        // Two local APICs, the second disabled and not online-capable
+        table[44..52].copy_from_slice(&[MADT_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+        table[52..60].copy_from_slice(&[MADT_LOCAL_APIC, 8, 1, 1, 0, 0, 0, 0]);
+        // IOAPIC 2 at 0xFEC00000, GSI base 0
+        table[60..72].copy_from_slice(&[MADT_IO_APIC, 12, 2, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+        // ISA IRQ 0 -> GSI 2
+        table[72..82].copy_from_slice(&[MADT_INTERRUPT_OVERRIDE, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+
+        let madt = parse_madt(&table);
+        assert_eq!(madt.local_apic_address, 0xFEE0_0000);
+        assert_eq!(madt.cpus().count(), 1);
+        let io_apic = madt.io_apics().next().expect("IOAPIC missing");
+        assert_eq!((io_apic.id, io_apic.address, io_apic.gsi_base), (2, 0xFEC0_0000, 0));
+        assert_eq!(madt.isa_irq_to_gsi(0), 2);
+        assert_eq!(madt.isa_irq_to_gsi(1), 1);
+    }
+
+    pub fn short_fadt_reads_as_zero() {
+        let mut table = [0u8; 116];
+        table[46..48].copy_from_slice(&9u16.to_le_bytes());
+        table[108] = 0x32;
+
+        let fadt = parse_fadt(&table);
+        assert_eq!(fadt.sci_interrupt, 9);
+        assert_eq!(fadt.century_register, 0x32);
+        assert_eq!(fadt.reset_register.address, 0);
+    }
+}
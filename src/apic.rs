@@ -0,0 +1,293 @@
+use core::arch::naked_asm;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use crate::acpi;
+use crate::cpu;
+use crate::idt;
+use crate::irq;
+use crate::paging::{self, PageFlags};
+use crate::sync::Once;
+use crate::timer;
+
+// Local APIC registers, as offsets from its MMIO base
+const LAPIC_ID: u32 = 0x020;
+const LAPIC_TASK_PRIORITY: u32 = 0x080;
+const LAPIC_EOI: u32 = 0x0B0;
+const LAPIC_SPURIOUS: u32 = 0x0F0;
+const LAPIC_ERROR_STATUS: u32 = 0x280;
+const LAPIC_ICR_LOW: u32 = 0x300;
+const LAPIC_ICR_HIGH: u32 = 0x310;
+const LAPIC_LVT_TIMER: u32 = 0x320;
+const LAPIC_TIMER_INITIAL: u32 = 0x380;
+const LAPIC_TIMER_CURRENT: u32 = 0x390;
+const LAPIC_TIMER_DIVIDE: u32 = 0x3E0;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+const ICR_INIT: u32 = 0x5 << 8;
+const ICR_STARTUP: u32 = 0x6 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+
+// IOAPIC registers are reached through a select/window pair
+const IOAPIC_SELECT: u32 = 0x00;
+const IOAPIC_WINDOW: u32 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIRECT_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECT_LEVEL: u32 = 1 << 15;
+const REDIRECT_MASKED: u32 = 1 << 16;
+
+const CALIBRATION_MS: u64 = 100;
+
+#[derive(Clone, Copy)]
+struct IoApicState {
+    address: u32,
+    gsi_base: u32,
+    entries: u32,
+}
+
+static LAPIC_BASE: AtomicU32 = AtomicU32::new(0);
+static IO_APICS: Once<[Option<IoApicState>; acpi::MAX_IO_APICS]> = Once::new();
+static BSP_APIC_ID: AtomicU8 = AtomicU8::new(0);
+// LAPIC timer input clock after the divider
+static TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+static TIMER_RUNNING: AtomicBool = AtomicBool::new(false);
+static SPURIOUS_COUNT: AtomicU32 = AtomicU32::new(0);
+
+unsafe fn lapic_read(reg: u32) -> u32 {
+    read_volatile((LAPIC_BASE.load(Ordering::Relaxed) + reg) as *const u32)
+}
+
+unsafe fn lapic_write(reg: u32, value: u32) {
+    write_volatile((LAPIC_BASE.load(Ordering::Relaxed) + reg) as *mut u32, value);
+}
+
+unsafe fn ioapic_read(base: u32, reg: u32) -> u32 {
+    write_volatile((base + IOAPIC_SELECT) as *mut u32, reg);
+    read_volatile((base + IOAPIC_WINDOW) as *const u32)
+}
+
+unsafe fn ioapic_write(base: u32, reg: u32, value: u32) {
+    write_volatile((base + IOAPIC_SELECT) as *mut u32, reg);
+    write_volatile((base + IOAPIC_WINDOW) as *mut u32, value);
+}
+
+/// Register pages must bypass the cache
+fn map_registers(base: u32) -> Result<(), &'static str> {
+    if !paging::is_enabled() {
+        return Ok(());
+    }
+    paging::identity_map(base, base + 0x1000, PageFlags::KERNEL_RW | PageFlags::NO_CACHE)
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn spurious_interrupt() {
+    naked_asm!(
+        "pusha",
+        "call apic_spurious_inner",
+        "popa",
+        "iretd",
+    );
+}
+
+/// Spurious APIC interrupts must not be acknowledged
+#[no_mangle]
+pub extern "C" fn apic_spurious_inner() {
+    SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Maps and enables the local APIC and every IOAPIC in the MADT, with all
+/// redirection entries masked, and calibrates the LAPIC timer. Expects the
+/// PIT to still be ticking through the 8259
+pub fn init() -> Result<(), &'static str> {
+    if !cpu::has_feature(cpu::FEATURE_APIC) {
+        return Err("CPU has no local APIC");
+    }
+    let madt = acpi::madt().ok_or("No MADT")?;
+    if madt.io_apics().next().is_none() {
+        return Err("No IOAPIC in the MADT");
+    }
+
+    let lapic = madt.local_apic_address;
+    map_registers(lapic)?;
+
+    let mut io_apics = [None; acpi::MAX_IO_APICS];
+    for (slot, io_apic) in io_apics.iter_mut().zip(madt.io_apics()) {
+        map_registers(io_apic.address)?;
+        let entries = unsafe {
+            let entries = ((ioapic_read(io_apic.address, IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+            for i in 0..entries {
+                ioapic_write(io_apic.address, IOAPIC_REDIRECTION + i * 2, REDIRECT_MASKED);
+            }
+            entries
+        };
+        *slot = Some(IoApicState {
+            address: io_apic.address,
+            gsi_base: io_apic.gsi_base,
+            entries,
+        });
+    }
+    IO_APICS.call_once(|| io_apics);
+
+    LAPIC_BASE.store(lapic, Ordering::Relaxed);
+    idt::set_handler(SPURIOUS_VECTOR as usize, spurious_interrupt as u32);
+    enable_local();
+    BSP_APIC_ID.store(local_id(), Ordering::Relaxed);
+
+    calibrate_timer();
+    Ok(())
+}
+
+/// Turns on the calling CPU's local APIC with its timer masked
+pub fn enable_local() {
+    unsafe {
+        lapic_write(LAPIC_TASK_PRIORITY, 0);
+        lapic_write(LAPIC_SPURIOUS, SPURIOUS_VECTOR as u32 | SPURIOUS_ENABLE);
+        lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+    }
+}
+
+pub fn local_id() -> u8 {
+    unsafe { (lapic_read(LAPIC_ID) >> 24) as u8 }
+}
+
+/// Counts LAPIC timer cycles across a known number of PIT ticks
+fn calibrate_timer() {
+    let frequency = timer::frequency() as u64;
+    let ticks = (CALIBRATION_MS * frequency / 1000).max(1);
+
+    unsafe {
+        lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+
+        // Start on a tick edge so the window is whole ticks
+        let edge = timer::ticks();
+        while timer::ticks() == edge {
+            idt::wait_for_interrupt();
+        }
+
+        let start = timer::ticks();
+        lapic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+        while timer::ticks() < start + ticks {
+            idt::wait_for_interrupt();
+        }
+        let elapsed = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT);
+        lapic_write(LAPIC_TIMER_INITIAL, 0);
+
+        TIMER_HZ.store(elapsed as u64 * frequency / ticks, Ordering::Relaxed);
+    }
+}
+
+/// Bus clock reaching the LAPIC timer after its divide-by-16
+pub fn timer_frequency() -> u64 {
+    TIMER_HZ.load(Ordering::Relaxed)
+}
+
+/// Reprograms the periodic LAPIC timer if it is running
+pub fn set_timer_frequency(hz: u32) {
+    if TIMER_RUNNING.load(Ordering::Relaxed) {
+        unsafe {
+            start_timer(hz);
+        }
+    }
+}
+
+unsafe fn start_timer(hz: u32) {
+    let vector = irq::IRQ_BASE_VECTOR as u32 + irq::IRQ_TIMER as u32;
+    let count = (timer_frequency() / hz as u64).clamp(1, u32::MAX as u64) as u32;
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_LVT_TIMER, vector | TIMER_PERIODIC);
+    lapic_write(LAPIC_TIMER_INITIAL, count);
+}
+
+/// Unmasking IRQ 0 starts the LAPIC timer in place of the PIT; every other
+/// ISA IRQ is routed through the IOAPIC to the boot CPU
+pub fn set_masked(irq: u8, masked: bool) {
+    if irq == irq::IRQ_TIMER {
+        TIMER_RUNNING.store(!masked, Ordering::Relaxed);
+        unsafe {
+            if masked {
+                lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+            } else {
+                start_timer(timer::frequency());
+            }
+        }
+        return;
+    }
+
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return,
+    };
+    let gsi = madt.isa_irq_to_gsi(irq);
+    let over = madt.overrides().find(|o| o.source == irq);
+
+    let io_apic = IO_APICS.get().and_then(|io_apics| {
+        io_apics
+            .iter()
+            .flatten()
+            .find(|a| gsi >= a.gsi_base && gsi < a.gsi_base + a.entries)
+            .copied()
+    });
+    let io_apic = match io_apic {
+        Some(io_apic) => io_apic,
+        None => return,
+    };
+
+    // ISA interrupts are edge-triggered, active high unless overridden
+    let mut low = irq::IRQ_BASE_VECTOR as u32 + irq as u32;
+    if let Some(over) = over {
+        if over.active_low() {
+            low |= REDIRECT_ACTIVE_LOW;
+        }
+        if over.level_triggered() {
+            low |= REDIRECT_LEVEL;
+        }
+    }
+    if masked {
+        low |= REDIRECT_MASKED;
+    }
+
+    let reg = IOAPIC_REDIRECTION + (gsi - io_apic.gsi_base) * 2;
+    unsafe {
+        ioapic_write(io_apic.address, reg + 1, (BSP_APIC_ID.load(Ordering::Relaxed) as u32) << 24);
+        ioapic_write(io_apic.address, reg, low);
+    }
+}
+
+pub fn end_of_interrupt() {
+    unsafe {
+        lapic_write(LAPIC_EOI, 0);
+    }
+}
+
+pub fn spurious_count() -> u32 {
+    SPURIOUS_COUNT.load(Ordering::Relaxed)
+}
+
+fn send_ipi(apic_id: u8, command: u32) {
+    unsafe {
+        lapic_write(LAPIC_ERROR_STATUS, 0);
+        lapic_write(LAPIC_ICR_HIGH, (apic_id as u32) << 24);
+        lapic_write(LAPIC_ICR_LOW, command);
+        while lapic_read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Puts another CPU into its wait-for-SIPI state
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, ICR_INIT | ICR_ASSERT);
+}
+
+/// Starts a CPU waiting for SIPI in real mode at `page * 4096`
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_ASSERT | page as u32);
+}
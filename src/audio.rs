@@ -1,16 +1,46 @@
-use core::arch::asm;
+use crate::io::{inb, outb};
+use crate::sync::IrqSpinLock;
+use crate::timer::{self, TimerId, PIT_FREQUENCY};
 
 const PIT_CONTROL_PORT: u16 = 0x43;
 const PIT_CHANNEL_2: u16 = 0x42;
 const SPEAKER_PORT: u16 = 0x61;
-const PIT_FREQUENCY: u32 = 1193180;  // Base frequency for PIT
 
-/// Play a beep at a specific frequency for a duration
+// The pending timer that ends the current `beep`, so a newer beep is not
+// cut short by an older one's
+static STOP_TIMER: IrqSpinLock<Option<TimerId>> = IrqSpinLock::new(None);
+
+/// Starts a beep at a specific frequency and returns at once; the timer
+/// queue silences the speaker after `duration_ms`
 pub fn beep(frequency_hz: u16, duration_ms: u32) {
     if frequency_hz == 0 {
         return;
     }
 
+    let mut stop_timer = STOP_TIMER.lock();
+    if let Some(id) = stop_timer.take() {
+        let _ = timer::cancel(id);
+    }
+    start_tone(frequency_hz);
+    match timer::add_oneshot(duration_ms as u64, stop_tone) {
+        Ok(id) => *stop_timer = Some(id),
+        Err(_) => stop_tone(),
+    }
+}
+
+/// Play a simple melody (array of notes), returning once it has finished.
+/// A frequency of 0 is a rest
+pub fn play_melody(notes: &[(u16, u32)]) {
+    for &(frequency, duration) in notes {
+        if frequency != 0 {
+            start_tone(frequency);
+        }
+        timer::sleep_ms(duration as u64);
+        stop_tone();
+    }
+}
+
+fn start_tone(frequency_hz: u16) {
     let divisor = (PIT_FREQUENCY / frequency_hz as u32) as u16;
 
     unsafe {
@@ -25,10 +55,9 @@ pub fn beep(frequency_hz: u16, duration_ms: u32) {
         let current = inb(SPEAKER_PORT);
         outb(SPEAKER_PORT, current | 0x03);
     }
+}
 
-    // Wait for duration
-    spin_wait_ms(duration_ms);
-
+fn stop_tone() {
     // Disable speaker (clear bits 0 and 1)
     unsafe {
         let current = inb(SPEAKER_PORT);
@@ -36,48 +65,6 @@ pub fn beep(frequency_hz: u16, duration_ms: u32) {
     }
 }
 
-/// Play a simple melody (array of notes)
-pub fn play_melody(notes: &[(u16, u32)]) {
-    for &(frequency, duration) in notes {
-        beep(frequency, duration);
-    }
-}
-
-/// Spin-wait for milliseconds using the PIT
-fn spin_wait_ms(ms: u32) {
-    // Simple busy-wait loop
-    // This is a rough approximation - adjust the constant as needed
-    for _ in 0..ms {
-        for _ in 0..1000 {
-            unsafe {
-                asm!("nop");
-            }
-        }
-    }
-}
-
-/// Output a byte to an I/O port
-unsafe fn outb(port: u16, value: u8) {
-    asm!(
-        "out dx, al",
-        in("dx") port,
-        in("al") value,
-        options(nostack)
-    );
-}
-
-/// Read a byte from an I/O port
-unsafe fn inb(port: u16) -> u8 {
-    let value: u8;
-    asm!(
-        "in al, dx",
-        in("dx") port,
-        out("al") value,
-        options(nostack)
-    );
-    value
-}
-
 // Some common frequencies for musical notes
 pub struct Notes;
 
@@ -25,11 +25,14 @@ _start:
 	pushl $0
 	popf
 
-	mov %cr0, %eax
-	and $0xFFFFFFFB, %eax
-	or $0x2, %eax
-	mov %eax, %cr0
-
+	mov %cr0, %ecx
+	and $0xFFFFFFFB, %ecx
+	or $0x2, %ecx
+	mov %ecx, %cr0
+
+	xor %ebp, %ebp
+	pushl %ebx
+	pushl %eax
 	call kernel_main
 
 	cli
@@ -1,14 +1,39 @@
-use crate::writer::Writer;
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::writer::{self, Console};
 use crate::keyboard::Keyboard;
 use crate::vga_colors::Color;
 use crate::idt;
+use crate::irq;
+use crate::smp;
+use crate::log::{self, Level};
+use crate::rtc::{self, DateTime};
+use crate::serial;
+use crate::timer;
+use crate::clock;
 use crate::snake::SnakeGame;
 
 use crate::video_player::VideoPlayer;
 use crate::bad_apple_data::{FRAME_COUNT as BAD_APPLE_FRAME_COUNT, FRAME_WIDTH as BAD_APPLE_FRAME_WIDTH, FRAME_HEIGHT as BAD_APPLE_FRAME_HEIGHT, TARGET_FPS as BAD_APPLE_TARGET_FPS, FRAMES as BAD_APPLE_FRAMES};
 use crate::RAHH_data::{FRAME_COUNT as RAHH_FRAME_COUNT, FRAME_WIDTH as RAHH_FRAME_WIDTH, FRAME_HEIGHT as RAHH_FRAME_HEIGHT, TARGET_FPS as RAHH_TARGET_FPS, FRAMES as RAHH_FRAMES};
 use crate::filesystem::{get_filesystem, FileEntry};
-use crate::editor::Editor;
+use crate::editor::{Editor, EditorExit};
+use crate::frame_allocator::{get_frame_allocator, FRAME_SIZE};
+use crate::heap;
+use crate::multiboot;
+use crate::paging;
+use crate::process::{self, Pid};
+use crate::acpi;
+use crate::apic;
+use crate::power;
+use crate::sync::SpinLock;
+use crate::thread::{self, ThreadId};
+use crate::usermode;
+use crate::vga_colors::color_code;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 
 use crate::hex_fetch::HexFetch;
@@ -19,6 +44,15 @@ use crate::snake_graphics::SnakeGameGraphics;
 
 const MAX_COMMAND_LEN: usize = 80;
 
+// Top-right corner of the text screen, where `clock on` draws the time
+const CLOCK_COLUMN: usize = 72;
+const CLOCK_WIDTH: usize = 8;
+
+static CLOCK_RUNNING: AtomicBool = AtomicBool::new(false);
+static CLOCK_THREAD: SpinLock<Option<ThreadId>> = SpinLock::new(None);
+// The editor Ctrl+Z put aside, for a bare `edit` to pick up again
+static SUSPENDED_EDITOR: SpinLock<Option<Box<Editor>>> = SpinLock::new(None);
+
 pub struct CLI {
     buffer: [u8; MAX_COMMAND_LEN],
     buffer_len: usize,
@@ -34,20 +68,30 @@ impl CLI {
         }
     }
 
-    pub fn show_prompt(&self, writer: &mut Writer) {
+    pub fn show_prompt(&self, writer: &mut Console) {
         writer.set_color(Color::LightGreen, Color::Black);
         writer.write_str("HexiumOS> ");
         writer.set_color(Color::White, Color::Black);
     }
 
-    pub fn run(&mut self, writer: &mut Writer) -> ! {
+    pub fn run(&mut self, writer: &mut Console) -> ! {
         self.show_prompt(writer);
 
         loop {
+            if process::interrupted() {
+                self.cancel_line(writer);
+                continue;
+            }
+
+            if let Some(byte) = serial::read_byte() {
+                self.handle_serial_byte(byte, writer);
+                continue;
+            }
+
             let scancode = match idt::get_scancode() {
                 Some(sc) => sc,
                 None => {
-                    idt::wait_for_interrupt();
+                    thread::idle();
                     continue;
                 }
             };
@@ -65,32 +109,65 @@ impl CLI {
             }
 
             if scancode == 0x1C {
-                writer.write_byte(b'\n');
-                self.execute_command(writer);
-                self.buffer_len = 0;
-                self.show_prompt(writer);
+                self.submit_line(writer);
                 continue;
             }
 
             if scancode == 0x0E {
-                if self.buffer_len > 0 {
-                    self.buffer_len -= 1;
-                    self.delete_char(writer);
-                }
+                self.backspace(writer);
                 continue;
             }
 
             if let Some(c) = Keyboard::scancode_to_char(scancode, self.shift_pressed) {
-                if self.buffer_len < MAX_COMMAND_LEN {
-                    self.buffer[self.buffer_len] = c as u8;
-                    self.buffer_len += 1;
-                    writer.write_byte(c as u8);
-                }
+                self.insert_char(c as u8, writer);
             }
         }
     }
 
-    fn delete_char(&self, writer: &mut Writer) {
+    fn handle_serial_byte(&mut self, byte: u8, writer: &mut Console) {
+        match byte {
+            b'\r' | b'\n' => self.submit_line(writer),
+            // Ctrl+C from a terminal
+            0x03 => self.cancel_line(writer),
+            0x08 | 0x7F => self.backspace(writer),
+            0x20..=0x7E => self.insert_char(byte, writer),
+            _ => {}
+        }
+    }
+
+    fn submit_line(&mut self, writer: &mut Console) {
+        writer.write_byte(b'\n');
+        self.execute_command(writer);
+        self.buffer_len = 0;
+        self.report_finished_jobs(writer);
+        self.show_prompt(writer);
+    }
+
+    /// Drops the half-typed line and starts a fresh prompt, as Ctrl+C does
+    /// in a shell
+    fn cancel_line(&mut self, writer: &mut Console) {
+        writer.write_str("^C\n");
+        self.buffer_len = 0;
+        self.report_finished_jobs(writer);
+        self.show_prompt(writer);
+    }
+
+    fn backspace(&mut self, writer: &mut Console) {
+        if self.buffer_len > 0 {
+            self.buffer_len -= 1;
+            self.delete_char(writer);
+        }
+    }
+
+    fn insert_char(&mut self, c: u8, writer: &mut Console) {
+        if self.buffer_len < MAX_COMMAND_LEN {
+            self.buffer[self.buffer_len] = c;
+            self.buffer_len += 1;
+            writer.write_byte(c);
+        }
+    }
+
+    fn delete_char(&self, writer: &mut Console) {
         unsafe {
             let vga = 0xb8000 as *mut u8;
             if writer.get_col() > 0 {
@@ -101,9 +178,12 @@ impl CLI {
                 writer.set_position(col, row);
             }
         }
+        if serial::is_mirroring() {
+            serial::write_str("\x08 \x08");
+        }
     }
 
-    fn execute_command(&self, writer: &mut Writer) {
+    fn execute_command(&self, writer: &mut Console) {
         if self.buffer_len == 0 {
             return;
         }
@@ -121,12 +201,36 @@ impl CLI {
             writer.write_str("  echo <text>   - Echo back the text\n");
             writer.write_str("  snake         - Play the snake game\n");
             writer.write_str("  play <video>  - Play a video (badapple)\n");
+            writer.write_str("  meminfo       - Show physical memory and heap usage\n");
+            writer.write_str("  irqstat       - Show per-IRQ interrupt counts\n");
+            writer.write_str("  acpi          - List ACPI tables and platform details\n");
+            writer.write_str("  cpus          - Show each CPU core and its state\n");
+            writer.write_str("  ps [-t]       - List processes, or kernel threads with -t\n");
+            writer.write_str("  kill <pid>    - Stop a process\n");
+            writer.write_str("  nice <tid> <low|normal|high> - Change a kernel thread's priority\n");
+            writer.write_str("  wait <pid>    - Wait for a background process to exit (Ctrl+C stops waiting)\n");
+            writer.write_str("  clock on|off  - Show a clock in the corner from a background thread\n");
+            writer.write_str("  run <program> [&] - Run a built-in program in user mode\n");
+            writer.write_str("  exec <file> [args] [&] - Run an ELF executable from a file or boot module\n");
+            writer.write_str("                  (a trailing & runs it in the background)\n");
+            writer.write_str("                  (Ctrl+C stops a program, Ctrl+Z sends it to the background)\n");
+            writer.write_str("  serial on|off - Mirror the console to COM1\n");
+            writer.write_str("  serial log on|off - Mirror the kernel log to COM1\n");
+            writer.write_str("  dmesg [level|module|clear] - Show the kernel log\n");
+            writer.write_str("  dmesg level <level> - Record only messages at least this severe\n");
+            writer.write_str("  date          - Show the current date and time\n");
+            writer.write_str("  time          - Show the current time\n");
+            writer.write_str("  uptime        - Show time since boot\n");
+            writer.write_str("  settime <YYYY-MM-DD HH:MM:SS> - Set the clock\n");
+            writer.write_str("  reboot        - Restart the machine\n");
+            writer.write_str("  shutdown      - Power off the machine\n");
             writer.set_color(Color::LightCyan, Color::Black);
             writer.write_str("File System:\n");
             writer.set_color(Color::White, Color::Black);
             writer.write_str("  ls            - List files and directories\n");
             writer.write_str("  cat <file>    - Display file contents\n");
             writer.write_str("  edit <file>   - Edit a file\n");
+            writer.write_str("  edit          - Resume the editor Ctrl+Z suspended\n");
             writer.write_str("  touch <file>  - Create an empty file\n");
             writer.write_str("  write <file>  - Write text to file\n");
             writer.write_str("  rm <file>     - Delete a file\n");
@@ -177,8 +281,10 @@ impl CLI {
             self.cmd_ls(writer);
         } else if cmd.starts_with(b"cat ") {
             self.cmd_cat(&cmd[4..], writer);
+        } else if cmd == b"edit" {
+            self.cmd_edit(None, writer);
         } else if cmd.starts_with(b"edit ") {
-            self.cmd_edit(&cmd[5..], writer);
+            self.cmd_edit(Some(&cmd[5..]), writer);
         } else if cmd.starts_with(b"touch ") {
             self.cmd_touch(&cmd[6..], writer);
         } else if cmd.starts_with(b"write ") {
@@ -193,6 +299,56 @@ impl CLI {
             self.cmd_cd(&cmd[3..], writer);
         } else if cmd == b"pwd" {
             self.cmd_pwd(writer);
+        } else if cmd.starts_with(b"serial ") {
+            self.cmd_serial(&cmd[7..], writer);
+        } else if cmd == b"dmesg" {
+            self.cmd_dmesg(b"", writer);
+        } else if cmd.starts_with(b"dmesg ") {
+            self.cmd_dmesg(&cmd[6..], writer);
+        } else if cmd == b"date" {
+            self.cmd_date(writer);
+        } else if cmd == b"time" {
+            self.cmd_time(writer);
+        } else if cmd == b"uptime" {
+            self.cmd_uptime(writer);
+        } else if cmd.starts_with(b"settime ") {
+            self.cmd_settime(&cmd[8..], writer);
+        } else if cmd == b"meminfo" {
+            self.cmd_meminfo(writer);
+        } else if cmd == b"reboot" {
+            writer.set_color(Color::Yellow, Color::Black);
+            writer.write_str("Rebooting...\n");
+            power::reboot();
+        } else if cmd == b"shutdown" {
+            writer.set_color(Color::Yellow, Color::Black);
+            writer.write_str("Powering off...\n");
+            power::shutdown();
+        } else if cmd == b"irqstat" {
+            self.cmd_irqstat(writer);
+        } else if cmd == b"acpi" {
+            self.cmd_acpi(writer);
+        } else if cmd == b"cpus" {
+            self.cmd_cpus(writer);
+        } else if cmd == b"ps" {
+            self.cmd_ps(writer);
+        } else if cmd == b"ps -t" {
+            self.cmd_threads(writer);
+        } else if cmd.starts_with(b"kill ") {
+            self.cmd_kill(&cmd[5..], writer);
+        } else if cmd.starts_with(b"nice ") {
+            self.cmd_nice(&cmd[5..], writer);
+        } else if cmd.starts_with(b"wait ") {
+            self.cmd_wait(&cmd[5..], writer);
+        } else if cmd.starts_with(b"clock ") {
+            self.cmd_clock(&cmd[6..], writer);
+        } else if cmd == b"exec" {
+            self.cmd_exec(b"", writer);
+        } else if cmd.starts_with(b"exec ") {
+            self.cmd_exec(&cmd[5..], writer);
+        } else if cmd == b"run" {
+            self.cmd_run(b"", writer);
+        } else if cmd.starts_with(b"run ") {
+            self.cmd_run(&cmd[4..], writer);
         } else if cmd == b"hexfetch" {
            HexFetch::fetch(writer);
         }  else if cmd == b"snake" {
@@ -210,7 +366,7 @@ impl CLI {
         }
     }
 
-    fn cmd_ls(&self, writer: &mut Writer) {
+    fn cmd_ls(&self, writer: &mut Console) {
         let fs = get_filesystem();
         let mut has_entries = false;
         
@@ -222,12 +378,12 @@ impl CLI {
                     writer.write_bytes(name);
                     writer.write_str("/\n");
                 }
-                FileEntry::File(name, size) => {
+                FileEntry::File(name, size, modified) => {
                     writer.set_color(Color::White, Color::Black);
                     writer.write_bytes(name);
                     writer.write_str("  (");
                     self.write_number(writer, size);
-                    writer.write_str(" bytes)\n");
+                    let _ = writeln!(writer, " bytes, {})", DateTime::from_unix_timestamp(modified));
                 }
             }
         }
@@ -239,7 +395,7 @@ impl CLI {
         writer.set_color(Color::White, Color::Black);
     }
 
-    fn cmd_cat(&self, filename: &[u8], writer: &mut Writer) {
+    fn cmd_cat(&self, filename: &[u8], writer: &mut Console) {
         let fs = get_filesystem();
         match fs.read_file(filename) {
             Some(content) => {
@@ -258,12 +414,31 @@ impl CLI {
         }
     }
 
-    fn cmd_edit(&self, filename: &[u8], writer: &mut Writer) {
-        let mut editor = Editor::new();
-        match editor.open(filename) {
-            Ok(()) => {
-                editor.run(writer);
+    /// Opens `filename` in the editor, or with None resumes the one Ctrl+Z
+    /// suspended. Opening another file drops a suspended editor only if it
+    /// has nothing unsaved
+    fn cmd_edit(&self, filename: Option<&[u8]>, writer: &mut Console) {
+        let suspended = SUSPENDED_EDITOR.lock().take();
+        let opened = match (filename, suspended) {
+            (None, Some(editor)) => Ok(editor),
+            (None, None) => Err("No suspended editor to resume"),
+            (Some(_), Some(editor)) if editor.is_modified() => {
+                *SUSPENDED_EDITOR.lock() = Some(editor);
+                Err("The suspended editor has unsaved changes; run `edit` to resume it")
+            }
+            (Some(filename), _) => {
+                let mut editor = Box::new(Editor::new());
+                editor.open(filename).map(|_| editor)
+            }
+        };
+        match opened {
+            Ok(mut editor) => {
+                let exit = editor.run(writer);
                 writer.clear();
+                if exit == EditorExit::Suspended {
+                    writer.write_str("Editor suspended; `edit` resumes it\n");
+                    *SUSPENDED_EDITOR.lock() = Some(editor);
+                }
             }
             Err(e) => {
                 writer.set_color(Color::Red, Color::Black);
@@ -275,8 +450,8 @@ impl CLI {
         }
     }
 
-    fn cmd_touch(&self, filename: &[u8], writer: &mut Writer) {
-        let fs = get_filesystem();
+    fn cmd_touch(&self, filename: &[u8], writer: &mut Console) {
+        let mut fs = get_filesystem();
         if fs.file_exists(filename) {
             writer.write_str("File already exists\n");
             return;
@@ -299,7 +474,7 @@ impl CLI {
         }
     }
 
-    fn cmd_write(&self, args: &[u8], writer: &mut Writer) {
+    fn cmd_write(&self, args: &[u8], writer: &mut Console) {
         let mut space_idx = None;
         for (i, &b) in args.iter().enumerate() {
             if b == b' ' {
@@ -313,7 +488,7 @@ impl CLI {
                 let filename = &args[..idx];
                 let content = &args[idx + 1..];
                 
-                let fs = get_filesystem();
+                let mut fs = get_filesystem();
                 match fs.write_file(filename, content) {
                     Ok(()) => {
                         writer.set_color(Color::Green, Color::Black);
@@ -339,8 +514,8 @@ impl CLI {
         }
     }
 
-    fn cmd_rm(&self, filename: &[u8], writer: &mut Writer) {
-        let fs = get_filesystem();
+    fn cmd_rm(&self, filename: &[u8], writer: &mut Console) {
+        let mut fs = get_filesystem();
         match fs.delete_file(filename) {
             Ok(()) => {
                 writer.set_color(Color::Green, Color::Black);
@@ -359,8 +534,8 @@ impl CLI {
         }
     }
 
-    fn cmd_mkdir(&self, dirname: &[u8], writer: &mut Writer) {
-        let fs = get_filesystem();
+    fn cmd_mkdir(&self, dirname: &[u8], writer: &mut Console) {
+        let mut fs = get_filesystem();
         match fs.create_directory(dirname) {
             Ok(()) => {
                 writer.set_color(Color::Green, Color::Black);
@@ -379,8 +554,8 @@ impl CLI {
         }
     }
 
-    fn cmd_rmdir(&self, dirname: &[u8], writer: &mut Writer) {
-        let fs = get_filesystem();
+    fn cmd_rmdir(&self, dirname: &[u8], writer: &mut Console) {
+        let mut fs = get_filesystem();
         match fs.remove_directory(dirname) {
             Ok(()) => {
                 writer.set_color(Color::Green, Color::Black);
@@ -399,12 +574,13 @@ impl CLI {
         }
     }
 
-    fn cmd_cd(&self, dirname: &[u8], writer: &mut Writer) {
-        let fs = get_filesystem();
-        match fs.change_directory(dirname) {
-            Ok(()) => {
-                // Success
-            }
+    fn cmd_cd(&self, dirname: &[u8], writer: &mut Console) {
+        let changed = {
+            let mut fs = get_filesystem();
+            fs.change_directory(dirname).map(|_| fs.current_dir())
+        };
+        match changed {
+            Ok(dir) => process::set_current_dir(dir),
             Err(e) => {
                 writer.set_color(Color::Red, Color::Black);
                 writer.write_str("Error: ");
@@ -415,7 +591,7 @@ impl CLI {
         }
     }
 
-    fn cmd_pwd(&self, writer: &mut Writer) {
+    fn cmd_pwd(&self, writer: &mut Console) {
         let fs = get_filesystem();
         let mut path_buffer = [0u8; 128];
         let len = fs.get_current_path(&mut path_buffer);
@@ -425,7 +601,616 @@ impl CLI {
         writer.set_color(Color::White, Color::Black);
     }
 
-    fn write_number(&self, writer: &mut Writer, mut num: usize) {
+    fn cmd_serial(&self, arg: &[u8], writer: &mut Console) {
+        if !serial::is_initialized() {
+            writer.set_color(Color::Red, Color::Black);
+            writer.write_str("Error: No serial port detected\n");
+            writer.set_color(Color::White, Color::Black);
+            return;
+        }
+
+        if arg == b"log on" {
+            log::set_serial_mirror(true);
+            writer.write_str("Kernel log mirror enabled\n");
+        } else if arg == b"log off" {
+            log::set_serial_mirror(false);
+            writer.write_str("Kernel log mirror disabled\n");
+        } else if arg == b"on" {
+            serial::set_mirror(true);
+            writer.write_str("Serial mirror enabled\n");
+        } else if arg == b"off" {
+            writer.write_str("Serial mirror disabled\n");
+            serial::set_mirror(false);
+        } else {
+            writer.set_color(Color::Yellow, Color::Black);
+            writer.write_str("Usage: serial [log] on|off\n");
+            writer.set_color(Color::White, Color::Black);
+        }
+    }
+
+    fn cmd_dmesg(&self, arg: &[u8], writer: &mut Console) {
+        if arg == b"clear" {
+            log::clear();
+            return;
+        }
+        if let Some(level) = arg.strip_prefix(b"level ") {
+            match Level::parse(level) {
+                Some(level) => {
+                    log::set_max_level(level);
+                    let _ = writeln!(writer, "Recording {} and more severe messages", level.name());
+                }
+                None => writer.write_str("Usage: dmesg level error|warn|info|debug|trace\n"),
+            }
+            return;
+        }
+
+        // A level name shows that level and everything more severe,
+        // anything else is matched against the module tag
+        let max_level = Level::parse(arg).unwrap_or(Level::Trace);
+        let module = if arg.is_empty() || Level::parse(arg).is_some() { None } else { Some(arg) };
+
+        log::for_each(|entry| {
+            if entry.level > max_level {
+                return;
+            }
+            if let Some(module) = module {
+                if entry.tag() != module {
+                    return;
+                }
+            }
+
+            writer.set_color(Color::DarkGray, Color::Black);
+            writer.write_byte(b'[');
+            let _ = write!(writer, "{}.{:03}] ", entry.timestamp_ms / 1000, entry.timestamp_ms % 1000);
+
+            let color = match entry.level {
+                Level::Error => Color::LightRed,
+                Level::Warn => Color::Yellow,
+                Level::Info => Color::White,
+                Level::Debug => Color::LightGray,
+                Level::Trace => Color::DarkGray,
+            };
+            writer.set_color(Color::LightCyan, Color::Black);
+            writer.write_bytes(entry.tag());
+            writer.write_str(": ");
+            writer.set_color(color, Color::Black);
+            writer.write_bytes(entry.message());
+            writer.write_byte(b'\n');
+        });
+        writer.set_color(Color::White, Color::Black);
+    }
+
+    fn cmd_date(&self, writer: &mut Console) {
+        let now = rtc::read();
+        writer.set_color(Color::LightCyan, Color::Black);
+        writer.write_str(rtc::weekday_name(now.day_of_week()));
+        writer.write_byte(b' ');
+        let _ = write!(writer, "{} UTC", now);
+        writer.set_color(Color::DarkGray, Color::Black);
+        let _ = writeln!(writer, "  (unix {})", now.to_unix_timestamp());
+        writer.set_color(Color::White, Color::Black);
+    }
+
+    fn cmd_time(&self, writer: &mut Console) {
+        let now = rtc::read();
+        writer.set_color(Color::LightCyan, Color::Black);
+        let _ = writeln!(writer, "{:02}:{:02}:{:02}", now.hour, now.minute, now.second);
+        writer.set_color(Color::White, Color::Black);
+    }
+
+    fn cmd_uptime(&self, writer: &mut Console) {
+        let ms = timer::uptime_ms();
+        let secs = ms / 1000;
+        writer.set_color(Color::LightCyan, Color::Black);
+        let _ = write!(writer, "up {}:{:02}:{:02}.{:03}", secs / 3600, secs / 60 % 60, secs % 60, ms % 1000);
+        writer.set_color(Color::DarkGray, Color::Black);
+        let _ = writeln!(writer, "  ({} ticks at {} Hz)", timer::ticks(), timer::frequency());
+        match clock::source() {
+            clock::ClockSource::Tsc => {
+                let _ = writeln!(writer, "  clock: TSC at {} MHz", clock::tsc_frequency() / 1_000_000);
+            }
+            clock::ClockSource::Pit => {
+                let _ = writeln!(writer, "  clock: PIT ticks");
+            }
+        }
+        writer.set_color(Color::White, Color::Black);
+    }
+
+    fn cmd_settime(&self, arg: &[u8], writer: &mut Console) {
+        let datetime = match DateTime::parse(arg) {
+            Some(datetime) => datetime,
+            None => {
+                writer.set_color(Color::Yellow, Color::Black);
+                writer.write_str("Usage: settime YYYY-MM-DD HH:MM:SS\n");
+                writer.set_color(Color::White, Color::Black);
+                return;
+            }
+        };
+
+        match rtc::write(&datetime) {
+            Ok(()) => {
+                info!("Clock set to {}", datetime);
+                writer.set_color(Color::Green, Color::Black);
+                let _ = writeln!(writer, "Clock set to {}", datetime);
+                writer.set_color(Color::White, Color::Black);
+            }
+            Err(e) => {
+                writer.set_color(Color::Red, Color::Black);
+                writer.write_str("Error: ");
+                writer.write_str(e);
+                writer.write_byte(b'\n');
+                writer.set_color(Color::White, Color::Black);
+            }
+        }
+    }
+
+    fn cmd_meminfo(&self, writer: &mut Console) {
+        let stats = get_frame_allocator().stats();
+        let kb_per_frame = (FRAME_SIZE / 1024) as usize;
+
+        writer.set_color(Color::LightCyan, Color::Black);
+        writer.write_str("Physical memory (4 KiB frames):\n");
+        writer.set_color(Color::White, Color::Black);
+        writer.write_str("  Total: ");
+        self.write_number(writer, stats.total);
+        writer.write_str(" frames (");
+        self.write_number(writer, stats.total * kb_per_frame);
+        writer.write_str(" KB)\n");
+        writer.write_str("  Used:  ");
+        self.write_number(writer, stats.used);
+        writer.write_str(" frames (");
+        self.write_number(writer, stats.used * kb_per_frame);
+        writer.write_str(" KB)\n");
+        writer.write_str("  Free:  ");
+        self.write_number(writer, stats.free);
+        writer.write_str(" frames (");
+        self.write_number(writer, stats.free * kb_per_frame);
+        writer.write_str(" KB)\n");
+
+        let heap = heap::stats();
+        writer.set_color(Color::LightCyan, Color::Black);
+        writer.write_str("Kernel heap:\n");
+        writer.set_color(Color::White, Color::Black);
+        writer.write_str("  Size:  ");
+        self.write_number(writer, heap.total / 1024);
+        writer.write_str(" KB\n");
+        writer.write_str("  Used:  ");
+        self.write_number(writer, heap.used);
+        writer.write_str(" bytes\n");
+        writer.write_str("  Free:  ");
+        self.write_number(writer, heap.free);
+        writer.write_str(" bytes\n");
+        writer.write_str("  Paging: ");
+        writer.write_str(if paging::is_enabled() { "enabled\n" } else { "disabled\n" });
+    }
+
+    fn cmd_irqstat(&self, writer: &mut Console) {
+        writer.set_color(Color::LightCyan, Color::Black);
+        let _ = writeln!(writer, "Interrupt controller: {}", irq::controller().name());
+        writer.write_str("IRQ  Count       Spurious  Handler\n");
+        writer.set_color(Color::White, Color::Black);
+        for i in 0..irq::IRQ_COUNT as u8 {
+            let stat = irq::stats(i);
+            if stat.name.is_none() && stat.count == 0 && stat.spurious == 0 {
+                continue;
+            }
+            let _ = write!(writer, "{:>3}  {:<10}  {:<8}  ", stat.irq, stat.count, stat.spurious);
+            match stat.name {
+                Some(name) => writer.write_str(name),
+                None => {
+                    writer.set_color(Color::DarkGray, Color::Black);
+                    writer.write_str("(none)");
+                }
+            }
+            if stat.masked {
+                writer.set_color(Color::DarkGray, Color::Black);
+                writer.write_str(" [masked]");
+            }
+            writer.set_color(Color::White, Color::Black);
+            writer.write_byte(b'\n');
+        }
+        if irq::controller() == irq::Controller::Apic {
+            writer.set_color(Color::DarkGray, Color::Black);
+            let _ = writeln!(
+                writer,
+                "LAPIC timer clock {} kHz, {} spurious APIC interrupts",
+                apic::timer_frequency() / 1000,
+                apic::spurious_count()
+            );
+            writer.set_color(Color::White, Color::Black);
+        }
+    }
+
+    fn cmd_acpi(&self, writer: &mut Console) {
+        let root = match acpi::root() {
+            Some(root) => root,
+            None => {
+                writer.set_color(Color::Yellow, Color::Black);
+                writer.write_str("ACPI tables not found\n");
+                writer.set_color(Color::White, Color::Black);
+                return;
+            }
+        };
+
+        writer.set_color(Color::LightCyan, Color::Black);
+        let _ = write!(writer, "ACPI rev {} ", root.revision);
+        writer.write_bytes(&root.oem_id);
+        let _ = writeln!(writer, ", {} at {:#010x}", if root.extended { "XSDT" } else { "RSDT" }, root.address);
+        writer.set_color(Color::White, Color::Black);
+        for table in acpi::tables() {
+            let header = table.header;
+            writer.write_str("  ");
+            writer.write_bytes(&header.signature);
+            let (length, revision) = (header.length, header.revision);
+            let _ = write!(writer, "  {:#010x}  {:>6} bytes  rev {}  ", table.address, length, revision);
+            writer.write_bytes(&header.oem_table_id);
+            writer.write_byte(b'\n');
+        }
+
+        if let Some(fadt) = acpi::fadt() {
+            writer.set_color(Color::LightCyan, Color::Black);
+            writer.write_str("FADT: ");
+            writer.set_color(Color::White, Color::Black);
+            let _ = writeln!(
+                writer,
+                "SCI IRQ {}, PM1a {:#x}, PM timer {:#x}, century reg {:#x}",
+                fadt.sci_interrupt, fadt.pm1a_control_block, fadt.pm_timer_block, fadt.century_register
+            );
+        }
+
+        if let Some(madt) = acpi::madt() {
+            writer.set_color(Color::LightCyan, Color::Black);
+            writer.write_str("MADT: ");
+            writer.set_color(Color::White, Color::Black);
+            let _ = write!(writer, "LAPIC {:#010x}, CPUs", madt.local_apic_address);
+            for cpu in madt.cpus() {
+                let _ = write!(writer, " {}{}", cpu.apic_id, if cpu.enabled { "" } else { "(off)" });
+            }
+            writer.write_byte(b'\n');
+            for io_apic in madt.io_apics() {
+                let _ = writeln!(writer, "  IOAPIC {} at {:#010x}, GSI base {}", io_apic.id, io_apic.address, io_apic.gsi_base);
+            }
+            for over in madt.overrides() {
+                let _ = writeln!(
+                    writer,
+                    "  IRQ {} -> GSI {}{}{}",
+                    over.source,
+                    over.gsi,
+                    if over.active_low() { ", active low" } else { "" },
+                    if over.level_triggered() { ", level" } else { "" }
+                );
+            }
+        }
+
+        if let Some(hpet) = acpi::hpet() {
+            writer.set_color(Color::LightCyan, Color::Black);
+            writer.write_str("HPET: ");
+            writer.set_color(Color::White, Color::Black);
+            let _ = writeln!(
+                writer,
+                "{:#010x}, {} comparators, {}-bit, min tick {}",
+                hpet.base_address.address,
+                hpet.comparator_count,
+                if hpet.counter_64bit { 64 } else { 32 },
+                hpet.minimum_tick
+            );
+        }
+    }
+
+    fn cmd_cpus(&self, writer: &mut Console) {
+        writer.set_color(Color::LightCyan, Color::Black);
+        let _ = writeln!(writer, "{} of {} CPUs online", smp::online_count(), smp::cpu_count());
+        writer.write_str("CPU  APIC ID  State\n");
+        writer.set_color(Color::White, Color::Black);
+        let current = smp::current_cpu();
+        for (index, cpu) in smp::cpus().iter().enumerate() {
+            let state = cpu.state();
+            let _ = write!(writer, "{:>3}  {:>7}  ", index, cpu.apic_id());
+            writer.set_color(
+                match state {
+                    smp::CpuState::Online => Color::LightGreen,
+                    smp::CpuState::Failed => Color::LightRed,
+                    _ => Color::Yellow,
+                },
+                Color::Black,
+            );
+            writer.write_str(state.name());
+            writer.set_color(Color::DarkGray, Color::Black);
+            if cpu.is_bsp() {
+                writer.write_str(" [BSP]");
+            }
+            if index == current {
+                writer.write_str(" [current]");
+            }
+            writer.set_color(Color::White, Color::Black);
+            writer.write_byte(b'\n');
+        }
+    }
+
+    fn cmd_ps(&self, writer: &mut Console) {
+        writer.set_color(Color::LightCyan, Color::Black);
+        writer.write_str("PID  PPID  State    TID  Name\n");
+        writer.set_color(Color::White, Color::Black);
+        let current = process::current();
+        process::for_each(|info| {
+            let _ = write!(writer, "{:>3}  ", info.pid.as_u32());
+            match info.parent {
+                Some(parent) => {
+                    let _ = write!(writer, "{:>4}  ", parent.as_u32());
+                }
+                None => writer.write_str("   -  "),
+            }
+            writer.set_color(
+                match info.state {
+                    process::ProcessState::Running => Color::LightGreen,
+                    process::ProcessState::Zombie(_) => Color::DarkGray,
+                },
+                Color::Black,
+            );
+            let _ = write!(writer, "{:<7}  ", info.state.name());
+            writer.set_color(Color::White, Color::Black);
+            let _ = write!(writer, "{:>3}  ", info.thread.as_u32());
+            writer.write_bytes(info.name());
+            writer.set_color(Color::DarkGray, Color::Black);
+            if info.background {
+                writer.write_str(" &");
+            }
+            if let process::ProcessState::Zombie(code) = info.state {
+                let _ = write!(writer, " [exit {}]", code);
+            }
+            if Some(info.pid) == current {
+                writer.write_str(" [current]");
+            }
+            writer.set_color(Color::White, Color::Black);
+            writer.write_byte(b'\n');
+        });
+    }
+
+    fn cmd_threads(&self, writer: &mut Console) {
+        writer.set_color(Color::LightCyan, Color::Black);
+        writer.write_str("TID  State     Priority  CPU ticks  Switches  Stack   Name\n");
+        writer.set_color(Color::White, Color::Black);
+        let current = thread::current();
+        thread::for_each(|info| {
+            let _ = write!(writer, "{:>3}  ", info.id.as_u32());
+            writer.set_color(
+                match info.state {
+                    thread::ThreadState::Running => Color::LightGreen,
+                    thread::ThreadState::Ready => Color::White,
+                    thread::ThreadState::Sleeping(_) | thread::ThreadState::Blocked => Color::Yellow,
+                    thread::ThreadState::Finished => Color::DarkGray,
+                },
+                Color::Black,
+            );
+            let _ = write!(writer, "{:<8}  ", info.state.name());
+            writer.set_color(Color::White, Color::Black);
+            let _ = write!(writer, "{:<8}  {:>9}  {:>8}  ", info.priority.name(), info.ticks, info.switches);
+            if info.stack_size == 0 {
+                writer.write_str("boot    ");
+            } else {
+                let _ = write!(writer, "{:>4} KB ", info.stack_size / 1024);
+            }
+            writer.write_str(info.name);
+            if info.id == current {
+                writer.set_color(Color::DarkGray, Color::Black);
+                writer.write_str(" [current]");
+                writer.set_color(Color::White, Color::Black);
+            }
+            writer.write_byte(b'\n');
+        });
+    }
+
+    fn cmd_clock(&self, arg: &[u8], writer: &mut Console) {
+        let mut clock_thread = CLOCK_THREAD.lock();
+        if arg == b"on" {
+            if clock_thread.is_some() {
+                writer.write_str("Clock already running\n");
+                return;
+            }
+            CLOCK_RUNNING.store(true, Ordering::Release);
+            match thread::spawn_with_priority("clock", thread::Priority::Low, clock_thread_main) {
+                Ok(id) => *clock_thread = Some(id),
+                Err(e) => {
+                    CLOCK_RUNNING.store(false, Ordering::Release);
+                    writer.set_color(Color::Red, Color::Black);
+                    writer.write_str("Error: ");
+                    writer.write_str(e);
+                    writer.write_byte(b'\n');
+                    writer.set_color(Color::White, Color::Black);
+                }
+            }
+        } else if arg == b"off" {
+            let id = match clock_thread.take() {
+                Some(id) => id,
+                None => {
+                    writer.write_str("Clock not running\n");
+                    return;
+                }
+            };
+            drop(clock_thread);
+            CLOCK_RUNNING.store(false, Ordering::Release);
+            let _ = thread::join(id);
+        } else {
+            writer.write_str("Usage: clock on|off\n");
+        }
+    }
+
+    fn cmd_run(&self, args: &[u8], writer: &mut Console) {
+        let (name, background) = split_background(args);
+        let image = match usermode::builtin(name) {
+            Some(image) => image,
+            None => {
+                writer.write_str("Usage: run <program> [&]\nPrograms:\n");
+                for (name, description) in usermode::BUILTINS.iter() {
+                    let _ = writeln!(writer, "  {:<8} - {}", name, description);
+                }
+                return;
+            }
+        };
+        let argv0 = name.to_vec();
+        let env = self.program_env();
+        let started = process::spawn(name, background, move || {
+            program_exit_code(usermode::run(image, &[&argv0], &[&env]))
+        });
+        self.finish_launch(started, background, writer);
+    }
+
+    fn cmd_exec(&self, args: &[u8], writer: &mut Console) {
+        let (args, background) = split_background(args);
+        let argv: Vec<Vec<u8>> = args
+            .split(|&b| b == b' ')
+            .filter(|w| !w.is_empty())
+            .map(|w| w.to_vec())
+            .collect();
+        let name = match argv.first() {
+            Some(name) => name.clone(),
+            None => {
+                writer.write_str("Usage: exec <file> [args...] [&]\n");
+                if let Some(info) = multiboot::boot_info() {
+                    for module in info.modules() {
+                        writer.write_str("  ");
+                        writer.write_bytes(module.file_name());
+                        writer.write_str(" (boot module)\n");
+                    }
+                }
+                return;
+            }
+        };
+
+        // Copied out, as the program may use the file system while it runs
+        let from_fs = get_filesystem().read_file(&name).map(|data| data.to_vec());
+        let module = multiboot::boot_info().and_then(|info| info.modules().find(|m| m.file_name() == &name[..]));
+        let file: Cow<'static, [u8]> = match (from_fs, module) {
+            (Some(data), _) => Cow::Owned(data),
+            (None, Some(module)) => Cow::Borrowed(module.data()),
+            (None, None) => {
+                writer.set_color(Color::Red, Color::Black);
+                writer.write_str("No such program: ");
+                writer.write_bytes(&name);
+                writer.write_byte(b'\n');
+                writer.set_color(Color::White, Color::Black);
+                return;
+            }
+        };
+
+        let env = self.program_env();
+        let started = process::spawn(&name, background, move || {
+            let argv: Vec<&[u8]> = argv.iter().map(|arg| &arg[..]).collect();
+            program_exit_code(usermode::exec(&file, &argv, &[&env]))
+        });
+        self.finish_launch(started, background, writer);
+    }
+
+    /// Waits for a program started in the foreground, which Ctrl+C stops
+    /// and Ctrl+Z sends to the background; one in the background is
+    /// reported by `report_finished_jobs` once it exits
+    fn finish_launch(&self, started: Result<Pid, &'static str>, background: bool, writer: &mut Console) {
+        match started {
+            Ok(pid) if background => {
+                let _ = writeln!(writer, "[{}] started", pid.as_u32());
+            }
+            Ok(pid) => match process::wait_foreground(pid) {
+                Ok(Some(code)) => self.report_exit(Ok(code), writer),
+                Ok(None) => {
+                    let _ = writeln!(writer, "[{}] continues in the background", pid.as_u32());
+                }
+                Err(e) => self.report_exit(Err(e), writer),
+            },
+            Err(e) => self.report_exit(Err(e), writer),
+        }
+    }
+
+    /// `PWD=<current directory>`, the environment programs start with
+    fn program_env(&self) -> Vec<u8> {
+        let mut path_buffer = [0u8; 128];
+        let len = get_filesystem().get_current_path(&mut path_buffer);
+        let mut env = b"PWD=".to_vec();
+        env.extend_from_slice(&path_buffer[..len]);
+        env
+    }
+
+    fn report_exit(&self, result: Result<i32, &'static str>, writer: &mut Console) {
+        match result {
+            Ok(0) => {}
+            Ok(code) => {
+                writer.set_color(Color::Yellow, Color::Black);
+                let _ = writeln!(writer, "Program exited with code {}", code);
+                writer.set_color(Color::White, Color::Black);
+            }
+            Err(e) => {
+                writer.set_color(Color::Red, Color::Black);
+                writer.write_str("Error: ");
+                writer.write_str(e);
+                writer.write_byte(b'\n');
+                writer.set_color(Color::White, Color::Black);
+            }
+        }
+    }
+
+    /// Collects background programs that have exited since the last
+    /// prompt, so they do not linger as zombies
+    fn report_finished_jobs(&self, writer: &mut Console) {
+        while let Some((pid, code)) = process::try_wait() {
+            writer.set_color(Color::DarkGray, Color::Black);
+            let _ = writeln!(writer, "[{}] done, exit code {}", pid.as_u32(), code);
+            writer.set_color(Color::White, Color::Black);
+        }
+    }
+
+    fn cmd_kill(&self, arg: &[u8], writer: &mut Console) {
+        let pid = match parse_pid(arg) {
+            Some(pid) => pid,
+            None => {
+                writer.write_str("Usage: kill <pid>\n");
+                return;
+            }
+        };
+        if let Err(e) = process::kill(pid) {
+            self.report_exit(Err(e), writer);
+        }
+    }
+
+    fn cmd_nice(&self, args: &[u8], writer: &mut Console) {
+        let mut parts = args.split(|&b| b == b' ').filter(|part| !part.is_empty());
+        let id = parts
+            .next()
+            .and_then(|id| core::str::from_utf8(id).ok())
+            .and_then(|id| id.parse().ok())
+            .map(ThreadId::from_u32);
+        let priority = parts.next().and_then(thread::Priority::from_name);
+        let (id, priority) = match (id, priority, parts.next()) {
+            (Some(id), Some(priority), None) => (id, priority),
+            _ => {
+                writer.write_str("Usage: nice <tid> <low|normal|high>\n");
+                return;
+            }
+        };
+        if let Err(e) = thread::set_priority(id, priority) {
+            writer.set_color(Color::Red, Color::Black);
+            writer.write_str("Error: ");
+            writer.write_str(e);
+            writer.write_byte(b'\n');
+            writer.set_color(Color::White, Color::Black);
+        }
+    }
+
+    fn cmd_wait(&self, arg: &[u8], writer: &mut Console) {
+        let pid = match parse_pid(arg) {
+            Some(pid) => pid,
+            None => {
+                writer.write_str("Usage: wait <pid>\n");
+                return;
+            }
+        };
+        match process::wait(pid) {
+            Ok(code) => {
+                let _ = writeln!(writer, "[{}] exit code {}", pid.as_u32(), code);
+            }
+            Err(e) => self.report_exit(Err(e), writer),
+        }
+    }
+
+    fn write_number(&self, writer: &mut Console, mut num: usize) {
         if num == 0 {
             writer.write_byte(b'0');
             return;
@@ -445,3 +1230,73 @@ impl CLI {
         }
     }
 }
+
+/// Redraws the time in the top-right corner until `clock off`
+fn clock_thread_main() {
+    let vga = 0xb8000 as *mut u8;
+    let color = color_code(Color::Black, Color::LightGray);
+    let mut last_second = 60;
+
+    while CLOCK_RUNNING.load(Ordering::Acquire) {
+        let now = rtc::read();
+        if now.second != last_second {
+            last_second = now.second;
+            let text = [
+                b'0' + now.hour / 10,
+                b'0' + now.hour % 10,
+                b':',
+                b'0' + now.minute / 10,
+                b'0' + now.minute % 10,
+                b':',
+                b'0' + now.second / 10,
+                b'0' + now.second % 10,
+            ];
+            for (i, &byte) in text.iter().enumerate() {
+                unsafe {
+                    *vga.add((CLOCK_COLUMN + i) * 2) = byte;
+                    *vga.add((CLOCK_COLUMN + i) * 2 + 1) = color;
+                }
+            }
+        }
+        timer::sleep_ms(100);
+    }
+
+    let blank = color_code(Color::White, Color::Black);
+    for i in 0..CLOCK_WIDTH {
+        unsafe {
+            *vga.add((CLOCK_COLUMN + i) * 2) = b' ';
+            *vga.add((CLOCK_COLUMN + i) * 2 + 1) = blank;
+        }
+    }
+}
+
+/// Strips a trailing `&`, which asks for the command to run in the
+/// background
+fn split_background(args: &[u8]) -> (&[u8], bool) {
+    let args = args.trim_ascii();
+    match args.strip_suffix(b"&") {
+        Some(rest) => (rest.trim_ascii(), true),
+        None => (args, false),
+    }
+}
+
+fn parse_pid(arg: &[u8]) -> Option<Pid> {
+    core::str::from_utf8(arg.trim_ascii())
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(Pid::from_u32)
+}
+
+/// The exit code of a program's process, or 126, as shells use for a
+/// file that cannot be executed, after saying why it would not load
+fn program_exit_code(result: Result<i32, &'static str>) -> i32 {
+    result.unwrap_or_else(|e| {
+        let mut console = writer::console();
+        console.set_color(Color::Red, Color::Black);
+        console.write_str("Error: ");
+        console.write_str(e);
+        console.write_byte(b'\n');
+        console.set_color(Color::White, Color::Black);
+        126
+    })
+}
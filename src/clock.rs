@@ -0,0 +1,212 @@
+use core::ops::{Add, Sub};
+pub use core::time::Duration;
+
+use crate::cpu;
+use crate::idt;
+use crate::io::{inb, outb};
+use crate::sync::Once;
+use crate::timer::{self, PIT_FREQUENCY};
+
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count)
+const PIT_CHANNEL_2_ONESHOT: u8 = 0xB0;
+// Port 0x61: bit 0 gates channel 2, bit 1 drives the speaker, bit 5 is OUT2
+const SPEAKER_PORT: u16 = 0x61;
+const SPEAKER_GATE: u8 = 0x01;
+const SPEAKER_DATA: u8 = 0x02;
+const SPEAKER_OUT2: u8 = 0x20;
+
+const CALIBRATION_MS: u32 = 50;
+const CALIBRATION_ROUNDS: usize = 3;
+// OUT2 should rise within 50 ms; give up long before a hang is noticeable
+const CALIBRATION_SPIN_LIMIT: u32 = 10_000_000;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    Tsc,
+    Pit,
+}
+
+impl ClockSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClockSource::Tsc => "TSC",
+            ClockSource::Pit => "PIT",
+        }
+    }
+}
+
+struct Calibration {
+    tsc_hz: u64,
+    // TSC value and PIT uptime at calibration; `Instant`s count from there
+    tsc_base: u64,
+    base_nanos: u64,
+}
+
+// Set once the TSC is calibrated; until then the clock runs on PIT ticks
+static CALIBRATION: Once<Calibration> = Once::new();
+
+/// Calibrates the TSC against PIT channel 2, falling back to PIT ticks
+pub fn init() -> Result<ClockSource, &'static str> {
+    if !cpu::has_feature(cpu::FEATURE_TSC) {
+        return Err("CPU has no TSC, using PIT ticks");
+    }
+
+    let mut best = u64::MAX;
+    for _ in 0..CALIBRATION_ROUNDS {
+        if let Some(cycles) = idt::without_interrupts(measure_tsc_cycles) {
+            best = best.min(cycles);
+        }
+    }
+    if best == u64::MAX || best == 0 {
+        return Err("TSC calibration timed out, using PIT ticks");
+    }
+
+    // SMIs or a busy host only ever lengthen a round, so the shortest wins
+    let hz = best * 1000 / CALIBRATION_MS as u64;
+    idt::without_interrupts(|| {
+        CALIBRATION.call_once(|| Calibration {
+            tsc_hz: hz,
+            tsc_base: cpu::rdtsc(),
+            base_nanos: timer::uptime_ms() * 1_000_000,
+        });
+    });
+    Ok(ClockSource::Tsc)
+}
+
+/// Counts TSC cycles while PIT channel 2 runs down `CALIBRATION_MS`
+fn measure_tsc_cycles() -> Option<u64> {
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+
+    unsafe {
+        let port = inb(SPEAKER_PORT);
+        outb(SPEAKER_PORT, (port & !SPEAKER_DATA) | SPEAKER_GATE);
+
+        outb(PIT_COMMAND, PIT_CHANNEL_2_ONESHOT);
+        outb(PIT_CHANNEL_2, (count & 0xFF) as u8);
+        outb(PIT_CHANNEL_2, ((count >> 8) & 0xFF) as u8);
+
+        let start = cpu::rdtsc();
+        let mut spins = 0;
+        while inb(SPEAKER_PORT) & SPEAKER_OUT2 == 0 {
+            spins += 1;
+            if spins > CALIBRATION_SPIN_LIMIT {
+                outb(SPEAKER_PORT, port);
+                return None;
+            }
+        }
+        let end = cpu::rdtsc();
+
+        outb(SPEAKER_PORT, port);
+        Some(end - start)
+    }
+}
+
+pub fn source() -> ClockSource {
+    if CALIBRATION.is_completed() {
+        ClockSource::Tsc
+    } else {
+        ClockSource::Pit
+    }
+}
+
+/// Calibrated TSC frequency, or 0 when running on PIT ticks
+pub fn tsc_frequency() -> u64 {
+    CALIBRATION.get().map_or(0, |c| c.tsc_hz)
+}
+
+/// Splits the division so the product cannot overflow for any realistic
+/// uptime
+fn cycles_to_nanos(cycles: u64, hz: u64) -> u64 {
+    let secs = cycles / hz;
+    let rem = cycles % hz;
+    secs * NANOS_PER_SEC + rem * NANOS_PER_SEC / hz
+}
+
+/// Nanoseconds since boot, never going backwards
+fn now_nanos() -> u64 {
+    match CALIBRATION.get() {
+        Some(c) => c.base_nanos + cycles_to_nanos(cpu::rdtsc().wrapping_sub(c.tsc_base), c.tsc_hz),
+        None => timer::uptime_ms() * 1_000_000,
+    }
+}
+
+/// Spins for `duration`; for delays much shorter than a timer tick
+pub fn busy_wait(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// A point on the monotonic clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Self { nanos: now_nanos() }
+    }
+
+    /// Saturates to zero if `earlier` is actually later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Time since boot
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant {
+            nanos: self.nanos + rhs.as_secs() * NANOS_PER_SEC + rhs.subsec_nanos() as u64,
+        }
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+
+    pub fn cycles_convert_without_overflow() {
+        assert_eq!(cycles_to_nanos(3_000_000_000, 3_000_000_000), NANOS_PER_SEC);
+        assert_eq!(cycles_to_nanos(1_500, 3_000_000_000), 500);
+        // An hour at 4 GHz overflows a naive `cycles * 1e9`
+        let hour = 3600 * 4_000_000_000u64;
+        assert_eq!(cycles_to_nanos(hour, 4_000_000_000), 3600 * NANOS_PER_SEC);
+    }
+
+    pub fn instant_is_monotonic() {
+        let start = Instant::now();
+        timer::sleep_ms(30);
+        let end = Instant::now();
+
+        assert!(end >= start);
+        let elapsed = end - start;
+        assert!(elapsed >= Duration::from_millis(20), "slept only {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(500), "slept for {:?}", elapsed);
+        assert_eq!(start - end, Duration::ZERO);
+    }
+}
@@ -0,0 +1,45 @@
+use core::arch::asm;
+
+// CPUID leaf 1 EDX feature bits
+pub const FEATURE_TSC: u32 = 1 << 4;
+pub const FEATURE_APIC: u32 = 1 << 9;
+
+pub fn cpuid(function: u32) -> (u32, u32, u32, u32) {
+    let eax: u32;
+    let ebx: u32;
+    let ecx: u32;
+    let edx: u32;
+
+    unsafe {
+        asm!(
+            "cpuid",
+            inout("eax") function => eax,
+            out("ebx") ebx,
+            out("ecx") ecx,
+            out("edx") edx,
+            options(nostack, preserves_flags)
+        );
+    }
+
+    (eax, ebx, ecx, edx)
+}
+
+/// Tests a CPUID leaf 1 EDX feature bit
+pub fn has_feature(feature: u32) -> bool {
+    let (max_func, _, _, _) = cpuid(0);
+    if max_func < 1 {
+        return false;
+    }
+    let (_, _, _, edx) = cpuid(1);
+    edx & feature != 0
+}
+
+/// Reads the time-stamp counter; check `FEATURE_TSC` first
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+    ((high as u64) << 32) | low as u64
+}
@@ -1,7 +1,9 @@
-use crate::writer::Writer;
+use crate::writer::Console;
 use crate::keyboard::Keyboard;
 use crate::vga_colors::Color;
 use crate::idt;
+use crate::process;
+use crate::thread;
 use crate::filesystem::get_filesystem;
 
 const EDITOR_WIDTH: usize = 80;
@@ -9,6 +11,14 @@ const EDITOR_HEIGHT: usize = 23;
 const MAX_LINES: usize = 100;
 const MAX_LINE_LEN: usize = 80;
 
+/// How `Editor::run` ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditorExit {
+    Closed,
+    // Ctrl+Z: the buffer is kept, unsaved changes and all, to run again
+    Suspended,
+}
+
 pub struct Editor {
     lines: [[u8; MAX_LINE_LEN]; MAX_LINES],
     line_lengths: [usize; MAX_LINES],
@@ -84,7 +94,13 @@ impl Editor {
         self.modified = false;
     }
 
-    pub fn run(&mut self, writer: &mut Writer) {
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Edits until the user quits or presses Ctrl+Z. Ctrl+C quits like
+    /// ESC, asking first if there are unsaved changes
+    pub fn run(&mut self, writer: &mut Console) -> EditorExit {
         let mut shift_pressed = false;
         let mut ctrl_pressed = false;
         
@@ -93,12 +109,18 @@ impl Editor {
         self.draw(writer);
 
         loop {
-            let scancode = match idt::get_scancode() {
-                Some(sc) => sc,
-                None => {
-                    idt::wait_for_interrupt();
-                    continue;
-                }
+            let scancode = match idt::read_scancode_unless(process::signal_pending) {
+                Some(scancode) => scancode,
+                None => match process::take_signal() {
+                    Some(process::Signal::Suspend) => return EditorExit::Suspended,
+                    _ => {
+                        if self.confirm_quit(writer, "Unsaved changes! Press Ctrl+C again to quit", None) {
+                            break;
+                        }
+                        self.draw(writer);
+                        continue;
+                    }
+                },
             };
 
             if scancode & 0x80 != 0 {
@@ -132,31 +154,19 @@ impl Editor {
             }
 
             if ctrl_pressed && scancode == 0x10 {
-                if self.modified {
-                    self.show_message(writer, "Unsaved changes! Press Ctrl+Q again to quit", Color::Yellow);
-                    self.draw(writer);
-                    let scancode2 = self.wait_for_key();
-                    if scancode2 == 0x10 && ctrl_pressed {
-                        break;
-                    }
-                    continue;
-                } else {
+                if self.confirm_quit(writer, "Unsaved changes! Press Ctrl+Q again to quit", Some(0x10)) {
                     break;
                 }
+                self.draw(writer);
+                continue;
             }
 
             if scancode == 0x01 {
-                if self.modified {
-                    self.show_message(writer, "Unsaved changes! Press ESC again to quit", Color::Yellow);
-                    self.draw(writer);
-                    let scancode2 = self.wait_for_key();
-                    if scancode2 == 0x01 {
-                        break;
-                    }
-                    continue;
-                } else {
+                if self.confirm_quit(writer, "Unsaved changes! Press ESC again to quit", Some(0x01)) {
                     break;
                 }
+                self.draw(writer);
+                continue;
             }
 
             let needs_redraw = match scancode {
@@ -195,9 +205,25 @@ impl Editor {
                 }
             }
         }
+        EditorExit::Closed
+    }
+
+    /// Whether to quit: at once with nothing unsaved, otherwise once the
+    /// user answers `prompt` with `key` or Ctrl+C. Ctrl+Z or any other
+    /// key keeps editing
+    fn confirm_quit(&self, writer: &mut Console, prompt: &str, key: Option<u8>) -> bool {
+        if !self.modified {
+            return true;
+        }
+        self.show_message(writer, prompt, Color::Yellow);
+        self.draw(writer);
+        match self.wait_for_key() {
+            Some(scancode) => Some(scancode) == key,
+            None => process::take_signal() == Some(process::Signal::Interrupt),
+        }
     }
 
-    fn draw(&self, writer: &mut Writer) {
+    fn draw(&self, writer: &mut Console) {
         writer.set_position(0, 0);
 
         for screen_row in 0..EDITOR_HEIGHT {
@@ -288,24 +314,28 @@ impl Editor {
         writer.set_position(self.cursor_x, screen_y);
     }
 
-    fn show_message(&self, writer: &mut Writer, msg: &str, color: Color) {
+    fn show_message(&self, writer: &mut Console, msg: &str, color: Color) {
         writer.set_position(0, 24);
         writer.set_color(color, Color::Black);
         writer.write_str(msg);
         
         for _ in 0..10 {
-            idt::wait_for_interrupt();
+            thread::idle();
         }
     }
 
-    fn wait_for_key(&self) -> u8 {
+    /// The next key press, or `None` if a signal came first
+    fn wait_for_key(&self) -> Option<u8> {
         loop {
+            if process::signal_pending() {
+                return None;
+            }
             if let Some(sc) = idt::get_scancode() {
                 if sc & 0x80 == 0 {
-                    return sc;
+                    return Some(sc);
                 }
             }
-            idt::wait_for_interrupt();
+            thread::idle();
         }
     }
 
@@ -489,10 +519,67 @@ impl Editor {
             }
         }
 
-        let fs = get_filesystem();
+        let mut fs = get_filesystem();
         fs.write_file(&self.filename[..self.filename_len], &content[..pos])?;
         self.modified = false;
 
         Ok(())
     }
 }
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+
+    fn line(editor: &Editor, index: usize) -> &[u8] {
+        &editor.lines[index][..editor.line_lengths[index]]
+    }
+
+    pub fn load_splits_lines() {
+        let mut editor = Editor::new();
+        editor.load_content(b"one\ntwo\n\nfour");
+        assert_eq!(editor.num_lines, 4);
+        assert_eq!(line(&editor, 0), b"one");
+        assert_eq!(line(&editor, 1), b"two");
+        assert_eq!(line(&editor, 2), b"");
+        assert_eq!(line(&editor, 3), b"four");
+        assert!(!editor.modified);
+    }
+
+    pub fn insert_newline_and_backspace() {
+        let mut editor = Editor::new();
+        editor.load_content(b"hello");
+        editor.cursor_x = 2;
+
+        editor.insert_newline();
+        assert_eq!(editor.num_lines, 2);
+        assert_eq!(line(&editor, 0), b"he");
+        assert_eq!(line(&editor, 1), b"llo");
+        assert_eq!((editor.cursor_x, editor.cursor_y), (0, 1));
+
+        editor.backspace();
+        assert_eq!(editor.num_lines, 1);
+        assert_eq!(line(&editor, 0), b"hello");
+        assert_eq!((editor.cursor_x, editor.cursor_y), (2, 0));
+        assert!(editor.modified);
+    }
+
+    pub fn save_round_trips_content() {
+        {
+            let mut fs = get_filesystem();
+            let _ = fs.delete_file(b"editor_test.txt");
+            assert!(fs.create_file(b"editor_test.txt", b"ab\ncd").is_ok());
+        }
+
+        // The editor takes the file system lock itself
+        let mut editor = Editor::new();
+        assert!(editor.open(b"editor_test.txt").is_ok());
+        editor.insert_char(b'!');
+        assert!(editor.save().is_ok());
+        assert!(!editor.modified);
+
+        let mut fs = get_filesystem();
+        assert_eq!(fs.read_file(b"editor_test.txt"), Some(&b"!ab\ncd"[..]));
+        let _ = fs.delete_file(b"editor_test.txt");
+    }
+}
@@ -0,0 +1,272 @@
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const CLASS_32: u8 = 1;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+
+const TYPE_EXECUTABLE: u16 = 2;
+const TYPE_SHARED: u16 = 3;
+const MACHINE_386: u16 = 3;
+
+const SEGMENT_LOAD: u32 = 1;
+const SEGMENT_INTERP: u32 = 3;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u32,
+    program_header_offset: u32,
+    section_header_offset: u32,
+    flags: u32,
+    header_size: u16,
+    program_header_size: u16,
+    program_header_count: u16,
+    section_header_size: u16,
+    section_header_count: u16,
+    section_names_index: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    offset: u32,
+    virtual_address: u32,
+    physical_address: u32,
+    file_size: u32,
+    memory_size: u32,
+    flags: u32,
+    align: u32,
+}
+
+/// A loadable segment: `file_size` bytes from `offset` in the file, then
+/// zeroes up to `memory_size`, placed at `address`
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub address: u32,
+    pub memory_size: u32,
+    pub offset: u32,
+    pub file_size: u32,
+}
+
+impl Segment {
+    pub fn end(&self) -> u32 {
+        self.address + self.memory_size
+    }
+}
+
+/// A validated statically linked i386 executable
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub entry: u32,
+    program_headers: usize,
+    program_header_size: usize,
+    program_header_count: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Checks the header and that every program header, and the file
+    /// contents of every loadable segment, lie inside `data`
+    pub fn parse(data: &'a [u8]) -> Result<Self, &'static str> {
+        if data.len() < size_of::<ElfHeader>() {
+            return Err("File too short for an ELF header");
+        }
+        let header = unsafe { read_unaligned(data.as_ptr() as *const ElfHeader) };
+
+        if header.ident[..4] != ELF_MAGIC {
+            return Err("Not an ELF file");
+        }
+        if header.ident[4] != CLASS_32 || header.ident[5] != DATA_LITTLE_ENDIAN {
+            return Err("Not a 32-bit little-endian ELF file");
+        }
+        if header.ident[6] != VERSION_CURRENT {
+            return Err("Unknown ELF version");
+        }
+        let kind = header.kind;
+        if kind == TYPE_SHARED {
+            return Err("Position-independent executables are not supported");
+        }
+        if kind != TYPE_EXECUTABLE {
+            return Err("Not an executable");
+        }
+        if header.machine != MACHINE_386 {
+            return Err("Not an i386 executable");
+        }
+
+        let program_header_size = header.program_header_size as usize;
+        let program_header_count = header.program_header_count as usize;
+        let program_headers = header.program_header_offset as usize;
+        if program_header_count == 0 {
+            return Err("No program headers");
+        }
+        if program_header_size < size_of::<ProgramHeader>() {
+            return Err("Program headers too small");
+        }
+        let table_end = program_header_count
+            .checked_mul(program_header_size)
+            .and_then(|size| size.checked_add(program_headers));
+        if table_end.map_or(true, |end| end > data.len()) {
+            return Err("Program headers outside the file");
+        }
+
+        let elf = Elf {
+            data,
+            entry: header.entry,
+            program_headers,
+            program_header_size,
+            program_header_count,
+        };
+        for index in 0..program_header_count {
+            let ph = elf.program_header(index);
+            if ph.kind == SEGMENT_INTERP {
+                return Err("Dynamically linked executables are not supported");
+            }
+            if ph.kind != SEGMENT_LOAD {
+                continue;
+            }
+            if ph.file_size > ph.memory_size {
+                return Err("Segment larger in the file than in memory");
+            }
+            if ph.virtual_address.checked_add(ph.memory_size).is_none() {
+                return Err("Segment wraps around the address space");
+            }
+            let file_end = (ph.offset as usize).checked_add(ph.file_size as usize);
+            if file_end.map_or(true, |end| end > data.len()) {
+                return Err("Segment outside the file");
+            }
+        }
+        if elf.segments().next().is_none() {
+            return Err("No loadable segments");
+        }
+        Ok(elf)
+    }
+
+    fn program_header(&self, index: usize) -> ProgramHeader {
+        let offset = self.program_headers + index * self.program_header_size;
+        unsafe { read_unaligned(self.data[offset..].as_ptr() as *const ProgramHeader) }
+    }
+
+    pub fn segments<'e>(&'e self) -> SegmentIter<'e, 'a> {
+        SegmentIter { elf: self, index: 0 }
+    }
+
+    /// The bytes of `segment` stored in the file
+    pub fn file_data(&self, segment: &Segment) -> &'a [u8] {
+        let start = segment.offset as usize;
+        &self.data[start..start + segment.file_size as usize]
+    }
+}
+
+pub struct SegmentIter<'e, 'a: 'e> {
+    elf: &'e Elf<'a>,
+    index: usize,
+}
+
+impl<'e, 'a> Iterator for SegmentIter<'e, 'a> {
+    type Item = Segment;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.elf.program_header_count {
+            let ph = self.elf.program_header(self.index);
+            self.index += 1;
+            if ph.kind == SEGMENT_LOAD && ph.memory_size > 0 {
+                return Some(Segment {
+                    address: ph.virtual_address,
+                    memory_size: ph.memory_size,
+                    offset: ph.offset,
+                    file_size: ph.file_size,
+                });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const HEADER_SIZE: usize = 52;
+    const PROGRAM_HEADER_SIZE: usize = 32;
+    // PF_R | PF_W | PF_X
+    const READ_WRITE_EXECUTE: u32 = 7;
+
+    fn push_u16(out: &mut Vec<u8>, value: u16) {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push_u32(out: &mut Vec<u8>, value: u32) {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// A minimal executable with one RWX segment holding `code` at
+    /// `address`, entered at its first byte
+    pub fn build(address: u32, code: &[u8], bss: u32) -> Vec<u8> {
+        let code_offset = (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u32;
+        let mut out = Vec::new();
+        out.extend_from_slice(&ELF_MAGIC);
+        out.extend_from_slice(&[CLASS_32, DATA_LITTLE_ENDIAN, VERSION_CURRENT]);
+        out.resize(16, 0);
+        push_u16(&mut out, TYPE_EXECUTABLE);
+        push_u16(&mut out, MACHINE_386);
+        push_u32(&mut out, 1);
+        push_u32(&mut out, address);
+        push_u32(&mut out, HEADER_SIZE as u32);
+        push_u32(&mut out, 0);
+        push_u32(&mut out, 0);
+        push_u16(&mut out, HEADER_SIZE as u16);
+        push_u16(&mut out, PROGRAM_HEADER_SIZE as u16);
+        push_u16(&mut out, 1);
+        push_u16(&mut out, 0);
+        push_u16(&mut out, 0);
+        push_u16(&mut out, 0);
+
+        push_u32(&mut out, SEGMENT_LOAD);
+        push_u32(&mut out, code_offset);
+        push_u32(&mut out, address);
+        push_u32(&mut out, address);
+        push_u32(&mut out, code.len() as u32);
+        push_u32(&mut out, code.len() as u32 + bss);
+        push_u32(&mut out, READ_WRITE_EXECUTE);
+        push_u32(&mut out, 0x1000);
+
+        out.extend_from_slice(code);
+        out
+    }
+
+    pub fn parses_minimal_executable() {
+        let file = build(0x4000_0000, &[0x90, 0xC3], 16);
+        let elf = Elf::parse(&file).expect("parse failed");
+        assert_eq!(elf.entry, 0x4000_0000);
+
+        let segments: Vec<Segment> = elf.segments().collect();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].address, 0x4000_0000);
+        assert_eq!(segments[0].end(), 0x4000_0012);
+        assert_eq!(elf.file_data(&segments[0]), &[0x90, 0xC3]);
+    }
+
+    pub fn rejects_malformed_files() {
+        assert!(Elf::parse(b"\x7fELF").is_err());
+
+        let good = build(0x4000_0000, &[0x90], 0);
+        let mut bad_magic = good.clone();
+        bad_magic[0] = 0;
+        assert_eq!(Elf::parse(&bad_magic).err(), Some("Not an ELF file"));
+
+        let mut wrong_machine = good.clone();
+        wrong_machine[18] = 62;
+        assert_eq!(Elf::parse(&wrong_machine).err(), Some("Not an i386 executable"));
+
+        // Truncating the file cuts into the segment's contents
+        let truncated = &good[..good.len() - 1];
+        assert_eq!(Elf::parse(truncated).err(), Some("Segment outside the file"));
+    }
+}
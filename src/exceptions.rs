@@ -0,0 +1,243 @@
+use core::arch::{asm, naked_asm};
+use core::fmt::Write;
+
+use crate::gdt;
+use crate::idt;
+use crate::usermode;
+use crate::vga_colors::{Color, color_code};
+use crate::writer::Writer;
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "Divide Error",
+    "Debug",
+    "Non-Maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "Bound Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection Fault",
+    "Page Fault",
+    "Reserved",
+    "x87 Floating-Point Exception",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+const DOUBLE_FAULT: usize = 8;
+const PAGE_FAULT: u32 = 14;
+
+/// Stack layout built by the stubs below: `pusha`, the vector number, the
+/// error code (or a dummy 0), then what the CPU pushed
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    pub esp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+    pub vector: u32,
+    pub error_code: u32,
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+}
+
+impl ExceptionFrame {
+    /// ESP at the moment of the fault (the `esp` pushed by `pusha` points
+    /// at the vector number instead)
+    pub fn interrupted_esp(&self) -> u32 {
+        self.esp + 20
+    }
+}
+
+pub fn exception_name(vector: u32) -> &'static str {
+    EXCEPTION_NAMES.get(vector as usize).copied().unwrap_or("Unknown")
+}
+
+// Vectors 8, 10-14, 17, 21, 29 and 30 have the CPU push an error code; the
+// rest push a dummy one so every frame looks the same
+macro_rules! exception_stub {
+    ($name:ident, $vector:expr) => {
+        #[unsafe(naked)]
+        unsafe extern "C" fn $name() {
+            naked_asm!(
+                "push 0",
+                "push {vector}",
+                "jmp exception_common",
+                vector = const $vector,
+            );
+        }
+    };
+    ($name:ident, $vector:expr, error_code) => {
+        #[unsafe(naked)]
+        unsafe extern "C" fn $name() {
+            naked_asm!(
+                "push {vector}",
+                "jmp exception_common",
+                vector = const $vector,
+            );
+        }
+    };
+}
+
+exception_stub!(exception_0, 0);
+exception_stub!(exception_1, 1);
+exception_stub!(exception_2, 2);
+exception_stub!(exception_3, 3);
+exception_stub!(exception_4, 4);
+exception_stub!(exception_5, 5);
+exception_stub!(exception_6, 6);
+exception_stub!(exception_7, 7);
+exception_stub!(exception_8, 8, error_code);
+exception_stub!(exception_9, 9);
+exception_stub!(exception_10, 10, error_code);
+exception_stub!(exception_11, 11, error_code);
+exception_stub!(exception_12, 12, error_code);
+exception_stub!(exception_13, 13, error_code);
+exception_stub!(exception_14, 14, error_code);
+exception_stub!(exception_15, 15);
+exception_stub!(exception_16, 16);
+exception_stub!(exception_17, 17, error_code);
+exception_stub!(exception_18, 18);
+exception_stub!(exception_19, 19);
+exception_stub!(exception_20, 20);
+exception_stub!(exception_21, 21, error_code);
+exception_stub!(exception_22, 22);
+exception_stub!(exception_23, 23);
+exception_stub!(exception_24, 24);
+exception_stub!(exception_25, 25);
+exception_stub!(exception_26, 26);
+exception_stub!(exception_27, 27);
+exception_stub!(exception_28, 28);
+exception_stub!(exception_29, 29, error_code);
+exception_stub!(exception_30, 30, error_code);
+exception_stub!(exception_31, 31);
+
+#[unsafe(naked)]
+#[no_mangle]
+pub unsafe extern "C" fn exception_common() {
+    naked_asm!(
+        "pusha",
+        "push esp",
+        "call exception_handler_inner",
+        "add esp, 4",
+        "popa",
+        "add esp, 8",
+        "iretd",
+    );
+}
+
+pub fn init() {
+    let stubs: [unsafe extern "C" fn(); 32] = [
+        exception_0, exception_1, exception_2, exception_3,
+        exception_4, exception_5, exception_6, exception_7,
+        exception_8, exception_9, exception_10, exception_11,
+        exception_12, exception_13, exception_14, exception_15,
+        exception_16, exception_17, exception_18, exception_19,
+        exception_20, exception_21, exception_22, exception_23,
+        exception_24, exception_25, exception_26, exception_27,
+        exception_28, exception_29, exception_30, exception_31,
+    ];
+
+    for (vector, stub) in stubs.iter().enumerate() {
+        idt::set_handler(vector, *stub as u32);
+    }
+
+    // A double fault is often a kernel stack overflow, so it switches to a
+    // task with its own stack instead of pushing onto the broken one
+    idt::set_task_gate(DOUBLE_FAULT, gdt::DOUBLE_FAULT_TSS_SELECTOR);
+}
+
+#[no_mangle]
+pub extern "C" fn exception_handler_inner(frame: &ExceptionFrame) {
+    // A fault in ring 3 is the program's problem, not the kernel's
+    if frame.cs & 3 == 3 {
+        usermode::kill(frame);
+    }
+
+    unsafe {
+        asm!("cli", options(nostack));
+    }
+    show_panic_screen(frame);
+
+    loop {
+        unsafe {
+            asm!("hlt", options(nostack));
+        }
+    }
+}
+
+pub fn show_panic_screen(frame: &ExceptionFrame) {
+    let (cr2, cr3): (u32, u32);
+    unsafe {
+        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack));
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack));
+    }
+
+    let mut writer = Writer::new(color_code(Color::White, Color::Blue));
+    writer.clear();
+
+    writer.set_color(Color::White, Color::Red);
+    writer.write_str(" KERNEL PANIC - CPU EXCEPTION \n\n");
+
+    writer.set_color(Color::Yellow, Color::Blue);
+    writer.write_str(exception_name(frame.vector));
+    let _ = writeln!(writer, " (vector {})", frame.vector);
+
+    writer.set_color(Color::White, Color::Blue);
+    let _ = write!(writer, "Error code: {:#010x}", frame.error_code);
+    if frame.vector == PAGE_FAULT {
+        writer.write_str("  (");
+        writer.write_str(if frame.error_code & 1 != 0 { "protection violation" } else { "page not present" });
+        writer.write_str(if frame.error_code & 2 != 0 { ", write" } else { ", read" });
+        writer.write_str(if frame.error_code & 4 != 0 { ", user mode)" } else { ", kernel mode)" });
+    }
+    writer.write_str("\n\n");
+
+    write_register(&mut writer, "EIP", frame.eip);
+    write_register(&mut writer, "CS", frame.cs);
+    write_register(&mut writer, "EFLAGS", frame.eflags);
+    writer.write_byte(b'\n');
+    write_register(&mut writer, "EAX", frame.eax);
+    write_register(&mut writer, "EBX", frame.ebx);
+    write_register(&mut writer, "ECX", frame.ecx);
+    write_register(&mut writer, "EDX", frame.edx);
+    writer.write_byte(b'\n');
+    write_register(&mut writer, "ESI", frame.esi);
+    write_register(&mut writer, "EDI", frame.edi);
+    write_register(&mut writer, "EBP", frame.ebp);
+    write_register(&mut writer, "ESP", frame.interrupted_esp());
+    writer.write_byte(b'\n');
+    write_register(&mut writer, "CR2", cr2);
+    write_register(&mut writer, "CR3", cr3);
+    writer.write_str("\n\n");
+
+    writer.set_color(Color::LightGray, Color::Blue);
+    writer.write_str("System halted.\n");
+}
+
+fn write_register(writer: &mut Writer, name: &str, value: u32) {
+    let _ = write!(writer, "{}={:#010x}  ", name, value);
+}
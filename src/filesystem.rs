@@ -1,3 +1,7 @@
+use crate::process;
+use crate::rtc;
+use crate::sync::{SpinLock, SpinLockGuard};
+
 const MAX_FILES: usize = 32;
 const MAX_FILENAME_LEN: usize = 32;
 const MAX_FILE_SIZE: usize = 4096;
@@ -12,6 +16,8 @@ pub struct File {
     pub size: usize,
     pub is_used: bool,
     pub parent_dir: usize,
+    // Unix time of the last write, from the RTC
+    pub modified: u64,
 }
 
 impl File {
@@ -23,6 +29,7 @@ impl File {
             size: 0,
             is_used: false,
             parent_dir: 0,
+            modified: 0,
         }
     }
 }
@@ -98,6 +105,7 @@ impl FileSystem {
                 file.size = content.len();
                 file.is_used = true;
                 file.parent_dir = self.current_dir;
+                file.modified = rtc::unix_time();
                 return Ok(());
             }
         }
@@ -129,6 +137,7 @@ impl FileSystem {
             {
                 file.data[..content.len()].copy_from_slice(content);
                 file.size = content.len();
+                file.modified = rtc::unix_time();
                 return Ok(());
             }
         }
@@ -147,6 +156,7 @@ impl FileSystem {
                 }
                 file.data[file.size..file.size + content.len()].copy_from_slice(content);
                 file.size += content.len();
+                file.modified = rtc::unix_time();
                 return Ok(());
             }
         }
@@ -246,6 +256,11 @@ impl FileSystem {
         Err("Directory not found")
     }
 
+    /// Index of the current directory, for `process::set_current_dir`
+    pub fn current_dir(&self) -> usize {
+        self.current_dir
+    }
+
     pub fn get_current_path(&self, buffer: &mut [u8; MAX_PATH_LEN]) -> usize {
         if self.current_dir == 0 {
             buffer[0] = b'/';
@@ -324,7 +339,8 @@ pub struct FileIterator<'a> {
 }
 
 pub enum FileEntry<'a> {
-    File(&'a [u8], usize),
+    // Name, size and modification time
+    File(&'a [u8], usize, u64),
     Directory(&'a [u8]),
 }
 
@@ -353,7 +369,7 @@ impl<'a> Iterator for FileIterator<'a> {
                         
                         let file = &self.files[i];
                         if file.is_used && file.parent_dir == self.current_dir {
-                            return Some(FileEntry::File(&file.name[..file.name_len], file.size));
+                            return Some(FileEntry::File(&file.name[..file.name_len], file.size, file.modified));
                         }
                     }
                     self.phase = ListPhase::Done;
@@ -364,8 +380,125 @@ impl<'a> Iterator for FileIterator<'a> {
     }
 }
 
-static mut FILE_SYSTEM: FileSystem = FileSystem::new();
+static FILE_SYSTEM: SpinLock<FileSystem> = SpinLock::new(FileSystem::new());
+
+/// Locks the file system until the guard is dropped; keep it out of scope
+/// before calling anything else that opens files. Names resolve in the
+/// calling process's current directory
+pub fn get_filesystem() -> SpinLockGuard<'static, FileSystem> {
+    let cwd = process::current_dir();
+    let mut fs = FILE_SYSTEM.lock();
+    // Another process may have removed it since
+    fs.current_dir = if fs.directories.get(cwd).map_or(false, |d| d.is_used) { cwd } else { 0 };
+    fs
+}
+
+/// Shutdown hook. Files only live in RAM, so there is nothing to write back
+/// yet; report what is about to be lost
+pub fn sync() {
+    // Whoever holds the lock is not coming back once we power off
+    let fs = match FILE_SYSTEM.try_lock() {
+        Some(fs) => fs,
+        None => {
+            warn!("File system busy, discarding in-memory files");
+            return;
+        }
+    };
+    let files = fs.files.iter().filter(|f| f.is_used).count();
+    let bytes: usize = fs.files.iter().filter(|f| f.is_used).map(|f| f.size).sum();
+    warn!("Discarding {} in-memory files ({} bytes)", files, bytes);
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+
+    // Far too big for the kernel stack, so the tests share one instance
+    static TEST_FS: SpinLock<FileSystem> = SpinLock::new(FileSystem::new());
+
+    fn fresh_fs() -> SpinLockGuard<'static, FileSystem> {
+        let mut fs = TEST_FS.lock();
+        for file in fs.files.iter_mut() {
+            file.is_used = false;
+            file.size = 0;
+            file.name_len = 0;
+        }
+        for dir in fs.directories.iter_mut().skip(1) {
+            dir.is_used = false;
+            dir.name_len = 0;
+        }
+        fs.current_dir = 0;
+        fs
+    }
+
+    pub fn create_and_read_file() {
+        let mut fs = fresh_fs();
+        assert!(fs.create_file(b"notes.txt", b"hello").is_ok());
+        assert!(fs.file_exists(b"notes.txt"));
+        assert_eq!(fs.read_file(b"notes.txt"), Some(&b"hello"[..]));
+        assert_eq!(fs.read_file(b"missing.txt"), None);
+    }
 
-pub fn get_filesystem() -> &'static mut FileSystem {
-    unsafe { &mut FILE_SYSTEM }
+    pub fn writes_record_modification_time() {
+        let mut fs = fresh_fs();
+        let before = rtc::unix_time();
+        assert!(fs.create_file(b"stamped", b"1").is_ok());
+        let modified = fs.list_files().find_map(|entry| match entry {
+            FileEntry::File(name, _, modified) if name == b"stamped" => Some(modified),
+            _ => None,
+        });
+        let modified = modified.expect("file not listed");
+        assert!(modified >= before && modified <= rtc::unix_time());
+    }
+
+    pub fn duplicate_file_is_rejected() {
+        let mut fs = fresh_fs();
+        assert!(fs.create_file(b"a", b"1").is_ok());
+        assert_eq!(fs.create_file(b"a", b"2"), Err("File already exists"));
+        assert_eq!(fs.create_file(&[b'x'; MAX_FILENAME_LEN + 1], b""), Err("Filename too long"));
+    }
+
+    pub fn write_append_and_delete() {
+        let mut fs = fresh_fs();
+        assert!(fs.write_file(b"log", b"one").is_ok());
+        assert!(fs.append_file(b"log", b",two").is_ok());
+        assert_eq!(fs.read_file(b"log"), Some(&b"one,two"[..]));
+
+        assert!(fs.write_file(b"log", b"reset").is_ok());
+        assert_eq!(fs.read_file(b"log"), Some(&b"reset"[..]));
+
+        assert!(fs.delete_file(b"log").is_ok());
+        assert!(!fs.file_exists(b"log"));
+        assert_eq!(fs.delete_file(b"log"), Err("File not found"));
+    }
+
+    pub fn directories_scope_files() {
+        let mut fs = fresh_fs();
+        assert!(fs.create_directory(b"docs").is_ok());
+        assert!(fs.change_directory(b"docs").is_ok());
+        assert!(fs.create_file(b"inner", b"x").is_ok());
+
+        let mut path = [0u8; MAX_PATH_LEN];
+        let len = fs.get_current_path(&mut path);
+        assert_eq!(&path[..len], b"/docs");
+
+        assert!(fs.change_directory(b"..").is_ok());
+        assert!(!fs.file_exists(b"inner"));
+        assert_eq!(fs.change_directory(b"nope"), Err("Directory not found"));
+    }
+
+    pub fn remove_non_empty_directory_fails() {
+        let mut fs = fresh_fs();
+        assert!(fs.create_directory(b"full").is_ok());
+        assert!(fs.change_directory(b"full").is_ok());
+        assert!(fs.create_file(b"f", b"").is_ok());
+        assert!(fs.change_directory(b"/").is_ok());
+
+        assert_eq!(fs.remove_directory(b"full"), Err("Directory not empty"));
+
+        assert!(fs.change_directory(b"full").is_ok());
+        assert!(fs.delete_file(b"f").is_ok());
+        assert!(fs.change_directory(b"..").is_ok());
+        assert!(fs.remove_directory(b"full").is_ok());
+    }
 }
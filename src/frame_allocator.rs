@@ -0,0 +1,285 @@
+use crate::multiboot::{self, MemoryRegionKind};
+use crate::sync::{IrqSpinLock, IrqSpinLockGuard};
+
+pub const FRAME_SIZE: u32 = 4096;
+
+// One bit per 4 KiB frame across the whole 32-bit physical address space
+const MAX_FRAMES: usize = 1024 * 1024;
+const BITMAP_WORDS: usize = MAX_FRAMES / 32;
+
+/// Everything below 1 MB (real mode IVT, BIOS data, VGA memory, option ROMs)
+pub const LOW_MEMORY_END: u32 = 0x100000;
+
+extern "C" {
+    static kernel_start: u8;
+    static kernel_end: u8;
+}
+
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+pub struct FrameAllocator {
+    // A set bit means the frame is in use or does not exist
+    bitmap: [u32; BITMAP_WORDS],
+    // A set bit means the frame is RAM the allocator hands out, so only
+    // these may come back through `free_frame`
+    available: [u32; BITMAP_WORDS],
+    total_frames: usize,
+    free_frames: usize,
+    next_search: usize,
+}
+
+impl FrameAllocator {
+    pub const fn new() -> Self {
+        Self {
+            bitmap: [0; BITMAP_WORDS],
+            available: [0; BITMAP_WORDS],
+            total_frames: 0,
+            free_frames: 0,
+            next_search: 0,
+        }
+    }
+
+    pub fn init(&mut self) -> Result<(), &'static str> {
+        let info = multiboot::boot_info().ok_or("No boot memory map")?;
+
+        for word in self.bitmap.iter_mut() {
+            *word = 0xFFFF_FFFF;
+        }
+        for word in self.available.iter_mut() {
+            *word = 0;
+        }
+        self.total_frames = 0;
+        self.free_frames = 0;
+        self.next_search = 0;
+
+        let mut has_map = false;
+        for region in info.memory_map() {
+            has_map = true;
+            if region.kind == MemoryRegionKind::Usable {
+                self.add_region(region.base, region.end());
+            }
+        }
+
+        if !has_map {
+            let upper_kb = info.upper_memory_kb().ok_or("No boot memory map")?;
+            self.add_region(LOW_MEMORY_END as u64, LOW_MEMORY_END as u64 + upper_kb as u64 * 1024);
+        }
+
+        self.reserve_range(0, LOW_MEMORY_END);
+
+        let (start, end) = kernel_bounds();
+        self.reserve_range(start, end);
+
+        for module in info.modules() {
+            self.reserve_range(module.start, module.end);
+        }
+        info.for_each_boot_data(|start, end| self.reserve_range(start, end));
+
+        Ok(())
+    }
+
+    /// Marks every whole frame inside [start, end) as free
+    fn add_region(&mut self, start: u64, end: u64) {
+        let end = end.min(MAX_FRAMES as u64 * FRAME_SIZE as u64);
+        let first = ((start + FRAME_SIZE as u64 - 1) / FRAME_SIZE as u64) as usize;
+        let last = (end / FRAME_SIZE as u64) as usize;
+
+        for frame in first..last {
+            if self.is_used(frame) {
+                self.clear_bit(frame);
+                self.available[frame / 32] |= 1 << (frame % 32);
+                self.total_frames += 1;
+                self.free_frames += 1;
+            }
+        }
+    }
+
+    /// Marks every frame touching [start, end) as used for good
+    pub fn reserve_range(&mut self, start: u32, end: u32) {
+        if end <= start {
+            return;
+        }
+        let first = (start / FRAME_SIZE) as usize;
+        let last = ((end as u64 + FRAME_SIZE as u64 - 1) / FRAME_SIZE as u64) as usize;
+
+        for frame in first..last.min(MAX_FRAMES) {
+            if !self.is_used(frame) {
+                self.set_bit(frame);
+                self.free_frames -= 1;
+            }
+            self.available[frame / 32] &= !(1 << (frame % 32));
+        }
+    }
+
+    /// Returns the physical address of a free 4 KiB frame
+    pub fn allocate_frame(&mut self) -> Option<u32> {
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        let start_word = self.next_search / 32;
+        for i in 0..BITMAP_WORDS {
+            let word_idx = (start_word + i) % BITMAP_WORDS;
+            let word = self.bitmap[word_idx];
+            if word == 0xFFFF_FFFF {
+                continue;
+            }
+
+            let bit = (!word).trailing_zeros() as usize;
+            let frame = word_idx * 32 + bit;
+            self.set_bit(frame);
+            self.free_frames -= 1;
+            self.next_search = frame + 1;
+            return Some(frame as u32 * FRAME_SIZE);
+        }
+
+        None
+    }
+
+    /// Returns the physical address of `count` adjacent free frames
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<u32> {
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+
+        let mut run_start = 0;
+        let mut run_len = 0;
+        for frame in 0..MAX_FRAMES {
+            if self.is_used(frame) {
+                run_len = 0;
+                continue;
+            }
+
+            if run_len == 0 {
+                run_start = frame;
+            }
+            run_len += 1;
+
+            if run_len == count {
+                for f in run_start..run_start + count {
+                    self.set_bit(f);
+                }
+                self.free_frames -= count;
+                return Some(run_start as u32 * FRAME_SIZE);
+            }
+        }
+
+        None
+    }
+
+    pub fn free_frame(&mut self, addr: u32) -> Result<(), &'static str> {
+        if addr % FRAME_SIZE != 0 {
+            return Err("Frame address not aligned");
+        }
+
+        let frame = (addr / FRAME_SIZE) as usize;
+        if !self.is_available(frame) {
+            return Err("Frame was never allocatable");
+        }
+        if !self.is_used(frame) {
+            return Err("Frame already free");
+        }
+
+        self.clear_bit(frame);
+        self.free_frames += 1;
+        if frame < self.next_search {
+            self.next_search = frame;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total_frames,
+            used: self.total_frames - self.free_frames,
+            free: self.free_frames,
+        }
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 32] & (1 << (frame % 32)) != 0
+    }
+
+    fn is_available(&self, frame: usize) -> bool {
+        self.available[frame / 32] & (1 << (frame % 32)) != 0
+    }
+
+    fn set_bit(&mut self, frame: usize) {
+        self.bitmap[frame / 32] |= 1 << (frame % 32);
+    }
+
+    fn clear_bit(&mut self, frame: usize) {
+        self.bitmap[frame / 32] &= !(1 << (frame % 32));
+    }
+}
+
+/// Physical bounds of the loaded kernel image, taken from linker.ld
+pub fn kernel_bounds() -> (u32, u32) {
+    unsafe {
+        (
+            &kernel_start as *const u8 as u32,
+            &kernel_end as *const u8 as u32,
+        )
+    }
+}
+
+// Page tables are built and torn down from any thread, so the bitmap is
+// only ever reached through the lock
+static FRAME_ALLOCATOR: IrqSpinLock<FrameAllocator> = IrqSpinLock::new(FrameAllocator::new());
+
+/// Locks the frame allocator. Keep the guard short-lived: paging and the
+/// heap take it too
+pub fn get_frame_allocator() -> IrqSpinLockGuard<'static, FrameAllocator> {
+    FRAME_ALLOCATOR.lock()
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+
+    pub fn allocate_and_free_frame() {
+        let mut allocator = get_frame_allocator();
+        let before = allocator.stats();
+
+        let frame = allocator.allocate_frame().expect("no free frames");
+        assert_eq!(frame % FRAME_SIZE, 0);
+        assert!(frame >= LOW_MEMORY_END);
+        assert_eq!(allocator.stats().free, before.free - 1);
+
+        assert!(allocator.free_frame(frame).is_ok());
+        assert_eq!(allocator.free_frame(frame), Err("Frame already free"));
+        assert_eq!(allocator.stats().free, before.free);
+    }
+
+    pub fn cannot_free_reserved_frames() {
+        let mut allocator = get_frame_allocator();
+        let before = allocator.stats();
+        let (kernel, _) = kernel_bounds();
+
+        assert_eq!(allocator.free_frame(0), Err("Frame was never allocatable"));
+        assert_eq!(allocator.free_frame(kernel & !(FRAME_SIZE - 1)), Err("Frame was never allocatable"));
+        // Past the end of RAM in any machine the tests run on
+        assert_eq!(allocator.free_frame(0xFFFF_F000), Err("Frame was never allocatable"));
+        assert_eq!(allocator.stats().free, before.free);
+    }
+
+    pub fn boot_data_is_reserved() {
+        let info = multiboot::boot_info().expect("no boot info");
+        let allocator = get_frame_allocator();
+        let mut ranges = 0;
+        info.for_each_boot_data(|start, end| {
+            ranges += 1;
+            if end <= start {
+                return;
+            }
+            for frame in (start / FRAME_SIZE)..=((end - 1) / FRAME_SIZE) {
+                assert!(allocator.is_used(frame as usize), "boot data frame {:#x} is free", frame * FRAME_SIZE);
+            }
+        });
+        assert!(ranges > 0);
+    }
+}
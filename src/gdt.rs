@@ -0,0 +1,282 @@
+use core::arch::{asm, naked_asm};
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+
+use crate::acpi::MAX_CPUS;
+use crate::exceptions::{self, ExceptionFrame};
+use crate::sync::IrqSpinLock;
+
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+pub const USER_CODE_SELECTOR: u16 = 0x18 | 3;
+pub const USER_DATA_SELECTOR: u16 = 0x20 | 3;
+pub const DOUBLE_FAULT_TSS_SELECTOR: u16 = 0x28;
+
+// One TSS per CPU from here on, so each has its own ring 0 stack
+const FIRST_TSS_ENTRY: usize = 6;
+const GDT_SIZE: usize = FIRST_TSS_ENTRY + MAX_CPUS;
+const DOUBLE_FAULT_STACK_SIZE: usize = 8192;
+
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct GdtEntry {
+    limit_low: u16,
+    base_low: u16,
+    base_middle: u8,
+    access: u8,
+    granularity: u8,
+    base_high: u8,
+}
+
+impl GdtEntry {
+    const fn null() -> Self {
+        Self::new(0, 0, 0, 0)
+    }
+
+    const fn new(base: u32, limit: u32, access: u8, flags: u8) -> Self {
+        Self {
+            limit_low: (limit & 0xFFFF) as u16,
+            base_low: (base & 0xFFFF) as u16,
+            base_middle: ((base >> 16) & 0xFF) as u8,
+            access,
+            granularity: (flags & 0xF0) | ((limit >> 16) & 0x0F) as u8,
+            base_high: ((base >> 24) & 0xFF) as u8,
+        }
+    }
+}
+
+#[repr(C, packed)]
+struct GdtPointer {
+    limit: u16,
+    base: u32,
+}
+
+/// Hardware task state. Segment fields are 32 bits wide here, the upper
+/// half of each is reserved by the CPU.
+#[repr(C)]
+pub struct TaskStateSegment {
+    pub prev_task: u32,
+    pub esp0: u32,
+    pub ss0: u32,
+    pub esp1: u32,
+    pub ss1: u32,
+    pub esp2: u32,
+    pub ss2: u32,
+    pub cr3: u32,
+    pub eip: u32,
+    pub eflags: u32,
+    pub eax: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub ebx: u32,
+    pub esp: u32,
+    pub ebp: u32,
+    pub esi: u32,
+    pub edi: u32,
+    pub es: u32,
+    pub cs: u32,
+    pub ss: u32,
+    pub ds: u32,
+    pub fs: u32,
+    pub gs: u32,
+    pub ldt: u32,
+    pub trap: u16,
+    pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    const fn empty() -> Self {
+        Self {
+            prev_task: 0,
+            esp0: 0,
+            ss0: 0,
+            esp1: 0,
+            ss1: 0,
+            esp2: 0,
+            ss2: 0,
+            cr3: 0,
+            eip: 0,
+            eflags: 0,
+            eax: 0,
+            ecx: 0,
+            edx: 0,
+            ebx: 0,
+            esp: 0,
+            ebp: 0,
+            esi: 0,
+            edi: 0,
+            es: 0,
+            cs: 0,
+            ss: 0,
+            ds: 0,
+            fs: 0,
+            gs: 0,
+            ldt: 0,
+            trap: 0,
+            // Past the segment limit: no I/O permission bitmap
+            iomap_base: size_of::<TaskStateSegment>() as u16,
+        }
+    }
+}
+
+/// Memory that, once `init` has filled it in, only the CPU touches,
+/// through the address `init` gave it
+#[repr(C, align(16))]
+struct HardwareOwned<T>(UnsafeCell<T>);
+
+// SAFETY: `init` is the only code that reaches the contents, and it runs
+// once on the boot CPU before any other CPU is started
+unsafe impl<T> Sync for HardwareOwned<T> {}
+
+static GDT: HardwareOwned<[GdtEntry; GDT_SIZE]> = HardwareOwned(UnsafeCell::new([GdtEntry::null(); GDT_SIZE]));
+static DOUBLE_FAULT_STACK: HardwareOwned<[u8; DOUBLE_FAULT_STACK_SIZE]> =
+    HardwareOwned(UnsafeCell::new([0; DOUBLE_FAULT_STACK_SIZE]));
+
+// The CPU also saves task state into the TSSs through their addresses; the
+// locks only order the kernel's own accesses
+static TSS: [IrqSpinLock<TaskStateSegment>; MAX_CPUS] =
+    [const { IrqSpinLock::new(TaskStateSegment::empty()) }; MAX_CPUS];
+static DOUBLE_FAULT_TSS: IrqSpinLock<TaskStateSegment> = IrqSpinLock::new(TaskStateSegment::empty());
+
+fn tss_selector(slot: usize) -> u16 {
+    ((FIRST_TSS_ENTRY + slot) * size_of::<GdtEntry>()) as u16
+}
+
+fn tss_slot(selector: u16) -> usize {
+    selector as usize / size_of::<GdtEntry>() - FIRST_TSS_ENTRY
+}
+
+/// Sets up the GDT shared by every CPU, with a TSS for each, and loads it
+/// on the boot CPU with TSS slot 0
+pub fn init() {
+    let tss_limit = (size_of::<TaskStateSegment>() - 1) as u32;
+    let mut double_fault_tss = DOUBLE_FAULT_TSS.lock();
+    // SAFETY: see `HardwareOwned`; nothing else holds a reference
+    let gdt = unsafe { &mut *GDT.0.get() };
+
+    gdt[1] = GdtEntry::new(0, 0xFFFFF, 0x9A, 0xC0);
+    gdt[2] = GdtEntry::new(0, 0xFFFFF, 0x92, 0xC0);
+    gdt[3] = GdtEntry::new(0, 0xFFFFF, 0xFA, 0xC0);
+    gdt[4] = GdtEntry::new(0, 0xFFFFF, 0xF2, 0xC0);
+    gdt[5] = GdtEntry::new(&*double_fault_tss as *const TaskStateSegment as u32, tss_limit, 0x89, 0x00);
+    for (slot, tss) in TSS.iter().enumerate() {
+        let mut tss = tss.lock();
+        tss.ss0 = KERNEL_DATA_SELECTOR as u32;
+        gdt[FIRST_TSS_ENTRY + slot] = GdtEntry::new(&*tss as *const TaskStateSegment as u32, tss_limit, 0x89, 0x00);
+    }
+
+    let stack_top = DOUBLE_FAULT_STACK.0.get() as u32 + DOUBLE_FAULT_STACK_SIZE as u32;
+    double_fault_tss.eip = double_fault_task as u32;
+    double_fault_tss.esp = stack_top;
+    double_fault_tss.esp0 = stack_top;
+    double_fault_tss.ss0 = KERNEL_DATA_SELECTOR as u32;
+    double_fault_tss.eflags = 0x2;
+    double_fault_tss.cs = KERNEL_CODE_SELECTOR as u32;
+    double_fault_tss.ds = KERNEL_DATA_SELECTOR as u32;
+    double_fault_tss.es = KERNEL_DATA_SELECTOR as u32;
+    double_fault_tss.fs = KERNEL_DATA_SELECTOR as u32;
+    double_fault_tss.gs = KERNEL_DATA_SELECTOR as u32;
+    double_fault_tss.ss = KERNEL_DATA_SELECTOR as u32;
+
+    let cr3: u32;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack));
+    }
+    double_fault_tss.cr3 = cr3;
+
+    // `lgdt` copies the pointer, so it can live on the stack
+    let gdt_ptr = GdtPointer {
+        limit: (size_of::<[GdtEntry; GDT_SIZE]>() - 1) as u16,
+        base: gdt.as_ptr() as u32,
+    };
+
+    unsafe {
+        asm!(
+            "lgdt [{gdt_ptr}]",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "mov fs, {data:x}",
+            "mov gs, {data:x}",
+            "mov ss, {data:x}",
+            "push {code}",
+            "lea {tmp}, [2f]",
+            "push {tmp}",
+            "retf",
+            "2:",
+            gdt_ptr = in(reg) &gdt_ptr,
+            data = in(reg) KERNEL_DATA_SELECTOR as u32,
+            code = const KERNEL_CODE_SELECTOR as u32,
+            tmp = out(reg) _,
+        );
+    }
+    load_tss(0);
+}
+
+/// Loads TSS `slot` on the calling CPU, which must not share it with any
+/// other. The APs call this once they are up on the boot CPU's GDT
+pub fn load_tss(slot: usize) {
+    unsafe {
+        asm!("ltr {0:x}", in(reg) tss_selector(slot) as u32, options(nostack));
+    }
+}
+
+/// The TSS slot `load_tss` gave the calling CPU, read back from its task
+/// register
+fn current_tss() -> usize {
+    let selector: u16;
+    unsafe {
+        asm!("str {0:x}", out(reg) selector, options(nomem, nostack));
+    }
+    tss_slot(selector)
+}
+
+/// Stack the calling CPU switches to when an interrupt arrives from ring 3
+pub fn set_kernel_stack(esp0: u32) {
+    TSS[current_tss()].lock().esp0 = esp0;
+}
+
+/// The double-fault task loads CR3 on entry, so it has to follow the kernel
+/// page directory once paging is switched on
+pub fn set_page_directory(cr3: u32) {
+    DOUBLE_FAULT_TSS.lock().cr3 = cr3;
+}
+
+#[no_mangle]
+pub extern "C" fn double_fault_handler_inner(error_code: u32) -> ! {
+    // The task switch saved the faulting context into the faulting CPU's
+    // TSS and linked back to it. Nothing else runs on this CPU any more, so
+    // a lock it held can be taken over
+    let link = unsafe { (*DOUBLE_FAULT_TSS.force_get()).prev_task };
+    let tss = unsafe { &*TSS[tss_slot(link as u16)].force_get() };
+    let frame = ExceptionFrame {
+        edi: tss.edi,
+        esi: tss.esi,
+        ebp: tss.ebp,
+        esp: tss.esp.wrapping_sub(20),
+        ebx: tss.ebx,
+        edx: tss.edx,
+        ecx: tss.ecx,
+        eax: tss.eax,
+        vector: 8,
+        error_code,
+        eip: tss.eip,
+        cs: tss.cs,
+        eflags: tss.eflags,
+    };
+    exceptions::show_panic_screen(&frame);
+
+    loop {
+        unsafe {
+            asm!("cli", "hlt", options(nostack));
+        }
+    }
+}
+
+#[unsafe(naked)]
+#[no_mangle]
+pub unsafe extern "C" fn double_fault_task() {
+    // The error code the CPU pushed is right where cdecl expects an argument
+    naked_asm!(
+        "call double_fault_handler_inner",
+    );
+}
@@ -0,0 +1,245 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::arch::asm;
+use core::fmt::Write;
+use core::mem;
+use core::ptr;
+
+use crate::frame_allocator::{get_frame_allocator, FRAME_SIZE};
+use crate::sync::IrqSpinLock;
+use crate::vga_colors::Color;
+use crate::writer::Writer;
+
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024;
+
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+const MIN_BLOCK_SIZE: usize = mem::size_of::<FreeBlock>();
+
+pub struct HeapStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// First-fit allocator over an address-ordered free list. Freed blocks are
+/// merged with their neighbours so the heap does not fragment into slivers.
+struct LinkedListHeap {
+    head: FreeBlock,
+    start: usize,
+    total: usize,
+    used: usize,
+}
+
+// The free list only points into the heap region, which the lock guards
+unsafe impl Send for LinkedListHeap {}
+
+impl LinkedListHeap {
+    const fn new() -> Self {
+        Self {
+            head: FreeBlock { size: 0, next: ptr::null_mut() },
+            start: 0,
+            total: 0,
+            used: 0,
+        }
+    }
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        let aligned = align_up(start, mem::align_of::<FreeBlock>());
+        let size = size - (aligned - start);
+        self.head.next = ptr::null_mut();
+        self.start = aligned;
+        self.total = size;
+        self.used = 0;
+        self.add_free_region(aligned, size);
+    }
+
+    /// Rounds a request up so every block can later hold a `FreeBlock` header
+    fn block_layout(layout: Layout) -> (usize, usize) {
+        let align = layout.align().max(mem::align_of::<FreeBlock>());
+        let size = align_up(layout.size().max(MIN_BLOCK_SIZE), mem::align_of::<FreeBlock>());
+        (size, align)
+    }
+
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = &mut self.head;
+        while !(*prev).next.is_null() && ((*prev).next as usize) < addr {
+            prev = (*prev).next;
+        }
+
+        let next = (*prev).next;
+        let node = addr as *mut FreeBlock;
+        (*node).size = size;
+        (*node).next = next;
+        (*prev).next = node;
+
+        if !next.is_null() && addr + size == next as usize {
+            (*node).size += (*next).size;
+            (*node).next = (*next).next;
+        }
+
+        if prev != &mut self.head as *mut FreeBlock && prev as usize + (*prev).size == addr {
+            (*prev).size += (*node).size;
+            (*prev).next = (*node).next;
+        }
+    }
+
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::block_layout(layout);
+
+        let mut prev: *mut FreeBlock = &mut self.head;
+        while !(*prev).next.is_null() {
+            let block = (*prev).next;
+            let block_start = block as usize;
+            let block_end = block_start + (*block).size;
+
+            let mut start = align_up(block_start, align);
+            if start != block_start && start - block_start < MIN_BLOCK_SIZE {
+                start = align_up(block_start + MIN_BLOCK_SIZE, align);
+            }
+
+            if let Some(end) = start.checked_add(size) {
+                let back = block_end.saturating_sub(end);
+                if end <= block_end && (back == 0 || back >= MIN_BLOCK_SIZE) {
+                    (*prev).next = (*block).next;
+
+                    let front = start - block_start;
+                    if front > 0 {
+                        self.add_free_region(block_start, front);
+                    }
+                    if back > 0 {
+                        self.add_free_region(end, back);
+                    }
+
+                    self.used += size;
+                    return start as *mut u8;
+                }
+            }
+
+            prev = block;
+        }
+
+        ptr::null_mut()
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::block_layout(layout);
+        self.add_free_region(ptr as usize, size);
+        self.used -= size;
+    }
+}
+
+pub struct KernelHeap {
+    inner: IrqSpinLock<LinkedListHeap>,
+}
+
+impl KernelHeap {
+    const fn new() -> Self {
+        Self {
+            inner: IrqSpinLock::new(LinkedListHeap::new()),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.inner.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.lock().deallocate(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelHeap = KernelHeap::new();
+
+/// Carves the heap out of physical frames. Must run after the frame allocator.
+pub fn init() -> Result<(), &'static str> {
+    let frames = HEAP_SIZE / FRAME_SIZE as usize;
+    let start = get_frame_allocator()
+        .allocate_contiguous(frames)
+        .ok_or("Not enough contiguous memory for the kernel heap")?;
+
+    unsafe {
+        ALLOCATOR.inner.lock().init(start as usize, HEAP_SIZE);
+    }
+    Ok(())
+}
+
+/// Start and end address of the heap region, empty before `init`
+pub fn bounds() -> (usize, usize) {
+    let heap = ALLOCATOR.inner.lock();
+    (heap.start, heap.start + heap.total)
+}
+
+pub fn stats() -> HeapStats {
+    let heap = ALLOCATOR.inner.lock();
+    HeapStats {
+        total: heap.total,
+        used: heap.used,
+        free: heap.total - heap.used,
+    }
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    unsafe {
+        asm!("cli", options(nostack));
+    }
+
+    let mut writer = Writer::new(0);
+    writer.set_color(Color::White, Color::Red);
+    let _ = write!(
+        writer,
+        "\nKernel heap exhausted: failed to allocate {} bytes (align {})\nSystem halted.\n",
+        layout.size(),
+        layout.align()
+    );
+
+    loop {
+        unsafe {
+            asm!("hlt", options(nostack));
+        }
+    }
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use alloc::boxed::Box;
+    use alloc::collections::BTreeMap;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use super::stats;
+
+    pub fn alloc_collections() {
+        let used_before = stats().used;
+        {
+            let boxed = Box::new(42u32);
+            assert_eq!(*boxed, 42);
+
+            let mut numbers = Vec::new();
+            for i in 0..1000u32 {
+                numbers.push(i);
+            }
+            assert_eq!(numbers.iter().sum::<u32>(), 499500);
+
+            let mut name = String::from("Hexium");
+            name.push_str("OS");
+            assert_eq!(name.as_str(), "HexiumOS");
+
+            let mut map = BTreeMap::new();
+            map.insert(2, "two");
+            map.insert(1, "one");
+            assert_eq!(map.keys().copied().collect::<Vec<_>>(), [1, 2]);
+        }
+        assert_eq!(stats().used, used_before);
+    }
+}
@@ -1,6 +1,8 @@
 use crate::vga_colors::Color;
-use crate::writer::Writer;
-use core::arch::asm;
+use crate::writer::Console;
+use crate::multiboot;
+use crate::timer;
+use crate::cpu::cpuid;
 
 pub struct HexFetch {}
 
@@ -53,27 +55,14 @@ impl CpuInfo {
     }
 }
 
-fn cpuid(function: u32) -> (u32, u32, u32, u32) {
-    let eax: u32;
-    let ebx: u32;
-    let ecx: u32;
-    let edx: u32;
-
-    unsafe {
-        asm!(
-            "cpuid",
-            inout("eax") function => eax,
-            out("ebx") ebx,
-            out("ecx") ecx,
-            out("edx") edx,
-            options(nostack, preserves_flags)
-        );
+fn detect_memory_kb() -> u32 {
+    if let Some(info) = multiboot::boot_info() {
+        let usable_kb = info.usable_memory_bytes() / 1024;
+        if usable_kb > 0 {
+            return usable_kb.min(u32::MAX as u64) as u32;
+        }
     }
 
-    (eax, ebx, ecx, edx)
-}
-
-fn detect_memory_kb() -> u32 {
     let base_mem: u16 = unsafe { *(0x413 as *const u16) };
 
     let extended_kb: u32 = 128 * 1024;
@@ -82,12 +71,11 @@ fn detect_memory_kb() -> u32 {
 }
 
 fn get_uptime_seconds() -> u32 {
-    let ticks: u32 = unsafe { *(0x46C as *const u32) };
-    ticks / 18
+    (timer::uptime_ms() / 1000) as u32
 }
 
 impl HexFetch {
-    pub fn fetch(writer: &mut Writer) {
+    pub fn fetch(writer: &mut Console) {
         let cpu = CpuInfo::detect();
         let memory_kb = detect_memory_kb();
         let memory_mb = memory_kb / 1024;
@@ -198,7 +186,7 @@ impl HexFetch {
 }
 
 /// Write a string truncated to max_len characters
-fn write_truncated(writer: &mut Writer, s: &str, max_len: usize) {
+fn write_truncated(writer: &mut Console, s: &str, max_len: usize) {
     let bytes = s.as_bytes();
     if bytes.len() <= max_len {
         writer.write_str(s);
@@ -209,7 +197,7 @@ fn write_truncated(writer: &mut Writer, s: &str, max_len: usize) {
     }
 }
 
-fn write_number(writer: &mut Writer, mut n: u32) {
+fn write_number(writer: &mut Console, mut n: u32) {
     if n == 0 {
         writer.write_str("0");
         return;
@@ -230,7 +218,7 @@ fn write_number(writer: &mut Writer, mut n: u32) {
     }
 }
 
-fn write_uptime(writer: &mut Writer, hours: u32, minutes: u32, seconds: u32) {
+fn write_uptime(writer: &mut Console, hours: u32, minutes: u32, seconds: u32) {
     if hours > 0 {
         write_number(writer, hours);
         writer.write_str("h ");
@@ -1,5 +1,14 @@
 use core::arch::asm;
-use core::arch::naked_asm;
+
+use crate::exceptions;
+use crate::gdt;
+use crate::io::inb;
+use crate::irq;
+use crate::keyboard::SignalKeys;
+use crate::process;
+use crate::syscall;
+use crate::sync::{IrqSpinLock, SpinLock};
+use crate::thread::WaitQueue;
 
 #[derive(Copy, Clone)]
 #[repr(C, packed)]
@@ -25,10 +34,24 @@ impl IdtEntry {
     pub fn set_handler(&mut self, handler: u32) {
         self.offset_low = (handler & 0xFFFF) as u16;
         self.offset_high = ((handler >> 16) & 0xFFFF) as u16;
-        self.selector = 0x08;
+        self.selector = gdt::KERNEL_CODE_SELECTOR;
         self.zero = 0;
         self.type_attr = 0x8E;
     }
+
+    /// An interrupt gate with DPL 3, so `int n` from ring 3 may use it
+    pub fn set_user_handler(&mut self, handler: u32) {
+        self.set_handler(handler);
+        self.type_attr = 0xEE;
+    }
+
+    pub fn set_task_gate(&mut self, tss_selector: u16) {
+        self.offset_low = 0;
+        self.offset_high = 0;
+        self.selector = tss_selector;
+        self.zero = 0;
+        self.type_attr = 0x85;
+    }
 }
 
 #[repr(C, packed)]
@@ -38,163 +61,157 @@ pub struct IdtPointer {
 }
 
 const IDT_SIZE: usize = 256;
-static mut IDT: [IdtEntry; IDT_SIZE] = [IdtEntry::empty(); IDT_SIZE];
-static mut IDT_PTR: IdtPointer = IdtPointer { limit: 0, base: 0 };
-static mut TICK_COUNT: u32 = 0;
+// The CPU reads gates straight from memory; the lock only orders our writes
+static IDT: SpinLock<[IdtEntry; IDT_SIZE]> = SpinLock::new([IdtEntry::empty(); IDT_SIZE]);
 
 pub fn init() {
-    unsafe {
-        IDT[0x20].set_handler(timer_interrupt_handler as u32);
-        IDT[0x21].set_handler(keyboard_interrupt_handler as u32);
-
-        IDT_PTR.limit = (core::mem::size_of::<[IdtEntry; IDT_SIZE]>() - 1) as u16;
-        IDT_PTR.base = IDT.as_ptr() as u32;
+    exceptions::init();
+    irq::init();
+    syscall::init();
+    load();
 
-        asm!("lidt [{}]", in(reg) &IDT_PTR, options(nostack));
-    }
-
-    init_pics();
-    init_pit();
+    let _ = irq::register(irq::IRQ_KEYBOARD, "keyboard", keyboard_handler);
 
     unsafe {
         asm!("sti", options(nostack));
     }
 }
 
-fn init_pit() {
-    let divisor: u16 = 11932;
+/// Loads the shared IDT on the calling CPU
+pub fn load() {
+    // `lidt` copies the pointer, so it can live on the stack
+    let pointer = IdtPointer {
+        limit: (core::mem::size_of::<[IdtEntry; IDT_SIZE]>() - 1) as u16,
+        base: IDT.lock().as_ptr() as u32,
+    };
     unsafe {
-        outb(0x43, 0x36);
-        outb(0x40, (divisor & 0xFF) as u8);
-        outb(0x40, ((divisor >> 8) & 0xFF) as u8);
+        asm!("lidt [{}]", in(reg) &pointer, options(nostack));
     }
 }
 
-pub fn get_ticks() -> u32 {
-    unsafe { TICK_COUNT }
+/// Installs an interrupt gate; safe to call before or after `init`
+pub fn set_handler(vector: usize, handler: u32) {
+    IDT.lock()[vector].set_handler(handler);
 }
 
-fn init_pics() {
-    unsafe {
-        outb(0x20, 0x11);
-        outb(0xA0, 0x11);
+pub fn set_user_handler(vector: usize, handler: u32) {
+    IDT.lock()[vector].set_user_handler(handler);
+}
 
-        outb(0x21, 0x20);
-        outb(0xA1, 0x28);
-       
-        outb(0x21, 0x04);
-        outb(0xA1, 0x02);
+pub fn set_task_gate(vector: usize, tss_selector: u16) {
+    IDT.lock()[vector].set_task_gate(tss_selector);
+}
 
-        outb(0x21, 0x01);
-        outb(0xA1, 0x01);
+const BUFFER_SIZE: usize = 32;
 
-        outb(0x21, 0xFC);
-        outb(0xA1, 0xFF);
-    }
+/// Scancodes queued by the keyboard IRQ until the shell reads them
+struct ScancodeBuffer {
+    data: [u8; BUFFER_SIZE],
+    head: usize,
+    tail: usize,
 }
 
-unsafe fn outb(port: u16, value: u8) {
-    asm!(
-        "out dx, al",
-        in("dx") port,
-        in("al") value,
-        options(nostack)
-    );
-}
+impl ScancodeBuffer {
+    fn push(&mut self, scancode: u8) {
+        let next_head = (self.head + 1) % BUFFER_SIZE;
+        if next_head != self.tail {
+            self.data[self.head] = scancode;
+            self.head = next_head;
+        }
+    }
 
-pub unsafe fn inb(port: u16) -> u8 {
-    let value: u8;
-    asm!(
-        "in al, dx",
-        in("dx") port,
-        out("al") value,
-        options(nostack)
-    );
-    value
+    fn pop(&mut self) -> Option<u8> {
+        if self.head == self.tail {
+            return None;
+        }
+        let scancode = self.data[self.tail];
+        self.tail = (self.tail + 1) % BUFFER_SIZE;
+        Some(scancode)
+    }
 }
 
-const BUFFER_SIZE: usize = 32;
-static mut KEY_BUFFER: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
-static mut BUFFER_HEAD: usize = 0;
-static mut BUFFER_TAIL: usize = 0;
+static KEY_BUFFER: IrqSpinLock<ScancodeBuffer> = IrqSpinLock::new(ScancodeBuffer {
+    data: [0; BUFFER_SIZE],
+    head: 0,
+    tail: 0,
+});
+
+static KEY_WAITERS: WaitQueue = WaitQueue::new();
+
+static SIGNAL_KEYS: IrqSpinLock<SignalKeys> = IrqSpinLock::new(SignalKeys::new());
 
 pub fn get_scancode() -> Option<u8> {
-    unsafe {
-        asm!("cli", options(nostack));
-        let result = if BUFFER_HEAD == BUFFER_TAIL {
-            None
-        } else {
-            let scancode = KEY_BUFFER[BUFFER_TAIL];
-            BUFFER_TAIL = (BUFFER_TAIL + 1) % BUFFER_SIZE;
-            Some(scancode)
-        };
-        asm!("sti", options(nostack));
-        result
-    }
+    KEY_BUFFER.lock().pop()
 }
 
-pub fn wait_for_interrupt() {
+/// Blocks the calling thread until a key event arrives, or gives up with
+/// `None` once `stop` holds, checked whenever the thread wakes
+pub fn read_scancode_unless(mut stop: impl FnMut() -> bool) -> Option<u8> {
+    let mut scancode = None;
+    let mut stopped = false;
+    KEY_WAITERS.wait_until(|| {
+        scancode = get_scancode();
+        stopped = scancode.is_none() && stop();
+        scancode.is_some() || stopped
+    });
+    scancode
+}
+
+const EFLAGS_IF: u32 = 1 << 9;
+
+#[cfg(kernel_test)]
+pub fn interrupts_enabled() -> bool {
+    let flags: u32;
     unsafe {
-        asm!("hlt", options(nostack));
+        asm!("pushfd", "pop {}", out(reg) flags, options(nomem, preserves_flags));
     }
+    flags & EFLAGS_IF != 0
 }
 
-pub fn flush_buffer() {
+/// Masks interrupts and reports whether they were on before
+pub fn disable_interrupts() -> bool {
+    let flags: u32;
     unsafe {
-        asm!("cli", options(nostack));
-        BUFFER_HEAD = 0;
-        BUFFER_TAIL = 0;
-        asm!("sti", options(nostack));
+        asm!("pushfd", "pop {}", "cli", out(reg) flags);
     }
+    flags & EFLAGS_IF != 0
 }
 
-fn buffer_push(scancode: u8) {
-    unsafe {
-        let next_head = (BUFFER_HEAD + 1) % BUFFER_SIZE;
-        if next_head != BUFFER_TAIL {
-            KEY_BUFFER[BUFFER_HEAD] = scancode;
-            BUFFER_HEAD = next_head;
+/// Undoes `disable_interrupts`, given what it returned
+pub fn restore_interrupts(were_enabled: bool) {
+    if were_enabled {
+        unsafe {
+            asm!("sti", options(nostack));
         }
     }
 }
 
-#[no_mangle]
-pub extern "C" fn keyboard_handler_inner() {
-    unsafe {
-        let scancode = inb(0x60);
-        
-        buffer_push(scancode);
-
-        outb(0x20, 0x20);
-    }
+/// Runs `f` with interrupts masked, restoring the previous IF state after
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let were_enabled = disable_interrupts();
+    let result = f();
+    restore_interrupts(were_enabled);
+    result
 }
 
-#[no_mangle]
-pub extern "C" fn timer_handler_inner() {
+pub fn wait_for_interrupt() {
     unsafe {
-        TICK_COUNT = TICK_COUNT.wrapping_add(1);
-        outb(0x20, 0x20);
+        asm!("hlt", options(nostack));
     }
 }
 
-#[unsafe(naked)]
-#[no_mangle]
-pub unsafe extern "C" fn keyboard_interrupt_handler() {
-    naked_asm!(
-        "pusha",
-        "call keyboard_handler_inner",
-        "popa",
-        "iretd",
-    );
-}
-
-#[unsafe(naked)]
-#[no_mangle]
-pub unsafe extern "C" fn timer_interrupt_handler() {
-    naked_asm!(
-        "pusha",
-        "call timer_handler_inner",
-        "popa",
-        "iretd",
-    );
+pub fn flush_buffer() {
+    let mut buffer = KEY_BUFFER.lock();
+    buffer.head = 0;
+    buffer.tail = 0;
+}
+
+fn keyboard_handler() {
+    let scancode = unsafe { inb(0x60) };
+    let signal = SIGNAL_KEYS.lock().feed(scancode);
+    match signal {
+        Some(signal) => process::raise(signal),
+        None => KEY_BUFFER.lock().push(scancode),
+    }
+    KEY_WAITERS.wake_all();
 }
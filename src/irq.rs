@@ -0,0 +1,269 @@
+use core::arch::naked_asm;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::apic;
+use crate::idt;
+use crate::pic;
+use crate::sync::IrqSpinLock;
+use crate::thread;
+use crate::usermode;
+
+pub const IRQ_COUNT: usize = 16;
+/// IDT vector of IRQ 0 after the PICs are remapped
+pub const IRQ_BASE_VECTOR: usize = 0x20;
+
+pub const IRQ_TIMER: u8 = 0;
+pub const IRQ_KEYBOARD: u8 = 1;
+const IRQ_CASCADE: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    Pic,
+    Apic,
+}
+
+impl Controller {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Controller::Pic => "8259 PIC",
+            Controller::Apic => "IOAPIC",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct IrqHandler {
+    name: &'static str,
+    handler: fn(),
+}
+
+pub struct IrqStat {
+    pub irq: u8,
+    pub name: Option<&'static str>,
+    pub count: u32,
+    pub spurious: u32,
+    pub masked: bool,
+}
+
+struct Lines {
+    handlers: [Option<IrqHandler>; IRQ_COUNT],
+    // Bit n set = IRQ n masked, whichever controller is in charge
+    mask: u16,
+    controller: Controller,
+}
+
+static LINES: IrqSpinLock<Lines> = IrqSpinLock::new(Lines {
+    handlers: [None; IRQ_COUNT],
+    mask: 0xFFFF,
+    controller: Controller::Pic,
+});
+static COUNTS: [AtomicU32; IRQ_COUNT] = [const { AtomicU32::new(0) }; IRQ_COUNT];
+static SPURIOUS_COUNTS: [AtomicU32; IRQ_COUNT] = [const { AtomicU32::new(0) }; IRQ_COUNT];
+
+// Each stub records its IRQ number so one common path can save registers
+// and dispatch
+macro_rules! irq_stub {
+    ($name:ident, $irq:expr) => {
+        #[unsafe(naked)]
+        unsafe extern "C" fn $name() {
+            naked_asm!(
+                "push {irq}",
+                "jmp irq_common",
+                irq = const $irq,
+            );
+        }
+    };
+}
+
+irq_stub!(irq_0, 0);
+irq_stub!(irq_1, 1);
+irq_stub!(irq_2, 2);
+irq_stub!(irq_3, 3);
+irq_stub!(irq_4, 4);
+irq_stub!(irq_5, 5);
+irq_stub!(irq_6, 6);
+irq_stub!(irq_7, 7);
+irq_stub!(irq_8, 8);
+irq_stub!(irq_9, 9);
+irq_stub!(irq_10, 10);
+irq_stub!(irq_11, 11);
+irq_stub!(irq_12, 12);
+irq_stub!(irq_13, 13);
+irq_stub!(irq_14, 14);
+irq_stub!(irq_15, 15);
+
+#[unsafe(naked)]
+#[no_mangle]
+pub unsafe extern "C" fn irq_common() {
+    naked_asm!(
+        "pusha",
+        // The interrupted cs, then the IRQ number the stub pushed
+        "push dword ptr [esp + 40]",
+        "push dword ptr [esp + 36]",
+        "call irq_dispatch",
+        "add esp, 8",
+        "popa",
+        "add esp, 4",
+        "iretd",
+    );
+}
+
+/// Points vectors 0x20-0x2F at the dispatcher and starts out on the
+/// 8259 PIC with every line masked
+pub fn init() {
+    let stubs: [unsafe extern "C" fn(); IRQ_COUNT] = [
+        irq_0, irq_1, irq_2, irq_3, irq_4, irq_5, irq_6, irq_7,
+        irq_8, irq_9, irq_10, irq_11, irq_12, irq_13, irq_14, irq_15,
+    ];
+    for (irq, stub) in stubs.iter().enumerate() {
+        idt::set_handler(IRQ_BASE_VECTOR + irq, *stub as u32);
+    }
+
+    pic::init(IRQ_BASE_VECTOR as u8);
+    let mut lines = LINES.lock();
+    lines.mask = 0xFFFF;
+    lines.controller = Controller::Pic;
+}
+
+/// Moves interrupt delivery from the 8259 to the local APIC and IOAPIC,
+/// carrying over every line that is currently unmasked
+pub fn enable_apic() -> Result<(), &'static str> {
+    apic::init()?;
+
+    let mut lines = LINES.lock();
+    pic::disable();
+    lines.controller = Controller::Apic;
+    for irq in 0..IRQ_COUNT as u8 {
+        if lines.mask & (1 << irq) == 0 {
+            apic::set_masked(irq, false);
+        }
+    }
+    Ok(())
+}
+
+pub fn controller() -> Controller {
+    LINES.lock().controller
+}
+
+pub fn register(irq: u8, name: &'static str, handler: fn()) -> Result<(), &'static str> {
+    if irq as usize >= IRQ_COUNT || irq == IRQ_CASCADE {
+        return Err("Invalid IRQ number");
+    }
+
+    {
+        let mut lines = LINES.lock();
+        if lines.handlers[irq as usize].is_some() {
+            return Err("IRQ already in use");
+        }
+        lines.handlers[irq as usize] = Some(IrqHandler { name, handler });
+    }
+
+    unmask(irq);
+    Ok(())
+}
+
+#[cfg(kernel_test)]
+pub fn unregister(irq: u8) -> Result<(), &'static str> {
+    if irq as usize >= IRQ_COUNT {
+        return Err("Invalid IRQ number");
+    }
+
+    mask(irq);
+    LINES.lock().handlers[irq as usize].take().map(|_| ()).ok_or("IRQ not registered")
+}
+
+#[cfg(kernel_test)]
+pub fn mask(irq: u8) {
+    set_masked(irq, true);
+}
+
+pub fn unmask(irq: u8) {
+    set_masked(irq, false);
+}
+
+fn set_masked(irq: u8, masked: bool) {
+    let mut lines = LINES.lock();
+    if masked {
+        lines.mask |= 1 << irq;
+    } else {
+        lines.mask &= !(1 << irq);
+    }
+    match lines.controller {
+        Controller::Pic => pic::set_masked(irq, masked),
+        Controller::Apic => apic::set_masked(irq, masked),
+    }
+}
+
+pub fn end_of_interrupt(irq: u8) {
+    match controller() {
+        Controller::Pic => pic::end_of_interrupt(irq),
+        Controller::Apic => apic::end_of_interrupt(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn irq_dispatch(irq: u32, cs: u32) {
+    let irq = irq as u8;
+
+    let (controller, handler) = {
+        let lines = LINES.lock();
+        (lines.controller, lines.handlers[irq as usize])
+    };
+
+    if controller == Controller::Pic && pic::is_spurious(irq) {
+        SPURIOUS_COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+
+    if let Some(handler) = handler {
+        (handler.handler)();
+    }
+
+    end_of_interrupt(irq);
+    thread::preempt();
+
+    // Ring 3 holds no kernel locks, so a killed or interrupted program can
+    // be ended here
+    if cs & 3 == 3 {
+        usermode::handle_signals();
+    }
+}
+
+pub fn stats(irq: u8) -> IrqStat {
+    let lines = LINES.lock();
+    IrqStat {
+        irq,
+        name: lines.handlers[irq as usize].map(|h| h.name),
+        count: COUNTS[irq as usize].load(Ordering::Relaxed),
+        spurious: SPURIOUS_COUNTS[irq as usize].load(Ordering::Relaxed),
+        masked: lines.mask & (1 << irq) != 0,
+    }
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+
+    fn dummy_handler() {}
+
+    pub fn register_updates_mask() {
+        let irq = 10;
+        assert!(stats(irq).masked);
+
+        register(irq, "test", dummy_handler).expect("free IRQ rejected");
+        assert_eq!(register(irq, "test", dummy_handler), Err("IRQ already in use"));
+        assert_eq!(stats(irq).name, Some("test"));
+        assert!(!stats(irq).masked);
+
+        unregister(irq).expect("registered IRQ not removed");
+        assert!(stats(irq).masked);
+        assert_eq!(unregister(irq), Err("IRQ not registered"));
+    }
+
+    pub fn cascade_cannot_be_claimed() {
+        assert_eq!(register(IRQ_CASCADE, "test", dummy_handler), Err("Invalid IRQ number"));
+        assert_eq!(register(IRQ_COUNT as u8, "test", dummy_handler), Err("Invalid IRQ number"));
+    }
+}
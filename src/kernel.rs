@@ -1,17 +1,42 @@
 #![no_std]
 #![no_main]
 #![feature(lang_items)]
+#![feature(alloc_error_handler)]
+
+extern crate alloc;
 
 use core::panic::PanicInfo;
 
+#[macro_use]
+mod serial;
+#[macro_use]
+mod log;
 mod vga_colors;
 mod writer;
 mod keyboard;
 mod cli;
 mod intrinsics;
 mod idt;
+mod gdt;
+mod exceptions;
+mod irq;
+mod pic;
+mod apic;
+mod smp;
+mod sync;
+mod thread;
+mod process;
+mod syscall;
+mod usermode;
+mod elf;
+mod timer;
+mod clock;
+mod cpu;
+mod acpi;
+mod power;
 mod snake;
 mod video_player;
+mod audio;
 mod bad_apple_data;
 mod RAHH_data;
 mod filesystem;
@@ -27,23 +52,121 @@ pub mod io;
 
 mod font;
 
-use vga_colors::{Color, color_code};
-use writer::Writer;
+mod multiboot;
+
+mod rtc;
+
+mod frame_allocator;
+
+mod heap;
+
+mod paging;
+
+mod panic_screen;
+
+#[cfg(kernel_test)]
+mod testing;
+
+use vga_colors::Color;
+use writer::Console;
+#[cfg(not(kernel_test))]
 use cli::CLI;
 
+#[cfg(not(kernel_test))]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    panic_screen::handle(info)
+}
+
+#[cfg(kernel_test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::test_panic(info)
 }
 
 #[no_mangle]
-pub extern "C" fn kernel_main() -> ! {
-    let mut writer = Writer::new(color_code(Color::White, Color::Black));
+pub extern "C" fn kernel_main(multiboot_magic: u32, multiboot_info: u32) -> ! {
+    let serial_status = serial::init();
+
+    let mut writer = Console;
     writer.clear();
     writer.enable_cursor();
 
+    gdt::init();
+    info!("GDT and TSS loaded");
     idt::init();
+    info!("IDT loaded, PIC remapped, interrupts enabled");
+    match timer::init(timer::DEFAULT_FREQUENCY_HZ) {
+        Ok(()) => info!("PIT running at {} Hz", timer::frequency()),
+        Err(e) => error!("Timer initialisation failed: {}", e),
+    }
+    match clock::init() {
+        Ok(_) => info!("TSC calibrated at {} kHz", clock::tsc_frequency() / 1000),
+        Err(e) => warn!("{}", e),
+    }
+    info!("RTC reports {} UTC", rtc::read());
+
+    if let Err(e) = serial_status {
+        warn!("COM1 unavailable: {}", e);
+        writer.set_color(Color::Yellow, Color::Black);
+        writer.write_str("Warning: ");
+        writer.write_str(e);
+        writer.write_str(", serial console disabled\n");
+    }
+
+    if let Err(e) = init_memory(multiboot_magic, multiboot_info) {
+        error!("Memory initialisation failed: {}", e);
+        writer.set_color(Color::LightRed, Color::Black);
+        writer.write_str("Warning: ");
+        writer.write_str(e);
+        writer.write_str(", memory management disabled\n");
+    }
+    match thread::init() {
+        Ok(()) => info!("Preemptive scheduler running"),
+        Err(e) => warn!("Scheduler unavailable, running single-threaded: {}", e),
+    }
+    process::init();
+
+    match acpi::init() {
+        Ok(()) => {
+            if let Some(madt) = acpi::madt() {
+                info!("ACPI: {} CPUs, {} IOAPICs", madt.cpus().count(), madt.io_apics().count());
+            } else {
+                info!("ACPI tables found, no MADT");
+            }
+            if let Some(fadt) = acpi::fadt() {
+                if fadt.century_register != 0 {
+                    rtc::set_century_register(fadt.century_register);
+                }
+            }
+        }
+        Err(e) => warn!("ACPI unavailable: {}", e),
+    }
+
+    if multiboot::boot_info().map_or(false, |info| info.has_option(b"noapic")) {
+        info!("APIC disabled on the command line, staying on the 8259 PIC");
+    } else {
+        match irq::enable_apic() {
+            Ok(()) => {
+                info!("Interrupts routed through the IOAPIC, LAPIC timer at {} Hz", timer::frequency());
+                match smp::init() {
+                    Ok(()) => info!("SMP: {} of {} CPUs online", smp::online_count(), smp::cpu_count()),
+                    Err(e) => warn!("SMP unavailable: {}", e),
+                }
+            }
+            Err(e) => warn!("APIC unavailable, staying on the 8259 PIC: {}", e),
+        }
+    }
+
+    #[cfg(kernel_test)]
+    testing::run();
+
+    #[cfg(not(kernel_test))]
+    start_shell(&mut writer);
+}
 
+#[cfg(not(kernel_test))]
+fn start_shell(writer: &mut Console) -> ! {
     writer.set_color(Color::LightCyan, Color::Black);
     writer.write_str("  _    _           _                  ____   _____ \n");
     writer.write_str(" | |  | |         (_)                / __ \\ / ____|\n");
@@ -60,9 +183,31 @@ pub extern "C" fn kernel_main() -> ! {
     writer.write_str("Type 'help' for available commands.\n\n");
 
     filesystem::get_filesystem().init();
+    info!("In-memory file system ready");
+    let _ = power::register_shutdown_hook("filesystem", filesystem::sync);
 
     let mut cli = CLI::new();
-    cli.run(&mut writer);
+    cli.run(writer);
 }
 
-
+fn init_memory(multiboot_magic: u32, multiboot_info: u32) -> Result<(), &'static str> {
+    multiboot::init(multiboot_magic, multiboot_info)?;
+    if let Some(info) = multiboot::boot_info() {
+        info!("Boot memory map: {} KB usable", info.usable_memory_bytes() / 1024);
+    }
+
+    let stats = {
+        let mut allocator = frame_allocator::get_frame_allocator();
+        allocator.init()?;
+        allocator.stats()
+    };
+    info!("Frame allocator: {} of {} frames free", stats.free, stats.total);
+
+    heap::init()?;
+    let (heap_start, heap_end) = heap::bounds();
+    info!("Kernel heap at {:#x}-{:#x}", heap_start, heap_end);
+
+    paging::init()?;
+    info!("Paging enabled");
+    Ok(())
+}
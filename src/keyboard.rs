@@ -1,6 +1,13 @@
+use crate::process::Signal;
+
 const KEYBOARD_DATA_PORT: u16 = 0x60;
 const KEYBOARD_STATUS_PORT: u16 = 0x64;
 
+const CTRL_PRESS: u8 = 0x1D;
+const CTRL_RELEASE: u8 = 0x9D;
+const C_PRESS: u8 = 0x2E;
+const Z_PRESS: u8 = 0x2C;
+
 pub struct Keyboard;
 
 impl Keyboard {
@@ -84,3 +91,62 @@ impl Keyboard {
         }
     }
 }
+
+/// Tracks Ctrl in the interrupt handler so Ctrl+C and Ctrl+Z reach the
+/// foreground process whatever is reading the keyboard
+pub struct SignalKeys {
+    ctrl: bool,
+}
+
+impl SignalKeys {
+    pub const fn new() -> Self {
+        SignalKeys { ctrl: false }
+    }
+
+    /// Returns the signal `scancode` completes, if any. That key press is
+    /// then the driver's and should not be queued for readers
+    pub fn feed(&mut self, scancode: u8) -> Option<Signal> {
+        match scancode {
+            CTRL_PRESS => self.ctrl = true,
+            CTRL_RELEASE => self.ctrl = false,
+            C_PRESS if self.ctrl => return Some(Signal::Interrupt),
+            Z_PRESS if self.ctrl => return Some(Signal::Suspend),
+            _ => {}
+        }
+        None
+    }
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+
+    pub fn letters_respect_shift() {
+        assert_eq!(Keyboard::scancode_to_char(0x1E, false), Some('a'));
+        assert_eq!(Keyboard::scancode_to_char(0x1E, true), Some('A'));
+        assert_eq!(Keyboard::scancode_to_char(0x02, false), Some('1'));
+        assert_eq!(Keyboard::scancode_to_char(0x02, true), Some('!'));
+        assert_eq!(Keyboard::scancode_to_char(0x39, true), Some(' '));
+    }
+
+    pub fn unknown_scancodes_are_ignored() {
+        // Escape, F1 and a key-release code
+        assert_eq!(Keyboard::scancode_to_char(0x01, false), None);
+        assert_eq!(Keyboard::scancode_to_char(0x3B, false), None);
+        assert_eq!(Keyboard::scancode_to_char(0x9E, false), None);
+    }
+
+    pub fn ctrl_c_and_ctrl_z_raise_signals() {
+        let mut keys = SignalKeys::new();
+        assert_eq!(keys.feed(C_PRESS), None);
+
+        assert_eq!(keys.feed(CTRL_PRESS), None);
+        assert_eq!(keys.feed(C_PRESS), Some(Signal::Interrupt));
+        assert_eq!(keys.feed(Z_PRESS), Some(Signal::Suspend));
+        // Releasing C does not count as another press
+        assert_eq!(keys.feed(C_PRESS | 0x80), None);
+
+        assert_eq!(keys.feed(CTRL_RELEASE), None);
+        assert_eq!(keys.feed(C_PRESS), None);
+    }
+}
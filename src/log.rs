@@ -0,0 +1,268 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::serial;
+use crate::sync::IrqSpinLock;
+use crate::timer;
+
+const LOG_CAPACITY: usize = 128;
+const MAX_TAG_LEN: usize = 16;
+const MAX_MESSAGE_LEN: usize = 72;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    pub fn parse(name: &[u8]) -> Option<Level> {
+        match name {
+            b"error" => Some(Level::Error),
+            b"warn" => Some(Level::Warn),
+            b"info" => Some(Level::Info),
+            b"debug" => Some(Level::Debug),
+            b"trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct LogEntry {
+    pub timestamp_ms: u64,
+    pub level: Level,
+    tag: [u8; MAX_TAG_LEN],
+    tag_len: usize,
+    message: [u8; MAX_MESSAGE_LEN],
+    message_len: usize,
+}
+
+impl LogEntry {
+    const fn empty() -> Self {
+        Self {
+            timestamp_ms: 0,
+            level: Level::Info,
+            tag: [0; MAX_TAG_LEN],
+            tag_len: 0,
+            message: [0; MAX_MESSAGE_LEN],
+            message_len: 0,
+        }
+    }
+
+    pub fn tag(&self) -> &[u8] {
+        &self.tag[..self.tag_len]
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.message[..self.message_len]
+    }
+}
+
+/// Formats into a fixed buffer, silently dropping whatever does not fit
+struct TruncatingBuffer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for TruncatingBuffer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len >= self.buf.len() {
+                break;
+            }
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+struct LogBuffer {
+    entries: [LogEntry; LOG_CAPACITY],
+    // Index the next entry goes to, and how many slots hold entries
+    head: usize,
+    count: usize,
+}
+
+static LOG_BUFFER: IrqSpinLock<LogBuffer> = IrqSpinLock::new(LogBuffer {
+    entries: [LogEntry::empty(); LOG_CAPACITY],
+    head: 0,
+    count: 0,
+});
+// A `Level` as u8; the discriminants follow the severity order
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Debug as u8);
+static SERIAL_MIRROR: AtomicBool = AtomicBool::new(true);
+
+/// Messages less severe than `level` are dropped
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn set_serial_mirror(enabled: bool) {
+    SERIAL_MIRROR.store(enabled, Ordering::Relaxed);
+}
+
+/// `module_path!()` always starts with the crate name, which adds nothing
+fn short_tag(module: &str) -> &str {
+    match module.find("::") {
+        Some(idx) => &module[idx + 2..],
+        None => module,
+    }
+}
+
+#[doc(hidden)]
+pub fn record(level: Level, module: &str, args: fmt::Arguments) {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut entry = LogEntry::empty();
+    entry.timestamp_ms = timer::uptime_ms();
+    entry.level = level;
+
+    let tag = short_tag(module).as_bytes();
+    entry.tag_len = tag.len().min(MAX_TAG_LEN);
+    entry.tag[..entry.tag_len].copy_from_slice(&tag[..entry.tag_len]);
+
+    let mut message = TruncatingBuffer {
+        buf: &mut entry.message,
+        len: 0,
+    };
+    let _ = message.write_fmt(args);
+    entry.message_len = message.len;
+
+    {
+        let mut log = LOG_BUFFER.lock();
+        let head = log.head;
+        log.entries[head] = entry;
+        log.head = (head + 1) % LOG_CAPACITY;
+        if log.count < LOG_CAPACITY {
+            log.count += 1;
+        }
+    }
+
+    if SERIAL_MIRROR.load(Ordering::Relaxed) {
+        let (secs, millis) = (entry.timestamp_ms / 1000, entry.timestamp_ms % 1000);
+        serial_print!("[{:5}.{:03}] {:<5} ", secs, millis, level.name());
+        serial::write_bytes(entry.tag());
+        serial::write_str(": ");
+        serial::write_bytes(entry.message());
+        serial::write_str("\n");
+    }
+}
+
+/// Calls `f` for every stored entry, oldest first
+pub fn for_each(mut f: impl FnMut(&LogEntry)) {
+    let (head, count) = {
+        let log = LOG_BUFFER.lock();
+        (log.head, log.count)
+    };
+
+    // Copy one entry at a time; the whole buffer is too big for the stack
+    let start = (head + LOG_CAPACITY - count) % LOG_CAPACITY;
+    for i in 0..count {
+        let entry = LOG_BUFFER.lock().entries[(start + i) % LOG_CAPACITY];
+        f(&entry);
+    }
+}
+
+pub fn clear() {
+    let mut log = LOG_BUFFER.lock();
+    log.head = 0;
+    log.count = 0;
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => (
+        $crate::log::record($level, module_path!(), format_args!($($arg)*))
+    );
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => (log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => (log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => (log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => (log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => (log!($crate::log::Level::Trace, $($arg)*));
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+
+    pub fn ring_buffer_keeps_newest_entries() {
+        set_serial_mirror(false);
+        clear();
+        for i in 0..LOG_CAPACITY + 2 {
+            info!("entry {}", i);
+        }
+
+        let mut seen = 0;
+        let mut first = LogEntry::empty();
+        for_each(|entry| {
+            if seen == 0 {
+                first = *entry;
+            }
+            seen += 1;
+        });
+
+        assert_eq!(seen, LOG_CAPACITY);
+        assert_eq!(first.message(), b"entry 2");
+        assert_eq!(first.tag(), b"log::tests");
+        clear();
+        set_serial_mirror(true);
+    }
+
+    pub fn max_level_filters_records() {
+        set_serial_mirror(false);
+        clear();
+        set_max_level(Level::Warn);
+        info!("dropped");
+        warn!("kept");
+        error!("kept too");
+        set_max_level(Level::Debug);
+
+        let mut levels = [Level::Trace; 2];
+        let mut seen = 0;
+        for_each(|entry| {
+            levels[seen] = entry.level;
+            seen += 1;
+        });
+        assert_eq!(seen, 2);
+        assert_eq!(levels, [Level::Warn, Level::Error]);
+        clear();
+        set_serial_mirror(true);
+    }
+}
@@ -0,0 +1,326 @@
+use core::mem::size_of;
+
+use crate::sync::Once;
+
+/// Value GRUB leaves in EAX when it hands control to a multiboot kernel
+pub const BOOTLOADER_MAGIC: u32 = 0x2BADB002;
+
+const FLAG_MEMORY: u32 = 1 << 0;
+const FLAG_CMDLINE: u32 = 1 << 2;
+const FLAG_MODULES: u32 = 1 << 3;
+const FLAG_MEMORY_MAP: u32 = 1 << 6;
+const FLAG_BOOTLOADER_NAME: u32 = 1 << 9;
+
+const MAX_CSTR_LEN: usize = 256;
+
+#[repr(C)]
+struct MultibootInfo {
+    flags: u32,
+    mem_lower: u32,
+    mem_upper: u32,
+    boot_device: u32,
+    cmdline: u32,
+    mods_count: u32,
+    mods_addr: u32,
+    syms: [u32; 4],
+    mmap_length: u32,
+    mmap_addr: u32,
+    drives_length: u32,
+    drives_addr: u32,
+    config_table: u32,
+    boot_loader_name: u32,
+}
+
+#[repr(C, packed)]
+struct RawMemoryMapEntry {
+    size: u32,
+    base_addr: u64,
+    length: u64,
+    kind: u32,
+}
+
+#[repr(C)]
+struct RawModule {
+    mod_start: u32,
+    mod_end: u32,
+    string: u32,
+    reserved: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryRegionKind {
+    Usable,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    BadMemory,
+}
+
+impl MemoryRegionKind {
+    fn from_raw(kind: u32) -> Self {
+        match kind {
+            1 => MemoryRegionKind::Usable,
+            3 => MemoryRegionKind::AcpiReclaimable,
+            4 => MemoryRegionKind::AcpiNvs,
+            5 => MemoryRegionKind::BadMemory,
+            _ => MemoryRegionKind::Reserved,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MemoryRegionKind::Usable => "usable",
+            MemoryRegionKind::Reserved => "reserved",
+            MemoryRegionKind::AcpiReclaimable => "ACPI reclaimable",
+            MemoryRegionKind::AcpiNvs => "ACPI NVS",
+            MemoryRegionKind::BadMemory => "bad memory",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    pub base: u64,
+    pub length: u64,
+    pub kind: MemoryRegionKind,
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> u64 {
+        self.base.saturating_add(self.length)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    pub start: u32,
+    pub end: u32,
+    pub name: &'static [u8],
+}
+
+impl Module {
+    /// The module's contents, identity-mapped by `paging::init`
+    pub fn data(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(self.start as *const u8, (self.end - self.start) as usize) }
+    }
+
+    /// Last path component of the first word of the module's string, which
+    /// GRUB and QEMU fill with its path and arguments
+    pub fn file_name(&self) -> &'static [u8] {
+        let path = self.name.split(|&b| b == b' ').next().unwrap_or(b"");
+        path.rsplit(|&b| b == b'/').next().unwrap_or(path)
+    }
+}
+
+pub struct BootInfo {
+    info: &'static MultibootInfo,
+}
+
+impl BootInfo {
+    fn has(&self, flag: u32) -> bool {
+        self.info.flags & flag != 0
+    }
+
+    /// Conventional memory below 1 MB, in KB
+    pub fn lower_memory_kb(&self) -> Option<u32> {
+        if self.has(FLAG_MEMORY) {
+            Some(self.info.mem_lower)
+        } else {
+            None
+        }
+    }
+
+    /// Contiguous memory starting at 1 MB, in KB
+    pub fn upper_memory_kb(&self) -> Option<u32> {
+        if self.has(FLAG_MEMORY) {
+            Some(self.info.mem_upper)
+        } else {
+            None
+        }
+    }
+
+    pub fn memory_map(&self) -> MemoryMapIter {
+        let (addr, length) = if self.has(FLAG_MEMORY_MAP) {
+            (self.info.mmap_addr as usize, self.info.mmap_length as usize)
+        } else {
+            (0, 0)
+        };
+        MemoryMapIter {
+            current: addr,
+            end: addr + length,
+        }
+    }
+
+    /// Sum of every usable region reported by the boot loader, in bytes
+    pub fn usable_memory_bytes(&self) -> u64 {
+        let mut total = 0u64;
+        let mut has_map = false;
+        for region in self.memory_map() {
+            has_map = true;
+            if region.kind == MemoryRegionKind::Usable {
+                total += region.length;
+            }
+        }
+
+        if !has_map {
+            if let (Some(lower), Some(upper)) = (self.lower_memory_kb(), self.upper_memory_kb()) {
+                total = (lower as u64 + upper as u64) * 1024;
+            }
+        }
+
+        total
+    }
+
+    pub fn modules(&self) -> ModuleIter {
+        let (addr, count) = if self.has(FLAG_MODULES) {
+            (self.info.mods_addr as usize, self.info.mods_count as usize)
+        } else {
+            (0, 0)
+        };
+        ModuleIter { addr, index: 0, count }
+    }
+
+    pub fn command_line(&self) -> Option<&'static [u8]> {
+        if self.has(FLAG_CMDLINE) && self.info.cmdline != 0 {
+            Some(unsafe { c_str(self.info.cmdline) })
+        } else {
+            None
+        }
+    }
+
+    /// Whether `option` appears as a word on the kernel command line
+    pub fn has_option(&self, option: &[u8]) -> bool {
+        match self.command_line() {
+            Some(cmdline) => cmdline.split(|&b| b == b' ').any(|word| word == option),
+            None => false,
+        }
+    }
+
+    pub fn bootloader_name(&self) -> Option<&'static [u8]> {
+        if self.has(FLAG_BOOTLOADER_NAME) && self.info.boot_loader_name != 0 {
+            Some(unsafe { c_str(self.info.boot_loader_name) })
+        } else {
+            None
+        }
+    }
+
+    /// Calls `f` with the [start, end) of everything the boot loader left
+    /// for us outside the module payloads: this structure, the memory map,
+    /// the module list and the strings. It may sit in free memory, so the
+    /// frame allocator must keep clear of it and paging must map it
+    pub fn for_each_boot_data(&self, mut f: impl FnMut(u32, u32)) {
+        let info = self.info as *const MultibootInfo as u32;
+        f(info, info + size_of::<MultibootInfo>() as u32);
+
+        if self.has(FLAG_MEMORY_MAP) {
+            f(self.info.mmap_addr, self.info.mmap_addr + self.info.mmap_length);
+        }
+        if self.has(FLAG_MODULES) {
+            let list_size = self.info.mods_count * size_of::<RawModule>() as u32;
+            f(self.info.mods_addr, self.info.mods_addr + list_size);
+        }
+
+        let mut string = |s: &[u8]| {
+            let start = s.as_ptr() as u32;
+            // Keep the terminating NUL too
+            f(start, start + s.len() as u32 + 1);
+        };
+        if let Some(cmdline) = self.command_line() {
+            string(cmdline);
+        }
+        if let Some(name) = self.bootloader_name() {
+            string(name);
+        }
+        for module in self.modules() {
+            if !module.name.is_empty() {
+                string(module.name);
+            }
+        }
+    }
+}
+
+pub struct MemoryMapIter {
+    current: usize,
+    end: usize,
+}
+
+impl Iterator for MemoryMapIter {
+    type Item = MemoryRegion;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current + size_of::<RawMemoryMapEntry>() > self.end {
+            return None;
+        }
+
+        let entry = unsafe { core::ptr::read_unaligned(self.current as *const RawMemoryMapEntry) };
+        // `size` does not count the size field itself
+        self.current += entry.size as usize + 4;
+
+        Some(MemoryRegion {
+            base: entry.base_addr,
+            length: entry.length,
+            kind: MemoryRegionKind::from_raw(entry.kind),
+        })
+    }
+}
+
+pub struct ModuleIter {
+    addr: usize,
+    index: usize,
+    count: usize,
+}
+
+impl Iterator for ModuleIter {
+    type Item = Module;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.count {
+            return None;
+        }
+
+        let raw = unsafe { &*((self.addr as *const RawModule).add(self.index)) };
+        self.index += 1;
+
+        let name: &'static [u8] = if raw.string != 0 {
+            unsafe { c_str(raw.string) }
+        } else {
+            b""
+        };
+
+        Some(Module {
+            start: raw.mod_start,
+            end: raw.mod_end,
+            name,
+        })
+    }
+}
+
+/// Turns a NUL-terminated string left by the boot loader into a byte slice
+unsafe fn c_str(addr: u32) -> &'static [u8] {
+    let ptr = addr as *const u8;
+    let mut len = 0;
+    while len < MAX_CSTR_LEN && *ptr.add(len) != 0 {
+        len += 1;
+    }
+    core::slice::from_raw_parts(ptr, len)
+}
+
+static BOOT_INFO: Once<BootInfo> = Once::new();
+
+pub fn init(magic: u32, info_addr: u32) -> Result<(), &'static str> {
+    if magic != BOOTLOADER_MAGIC {
+        return Err("Invalid multiboot magic");
+    }
+    if info_addr == 0 {
+        return Err("Missing multiboot info structure");
+    }
+
+    BOOT_INFO.call_once(|| BootInfo {
+        info: unsafe { &*(info_addr as *const MultibootInfo) },
+    });
+    Ok(())
+}
+
+pub fn boot_info() -> Option<&'static BootInfo> {
+    BOOT_INFO.get()
+}
@@ -0,0 +1,355 @@
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::ops::BitOr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::frame_allocator::{get_frame_allocator, kernel_bounds, FRAME_SIZE, LOW_MEMORY_END};
+use crate::gdt;
+use crate::heap;
+use crate::multiboot;
+use crate::sync::{IrqSpinLock, IrqSpinLockGuard};
+
+const ENTRIES_PER_TABLE: usize = 1024;
+const PAGE_SIZE: u32 = FRAME_SIZE;
+const ADDRESS_MASK: u32 = 0xFFFF_F000;
+
+// The last directory slot points back at the directory itself, so once paging
+// is on every page table shows up in the top 4 MB of the address space
+const RECURSIVE_INDEX: usize = 1023;
+const RECURSIVE_TABLES_BASE: u32 = 0xFFC0_0000;
+const RECURSIVE_DIRECTORY: u32 = 0xFFFF_F000;
+
+/// Addresses each `AddressSpace` maps for itself. Everything outside this
+/// range belongs to the kernel and looks the same in every address space
+pub const PRIVATE_START: u32 = 0x4000_0000;
+pub const PRIVATE_END: u32 = 0x8000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFlags(u32);
+
+impl PageFlags {
+    pub const PRESENT: PageFlags = PageFlags(1 << 0);
+    pub const WRITABLE: PageFlags = PageFlags(1 << 1);
+    pub const USER: PageFlags = PageFlags(1 << 2);
+    pub const WRITE_THROUGH: PageFlags = PageFlags(1 << 3);
+    pub const NO_CACHE: PageFlags = PageFlags(1 << 4);
+
+    pub const KERNEL_RW: PageFlags = PageFlags(Self::PRESENT.0 | Self::WRITABLE.0);
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(&self, other: PageFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for PageFlags {
+    type Output = PageFlags;
+
+    fn bitor(self, rhs: PageFlags) -> PageFlags {
+        PageFlags(self.0 | rhs.0)
+    }
+}
+
+#[repr(C, align(4096))]
+struct PageTable {
+    entries: [u32; ENTRIES_PER_TABLE],
+}
+
+// The kernel's own directory. Its lock also serialises every change to the
+// page tables, whichever directory is active
+static PAGE_DIRECTORY: IrqSpinLock<PageTable> = IrqSpinLock::new(PageTable {
+    entries: [0; ENTRIES_PER_TABLE],
+});
+static PAGING_ENABLED: AtomicBool = AtomicBool::new(false);
+
+type KernelDirectory = IrqSpinLockGuard<'static, PageTable>;
+
+fn address_of(directory: &PageTable) -> u32 {
+    directory as *const PageTable as u32
+}
+
+/// Builds the kernel address space and switches paging on
+pub fn init() -> Result<(), &'static str> {
+    {
+        let mut directory = PAGE_DIRECTORY.lock();
+        for entry in directory.entries.iter_mut() {
+            *entry = 0;
+        }
+        directory.entries[RECURSIVE_INDEX] = address_of(&directory) | PageFlags::KERNEL_RW.bits();
+    }
+
+    // BIOS data area, VGA mode 13h framebuffer (0xA0000) and text buffer (0xB8000)
+    identity_map(0, LOW_MEMORY_END, PageFlags::KERNEL_RW)?;
+
+    let (kernel_start, kernel_end) = kernel_bounds();
+    identity_map(kernel_start, kernel_end, PageFlags::KERNEL_RW)?;
+
+    let (heap_start, heap_end) = heap::bounds();
+    identity_map(heap_start as u32, heap_end as u32, PageFlags::KERNEL_RW)?;
+
+    if let Some(info) = multiboot::boot_info() {
+        for module in info.modules() {
+            identity_map(module.start, module.end, PageFlags::KERNEL_RW)?;
+        }
+        let mut mapped = Ok(());
+        info.for_each_boot_data(|start, end| {
+            if mapped.is_ok() {
+                mapped = identity_map(start, end, PageFlags::KERNEL_RW);
+            }
+        });
+        mapped?;
+    }
+
+    let directory = PAGE_DIRECTORY.lock();
+    let address = address_of(&directory);
+    gdt::set_page_directory(address);
+    unsafe {
+        asm!("mov cr3, {}", in(reg) address, options(nostack));
+
+        // PG plus WP, so the kernel also honours read-only pages
+        let mut cr0: u32;
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack));
+        cr0 |= (1 << 31) | (1 << 16);
+        asm!("mov cr0, {}", in(reg) cr0, options(nostack));
+    }
+    PAGING_ENABLED.store(true, Ordering::Release);
+
+    Ok(())
+}
+
+pub fn is_enabled() -> bool {
+    PAGING_ENABLED.load(Ordering::Acquire)
+}
+
+/// A page directory of its own for [PRIVATE_START, PRIVATE_END), sharing
+/// every kernel mapping. Mapping into it works like mapping anywhere else,
+/// once `switch_directory` has made it the active one
+pub struct AddressSpace {
+    directory: Box<PageTable>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, &'static str> {
+        if !is_enabled() {
+            return Err("Address spaces need paging");
+        }
+        let mut space = AddressSpace {
+            directory: Box::new(PageTable {
+                entries: [0; ENTRIES_PER_TABLE],
+            }),
+        };
+        let address = space.directory();
+        space.directory.entries[RECURSIVE_INDEX] = address | PageFlags::KERNEL_RW.bits();
+        copy_shared_entries(&PAGE_DIRECTORY.lock(), &mut space.directory);
+        Ok(space)
+    }
+
+    /// Physical address of the directory, as loaded into CR3. The heap is
+    /// identity-mapped, so it is also the virtual one
+    pub fn directory(&self) -> u32 {
+        &*self.directory as *const PageTable as u32
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees the page tables. Whoever mapped pages into the space must
+    /// have unmapped them already
+    fn drop(&mut self) {
+        assert!(active_directory() != self.directory(), "dropping the active address space");
+        for &entry in self.directory.entries[private_indexes()].iter() {
+            if entry & PageFlags::PRESENT.bits() != 0 {
+                let _ = get_frame_allocator().free_frame(entry & ADDRESS_MASK);
+            }
+        }
+    }
+}
+
+/// Makes `directory`, from `AddressSpace::directory`, the active one, or
+/// the kernel's own if it is 0. Used by the scheduler on every switch
+pub fn switch_directory(directory: u32) {
+    if !is_enabled() {
+        return;
+    }
+    let kernel = PAGE_DIRECTORY.lock();
+    let target = if directory == 0 { address_of(&kernel) } else { directory };
+    if active_directory() == target {
+        return;
+    }
+    if directory != 0 {
+        // Catch up on kernel tables created while it was switched out
+        copy_shared_entries(&kernel, unsafe { &mut *(directory as *mut PageTable) });
+    }
+    unsafe {
+        asm!("mov cr3, {}", in(reg) target, options(nostack));
+    }
+}
+
+fn active_directory() -> u32 {
+    let cr3: u32;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+    cr3 & ADDRESS_MASK
+}
+
+fn private_indexes() -> core::ops::Range<usize> {
+    (PRIVATE_START >> 22) as usize..(PRIVATE_END >> 22) as usize
+}
+
+/// Copies the kernel's directory entries outside the private range into
+/// `directory`, leaving its own recursive entry alone
+fn copy_shared_entries(kernel: &PageTable, directory: &mut PageTable) {
+    let private = private_indexes();
+    for index in 0..RECURSIVE_INDEX {
+        if !private.contains(&index) {
+            directory.entries[index] = kernel.entries[index];
+        }
+    }
+}
+
+pub fn map_page(virt: u32, phys: u32, flags: PageFlags) -> Result<(), &'static str> {
+    if virt % PAGE_SIZE != 0 || phys % PAGE_SIZE != 0 {
+        return Err("Page address not aligned");
+    }
+
+    let dir_index = (virt >> 22) as usize;
+    if dir_index == RECURSIVE_INDEX {
+        return Err("Address reserved for page tables");
+    }
+
+    let mut kernel = PAGE_DIRECTORY.lock();
+    let table = unsafe { table_for(&mut kernel, dir_index, flags)? };
+    let table_index = ((virt >> 12) & 0x3FF) as usize;
+
+    unsafe {
+        if (*table).entries[table_index] & PageFlags::PRESENT.bits() != 0 {
+            return Err("Page already mapped");
+        }
+        (*table).entries[table_index] = phys | flags.bits() | PageFlags::PRESENT.bits();
+        flush_tlb(virt);
+    }
+    Ok(())
+}
+
+/// Removes a mapping and returns the physical frame it pointed at
+pub fn unmap_page(virt: u32) -> Result<u32, &'static str> {
+    let dir_index = (virt >> 22) as usize;
+    if dir_index == RECURSIVE_INDEX {
+        return Err("Address reserved for page tables");
+    }
+
+    let mut kernel = PAGE_DIRECTORY.lock();
+    let table = unsafe { existing_table(&mut kernel, dir_index).ok_or("Page not mapped")? };
+    let table_index = ((virt >> 12) & 0x3FF) as usize;
+
+    unsafe {
+        let entry = (*table).entries[table_index];
+        if entry & PageFlags::PRESENT.bits() == 0 {
+            return Err("Page not mapped");
+        }
+        (*table).entries[table_index] = 0;
+        flush_tlb(virt & ADDRESS_MASK);
+        Ok(entry & ADDRESS_MASK)
+    }
+}
+
+/// Maps every page touching [start, end) onto the same physical address
+pub fn identity_map(start: u32, end: u32, flags: PageFlags) -> Result<(), &'static str> {
+    if end <= start {
+        return Ok(());
+    }
+
+    let mut page = start & ADDRESS_MASK;
+    while page < end {
+        match translate(page) {
+            Some(phys) if phys == page => {}
+            Some(_) => return Err("Page already mapped"),
+            None => map_page(page, page, flags)?,
+        }
+        page = match page.checked_add(PAGE_SIZE) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(())
+}
+
+pub fn translate(virt: u32) -> Option<u32> {
+    let dir_index = (virt >> 22) as usize;
+    let mut kernel = PAGE_DIRECTORY.lock();
+    let table = unsafe { existing_table(&mut kernel, dir_index)? };
+    let entry = unsafe { (*table).entries[((virt >> 12) & 0x3FF) as usize] };
+
+    if entry & PageFlags::PRESENT.bits() == 0 {
+        return None;
+    }
+    Some((entry & ADDRESS_MASK) | (virt & !ADDRESS_MASK))
+}
+
+/// The active directory, reached through its recursive entry once paging
+/// is on
+fn directory(kernel: &mut KernelDirectory) -> *mut PageTable {
+    if is_enabled() {
+        RECURSIVE_DIRECTORY as *mut PageTable
+    } else {
+        &mut **kernel as *mut PageTable
+    }
+}
+
+/// Address through which the page table for `dir_index` can be written
+unsafe fn table_address(kernel: &mut KernelDirectory, dir_index: usize) -> *mut PageTable {
+    if is_enabled() {
+        (RECURSIVE_TABLES_BASE + (dir_index as u32) * PAGE_SIZE) as *mut PageTable
+    } else {
+        ((*directory(kernel)).entries[dir_index] & ADDRESS_MASK) as *mut PageTable
+    }
+}
+
+unsafe fn existing_table(kernel: &mut KernelDirectory, dir_index: usize) -> Option<*mut PageTable> {
+    if (*directory(kernel)).entries[dir_index] & PageFlags::PRESENT.bits() == 0 {
+        return None;
+    }
+    Some(table_address(kernel, dir_index))
+}
+
+unsafe fn table_for(
+    kernel: &mut KernelDirectory,
+    dir_index: usize,
+    flags: PageFlags,
+) -> Result<*mut PageTable, &'static str> {
+    let dir = directory(kernel);
+
+    if (*dir).entries[dir_index] & PageFlags::PRESENT.bits() == 0 {
+        let frame = get_frame_allocator()
+            .allocate_frame()
+            .ok_or("Out of physical memory for page tables")?;
+        (*dir).entries[dir_index] = frame | PageFlags::KERNEL_RW.bits();
+
+        let table = table_address(kernel, dir_index);
+        flush_tlb(table as u32);
+        for entry in (*table).entries.iter_mut() {
+            *entry = 0;
+        }
+    }
+
+    // User pages need the user bit on the directory entry as well
+    if flags.contains(PageFlags::USER) {
+        (*dir).entries[dir_index] |= PageFlags::USER.bits();
+    }
+    // Kernel tables are shared, so the kernel's directory must hear about
+    // one created while another address space is active
+    if !private_indexes().contains(&dir_index) {
+        kernel.entries[dir_index] = (*dir).entries[dir_index];
+    }
+
+    Ok(table_address(kernel, dir_index))
+}
+
+unsafe fn flush_tlb(virt: u32) {
+    if is_enabled() {
+        asm!("invlpg [{}]", in(reg) virt, options(nostack));
+    }
+}
@@ -0,0 +1,106 @@
+use core::arch::asm;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::paging;
+use crate::vga_colors::{Color, color_code};
+use crate::writer::Writer;
+
+const MAX_BACKTRACE_DEPTH: usize = 16;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Reports a Rust panic on a fresh writer, so it works no matter what state
+/// the CLI's writer was left in, then halts with interrupts disabled
+pub fn handle(info: &PanicInfo) -> ! {
+    unsafe {
+        asm!("cli", options(nostack));
+    }
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        halt();
+    }
+
+    let mut writer = Writer::new(color_code(Color::White, Color::Blue));
+    writer.clear();
+
+    writer.set_color(Color::White, Color::Red);
+    writer.write_str(" KERNEL PANIC \n\n");
+
+    writer.set_color(Color::Yellow, Color::Blue);
+    let _ = writeln!(writer, "{}", info.message());
+
+    writer.set_color(Color::White, Color::Blue);
+    match info.location() {
+        Some(location) => {
+            let _ = writeln!(
+                writer,
+                "at {}:{}:{}\n",
+                location.file(),
+                location.line(),
+                location.column()
+            );
+        }
+        None => writer.write_str("at unknown location\n\n"),
+    }
+
+    writer.set_color(Color::LightCyan, Color::Blue);
+    writer.write_str("Backtrace:\n");
+    writer.set_color(Color::White, Color::Blue);
+    write_backtrace(&mut writer);
+
+    writer.set_color(Color::LightGray, Color::Blue);
+    writer.write_str("\nSystem halted.\n");
+
+    halt();
+}
+
+/// Walks the saved EBP chain; needs the kernel built with frame pointers
+fn write_backtrace(writer: &mut Writer) {
+    let mut ebp: u32;
+    unsafe {
+        asm!("mov {}, ebp", out(reg) ebp, options(nomem, nostack));
+    }
+
+    let mut depth = 0;
+    while depth < MAX_BACKTRACE_DEPTH && is_valid_frame(ebp) {
+        let (next_ebp, return_addr) = unsafe {
+            let frame = ebp as *const u32;
+            (*frame, *frame.add(1))
+        };
+        if return_addr == 0 {
+            break;
+        }
+
+        let _ = writeln!(writer, "  #{:<2} {:#010x}", depth, return_addr);
+        depth += 1;
+
+        // The caller's frame always lives higher up the stack
+        if next_ebp <= ebp {
+            break;
+        }
+        ebp = next_ebp;
+    }
+
+    if depth == 0 {
+        writer.write_str("  (no frames)\n");
+    }
+}
+
+fn is_valid_frame(ebp: u32) -> bool {
+    if ebp == 0 || ebp % 4 != 0 || ebp > u32::MAX - 8 {
+        return false;
+    }
+    if paging::is_enabled() {
+        return paging::translate(ebp).is_some() && paging::translate(ebp + 4).is_some();
+    }
+    true
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe {
+            asm!("cli", "hlt", options(nostack));
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use crate::io::{inb, outb};
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+const PIC_EOI: u8 = 0x20;
+const PIC_READ_ISR: u8 = 0x0B;
+
+const IRQ_CASCADE: u8 = 2;
+const IRQ_MASTER_SPURIOUS: u8 = 7;
+const IRQ_SLAVE_SPURIOUS: u8 = 15;
+
+// Bit n set = IRQ n masked; the cascade line is opened on demand
+static MASK: AtomicU16 = AtomicU16::new(0xFFFF);
+
+/// Remaps both 8259s to `base_vector` and up with every line masked
+pub fn init(base_vector: u8) {
+    unsafe {
+        outb(PIC1_COMMAND, 0x11);
+        outb(PIC2_COMMAND, 0x11);
+
+        outb(PIC1_DATA, base_vector);
+        outb(PIC2_DATA, base_vector + 8);
+
+        outb(PIC1_DATA, 0x04);
+        outb(PIC2_DATA, 0x02);
+
+        outb(PIC1_DATA, 0x01);
+        outb(PIC2_DATA, 0x01);
+    }
+    MASK.store(0xFFFF, Ordering::SeqCst);
+    write_mask();
+}
+
+/// Masks every line, for when the IOAPIC takes over
+pub fn disable() {
+    MASK.store(0xFFFF, Ordering::SeqCst);
+    write_mask();
+}
+
+pub fn set_masked(irq: u8, masked: bool) {
+    let mut mask = MASK.load(Ordering::SeqCst);
+    if masked {
+        mask |= 1 << irq;
+        // Close the cascade once no slave line is left open
+        if mask & 0xFF00 == 0xFF00 {
+            mask |= 1 << IRQ_CASCADE;
+        }
+    } else {
+        mask &= !(1 << irq);
+        if irq >= 8 {
+            mask &= !(1 << IRQ_CASCADE);
+        }
+    }
+    MASK.store(mask, Ordering::SeqCst);
+    write_mask();
+}
+
+fn write_mask() {
+    let mask = MASK.load(Ordering::SeqCst);
+    unsafe {
+        outb(PIC1_DATA, (mask & 0xFF) as u8);
+        outb(PIC2_DATA, (mask >> 8) as u8);
+    }
+}
+
+fn in_service(irq: u8) -> bool {
+    unsafe {
+        if irq < 8 {
+            outb(PIC1_COMMAND, PIC_READ_ISR);
+            inb(PIC1_COMMAND) & (1 << irq) != 0
+        } else {
+            outb(PIC2_COMMAND, PIC_READ_ISR);
+            inb(PIC2_COMMAND) & (1 << (irq - 8)) != 0
+        }
+    }
+}
+
+/// IRQ 7 and 15 fire on their own when a line drops before the CPU
+/// acknowledges it; the ISR bit tells a real interrupt from a ghost.
+/// A spurious IRQ must not be acknowledged, except on the master's
+/// cascade line for a slave ghost
+pub fn is_spurious(irq: u8) -> bool {
+    if irq != IRQ_MASTER_SPURIOUS && irq != IRQ_SLAVE_SPURIOUS {
+        return false;
+    }
+    if in_service(irq) {
+        return false;
+    }
+    if irq == IRQ_SLAVE_SPURIOUS {
+        unsafe {
+            outb(PIC1_COMMAND, PIC_EOI);
+        }
+    }
+    true
+}
+
+pub fn end_of_interrupt(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            outb(PIC2_COMMAND, PIC_EOI);
+        }
+        outb(PIC1_COMMAND, PIC_EOI);
+    }
+}
@@ -0,0 +1,149 @@
+use core::arch::asm;
+
+use crate::acpi;
+use crate::io::{inb, inw, outb, outw};
+use crate::sync::SpinLock;
+use crate::timer;
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_COMMAND: u16 = 0x64;
+const KBC_STATUS_INPUT_FULL: u8 = 0x02;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+// PM1 control register bits
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+// Emulator shortcuts for when ACPI is missing or ignored
+const QEMU_SHUTDOWN: (u16, u16) = (0x604, 0x2000);
+const BOCHS_SHUTDOWN: (u16, u16) = (0xB004, 0x2000);
+const VIRTUALBOX_SHUTDOWN: (u16, u16) = (0x4004, 0x3400);
+
+const MAX_SHUTDOWN_HOOKS: usize = 8;
+const ACPI_ENABLE_TIMEOUT_MS: u64 = 300;
+
+#[derive(Clone, Copy)]
+struct ShutdownHook {
+    name: &'static str,
+    hook: fn(),
+}
+
+static SHUTDOWN_HOOKS: SpinLock<[Option<ShutdownHook>; MAX_SHUTDOWN_HOOKS]> = SpinLock::new([None; MAX_SHUTDOWN_HOOKS]);
+
+/// Registers `hook` to run before the machine reboots or powers off
+pub fn register_shutdown_hook(name: &'static str, hook: fn()) -> Result<(), &'static str> {
+    let mut hooks = SHUTDOWN_HOOKS.lock();
+    let slot = hooks
+        .iter_mut()
+        .find(|h| h.is_none())
+        .ok_or("Too many shutdown hooks")?;
+    *slot = Some(ShutdownHook { name, hook });
+    Ok(())
+}
+
+fn run_shutdown_hooks() {
+    // A copy, so hooks run without the lock held
+    let hooks = *SHUTDOWN_HOOKS.lock();
+    for hook in hooks.iter().flatten() {
+        info!("Running shutdown hook '{}'", hook.name);
+        (hook.hook)();
+    }
+}
+
+/// Resets the machine through the keyboard controller, then by triple fault
+pub fn reboot() -> ! {
+    run_shutdown_hooks();
+    warn!("Rebooting");
+
+    unsafe {
+        asm!("cli", options(nostack));
+
+        let mut spins = 0;
+        while inb(KBC_STATUS) & KBC_STATUS_INPUT_FULL != 0 && spins < 100_000 {
+            spins += 1;
+        }
+        outb(KBC_COMMAND, KBC_PULSE_RESET);
+    }
+
+    // Give the controller a moment before pulling the rug out ourselves
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+
+    // An empty IDT turns the next interrupt into a triple fault
+    let null_idt: [u16; 3] = [0; 3];
+    unsafe {
+        asm!("lidt [{}]", "int3", in(reg) &null_idt, options(nostack));
+    }
+    halt();
+}
+
+/// Powers off through ACPI S5, falling back to emulator shutdown ports
+pub fn shutdown() -> ! {
+    run_shutdown_hooks();
+    warn!("Powering off");
+
+    if let Err(e) = acpi_power_off() {
+        warn!("ACPI power-off failed: {}", e);
+    }
+
+    unsafe {
+        asm!("cli", options(nostack));
+        for &(port, value) in &[QEMU_SHUTDOWN, BOCHS_SHUTDOWN, VIRTUALBOX_SHUTDOWN] {
+            outw(port, value);
+        }
+    }
+
+    error!("Power-off failed, halting");
+    halt();
+}
+
+fn acpi_power_off() -> Result<(), &'static str> {
+    let fadt = acpi::fadt().ok_or("ACPI not available")?;
+    if fadt.pm1a_control_block == 0 {
+        return Err("FADT has no PM1a control block");
+    }
+    let (slp_typ_a, slp_typ_b) = acpi::s5_sleep_types().ok_or("No \\_S5 object in the DSDT")?;
+
+    let pm1a = fadt.pm1a_control_block as u16;
+    let pm1b = fadt.pm1b_control_block as u16;
+
+    unsafe {
+        // Firmware may still own the hardware until ACPI mode is switched on
+        if inw(pm1a) & SCI_EN == 0 {
+            if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+                return Err("Cannot switch to ACPI mode");
+            }
+            outb(fadt.smi_command_port as u16, fadt.acpi_enable);
+            let timeout = timer::Timeout::after_ms(ACPI_ENABLE_TIMEOUT_MS);
+            while inw(pm1a) & SCI_EN == 0 {
+                if timeout.expired() {
+                    return Err("Firmware did not enter ACPI mode");
+                }
+            }
+        }
+
+        asm!("cli", options(nostack));
+        // Keep SCI_EN and the other control bits as the firmware set them
+        outw(pm1a, (inw(pm1a) & !SLP_TYP_MASK) | (slp_typ_a << SLP_TYP_SHIFT) | SLP_EN);
+        if pm1b != 0 {
+            outw(pm1b, (inw(pm1b) & !SLP_TYP_MASK) | (slp_typ_b << SLP_TYP_SHIFT) | SLP_EN);
+        }
+    }
+
+    // Power should be gone by now; give the chipset a moment
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+    Err("Machine did not power off")
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe {
+            asm!("cli", "hlt", options(nostack));
+        }
+    }
+}
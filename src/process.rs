@@ -0,0 +1,504 @@
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use crate::sync::SpinLock;
+use crate::syscall::FileTable;
+use crate::thread::{self, ThreadId, WaitQueue};
+use crate::usermode::Program;
+
+pub const MAX_PROCESSES: usize = 16;
+const MAX_NAME_LEN: usize = 16;
+
+/// The kernel itself: the boot thread, which goes on to run the shell
+pub const KERNEL_PID: Pid = Pid(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pid(u32);
+
+impl Pid {
+    pub fn from_u32(pid: u32) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u32(&self) -> u32 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    // Exited with the given code, which the parent has not collected yet
+    Zombie(i32),
+}
+
+/// What the keyboard driver sends the foreground process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    // Ctrl+C: stop what you are doing
+    Interrupt,
+    // Ctrl+Z: let the shell have the keyboard back
+    Suspend,
+}
+
+impl Signal {
+    fn from_u8(value: u8) -> Option<Signal> {
+        match value {
+            1 => Some(Signal::Interrupt),
+            2 => Some(Signal::Suspend),
+            _ => None,
+        }
+    }
+
+    fn as_u8(&self) -> u8 {
+        match self {
+            Signal::Interrupt => 1,
+            Signal::Suspend => 2,
+        }
+    }
+}
+
+impl ProcessState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProcessState::Running => "running",
+            ProcessState::Zombie(_) => "zombie",
+        }
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    // None once the parent has exited; nobody collects such a process
+    parent: Option<Pid>,
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    thread: ThreadId,
+    state: ProcessState,
+    // Background processes read end-of-file from the keyboard
+    background: bool,
+    kill_requested: bool,
+    // Directory index relative names resolve against
+    cwd: usize,
+    pub files: FileTable,
+    // The user program the process's thread is running, if any
+    pub program: Option<Program>,
+}
+
+struct ProcessTable {
+    processes: [Option<Process>; MAX_PROCESSES],
+    next_pid: u32,
+}
+
+impl ProcessTable {
+    fn find(&self, pid: Pid) -> Option<usize> {
+        self.processes.iter().position(|p| matches!(p, Some(p) if p.pid == pid))
+    }
+
+    fn find_thread(&self, thread: ThreadId) -> Option<usize> {
+        self.processes
+            .iter()
+            .position(|p| matches!(p, Some(p) if p.thread == thread && p.state == ProcessState::Running))
+    }
+}
+
+static PROCESSES: SpinLock<ProcessTable> = SpinLock::new(ProcessTable {
+    processes: [const { None }; MAX_PROCESSES],
+    next_pid: 1,
+});
+
+// Woken when a process exits or goes to the background, and when a
+// signal arrives, as waiting is what a foreground shell gets stuck in
+static EXITED: WaitQueue = WaitQueue::new();
+
+// The process keyboard input and signals go to. The keyboard IRQ reads
+// these, so they live outside the locked table
+static FOREGROUND: AtomicU32 = AtomicU32::new(KERNEL_PID.0);
+static PENDING_SIGNAL: AtomicU8 = AtomicU8::new(0);
+
+/// A snapshot of one process for `ps`
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub thread: ThreadId,
+    pub state: ProcessState,
+    pub background: bool,
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+}
+
+impl ProcessInfo {
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+/// Makes the calling thread process 1, the one every other is started from
+pub fn init() {
+    let mut table = PROCESSES.lock();
+    let pid = Pid(table.next_pid);
+    table.next_pid += 1;
+    table.processes[0] = Some(new_process(pid, None, b"kernel", thread::current(), 0, false));
+}
+
+fn new_process(
+    pid: Pid,
+    parent: Option<Pid>,
+    name: &[u8],
+    thread: ThreadId,
+    cwd: usize,
+    background: bool,
+) -> Process {
+    let mut process = Process {
+        pid,
+        parent,
+        name: [0; MAX_NAME_LEN],
+        name_len: name.len().min(MAX_NAME_LEN),
+        thread,
+        state: ProcessState::Running,
+        background,
+        kill_requested: false,
+        cwd,
+        files: FileTable::new(),
+        program: None,
+    };
+    process.name[..process.name_len].copy_from_slice(&name[..process.name_len]);
+    process
+}
+
+/// Starts `f` on a thread of its own as a child of the calling process,
+/// inheriting its current directory. What `f` returns is the
+/// exit code the parent collects with `wait`
+pub fn spawn<F>(name: &[u8], background: bool, f: F) -> Result<Pid, &'static str>
+where
+    F: FnOnce() -> i32 + Send + 'static,
+{
+    let current = thread::current();
+    let mut table = PROCESSES.lock();
+    let parent = table.find_thread(current).ok_or("Only a process can start processes")?;
+    let slot = table.processes.iter().position(|p| p.is_none()).ok_or("Too many processes")?;
+    let (parent_pid, cwd) = {
+        let parent = table.processes[parent].as_ref().unwrap();
+        (parent.pid, parent.cwd)
+    };
+
+    // The table stays locked, so the new thread cannot look itself up
+    // before it is entered
+    let pid = Pid(table.next_pid);
+    let thread = thread::spawn("process", move || {
+        let code = f();
+        finish(pid, code);
+    })?;
+    table.next_pid += 1;
+    table.processes[slot] = Some(new_process(pid, Some(parent_pid), name, thread, cwd, background));
+    Ok(pid)
+}
+
+/// Records that `pid` exited with `code`. Its children are orphaned, and
+/// it is forgotten at once if it is an orphan itself
+fn finish(pid: Pid, code: i32) {
+    {
+        let mut table = PROCESSES.lock();
+        for slot in table.processes.iter_mut() {
+            let orphaned_zombie = match slot {
+                Some(child) if child.parent == Some(pid) => {
+                    child.parent = None;
+                    child.state != ProcessState::Running
+                }
+                _ => false,
+            };
+            if orphaned_zombie {
+                *slot = None;
+            }
+        }
+
+        if let Some(index) = table.find(pid) {
+            let process = table.processes[index].as_mut().unwrap();
+            if process.parent.is_none() {
+                table.processes[index] = None;
+            } else {
+                process.state = ProcessState::Zombie(code);
+                process.files = FileTable::new();
+            }
+        }
+    }
+    EXITED.wake_all();
+}
+
+/// Waits for child `pid` of the calling process to exit and returns its
+/// exit code, releasing its slot. Ctrl+C gives up on it if the caller is
+/// in the foreground
+pub fn wait(pid: Pid) -> Result<i32, &'static str> {
+    wait_for(pid, false).map(|code| code.unwrap_or(0))
+}
+
+/// Like `wait`, with `pid` in the foreground until it exits. Returns None
+/// instead of an exit code if Ctrl+Z sent it to the background
+pub fn wait_foreground(pid: Pid) -> Result<Option<i32>, &'static str> {
+    let me = current().ok_or("Not a process")?;
+    FOREGROUND.store(pid.0, Ordering::Release);
+    let result = wait_for(pid, true);
+    // Keys pressed for the child are not meant for us
+    FOREGROUND.store(me.0, Ordering::Release);
+    PENDING_SIGNAL.store(0, Ordering::Release);
+    result
+}
+
+fn wait_for(pid: Pid, foreground: bool) -> Result<Option<i32>, &'static str> {
+    let me = current().ok_or("Not a process")?;
+    let mut result = Err("No such process");
+    let mut thread = None;
+    EXITED.wait_until(|| {
+        let mut table = PROCESSES.lock();
+        let index = match table.find(pid) {
+            Some(index) => index,
+            None => return true,
+        };
+        let process = table.processes[index].as_mut().unwrap();
+        if process.parent != Some(me) {
+            result = Err("Not a child of this process");
+            return true;
+        }
+        match process.state {
+            ProcessState::Zombie(code) => {
+                result = Ok(Some(code));
+                thread = Some(process.thread);
+                table.processes[index] = None;
+                true
+            }
+            ProcessState::Running if foreground && process.background => {
+                result = Ok(None);
+                true
+            }
+            ProcessState::Running if !foreground && take_interrupt_for(me) => {
+                result = Err("Interrupted");
+                true
+            }
+            ProcessState::Running => false,
+        }
+    });
+    if let Some(thread) = thread {
+        let _ = thread::join(thread);
+    }
+    result
+}
+
+/// Collects any one child of the calling process that has exited, without
+/// blocking
+pub fn try_wait() -> Option<(Pid, i32)> {
+    let me = current()?;
+    let (pid, code, thread) = {
+        let mut table = PROCESSES.lock();
+        let index = table.processes.iter().position(|p| {
+            matches!(p, Some(p) if p.parent == Some(me) && p.state != ProcessState::Running)
+        })?;
+        let process = table.processes[index].take().unwrap();
+        match process.state {
+            ProcessState::Zombie(code) => (process.pid, code, process.thread),
+            ProcessState::Running => unreachable!(),
+        }
+    };
+    let _ = thread::join(thread);
+    Some((pid, code))
+}
+
+/// Asks process `pid` to exit. A user program acts on it the next time it
+/// makes a system call or is interrupted
+pub fn kill(pid: Pid) -> Result<(), &'static str> {
+    if pid == KERNEL_PID {
+        return Err("The kernel process cannot be killed");
+    }
+    let mut table = PROCESSES.lock();
+    let index = table.find(pid).ok_or("No such process")?;
+    let process = table.processes[index].as_mut().unwrap();
+    if process.state != ProcessState::Running {
+        return Err("Process already exited");
+    }
+    process.kill_requested = true;
+    Ok(())
+}
+
+/// The process the calling thread belongs to
+pub fn current() -> Option<Pid> {
+    with_current(|p| p.pid)
+}
+
+pub fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let current = thread::current();
+    let mut table = PROCESSES.lock();
+    let index = table.find_thread(current)?;
+    Some(f(table.processes[index].as_mut().unwrap()))
+}
+
+/// Hands `signal` to the foreground process. Called by the keyboard IRQ
+pub fn raise(signal: Signal) {
+    PENDING_SIGNAL.store(signal.as_u8(), Ordering::Release);
+    EXITED.wake_all();
+}
+
+pub fn foreground() -> Pid {
+    Pid(FOREGROUND.load(Ordering::Acquire))
+}
+
+/// Takes the signal waiting for the calling process, if it is the
+/// foreground one
+pub fn take_signal() -> Option<Signal> {
+    take_signal_for(current()?)
+}
+
+fn take_signal_for(pid: Pid) -> Option<Signal> {
+    if pid != foreground() {
+        return None;
+    }
+    Signal::from_u8(PENDING_SIGNAL.swap(0, Ordering::AcqRel))
+}
+
+/// Collects a pending Ctrl+C for `pid`, leaving any other signal pending
+fn take_interrupt_for(pid: Pid) -> bool {
+    pid == foreground()
+        && PENDING_SIGNAL
+            .compare_exchange(Signal::Interrupt.as_u8(), 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+}
+
+/// Whether a signal is waiting for the calling process, left for
+/// `take_signal` to collect
+pub fn signal_pending() -> bool {
+    PENDING_SIGNAL.load(Ordering::Acquire) != 0 && current() == Some(foreground())
+}
+
+/// For built-in commands, which end on any signal: whether Ctrl+C or
+/// Ctrl+Z was pressed since they last asked
+pub fn interrupted() -> bool {
+    take_signal().is_some()
+}
+
+/// Puts the calling process in the background and gives the keyboard back
+/// to its parent, which stops waiting for it in `wait_foreground`
+pub fn move_to_background() {
+    let parent = with_current(|p| {
+        p.background = true;
+        p.parent
+    });
+    if let Some(Some(parent)) = parent {
+        FOREGROUND.store(parent.0, Ordering::Release);
+    }
+    EXITED.wake_all();
+}
+
+pub fn kill_requested() -> bool {
+    with_current(|p| p.kill_requested).unwrap_or(false)
+}
+
+pub fn is_background() -> bool {
+    with_current(|p| p.background).unwrap_or(false)
+}
+
+/// Runs `f` on the calling process's open files, with the process table
+/// locked; it must not open anything itself
+pub fn with_files<R>(f: impl FnOnce(&mut FileTable) -> R) -> Option<R> {
+    with_current(|p| f(&mut p.files))
+}
+
+/// Directory index the calling process resolves names in; the root for
+/// threads outside any process
+pub fn current_dir() -> usize {
+    with_current(|p| p.cwd).unwrap_or(0)
+}
+
+pub fn set_current_dir(dir: usize) {
+    with_current(|p| p.cwd = dir);
+}
+
+/// Calls `f` with every process, running or waiting to be collected
+pub fn for_each(mut f: impl FnMut(&ProcessInfo)) {
+    for i in 0..MAX_PROCESSES {
+        let info = {
+            let table = PROCESSES.lock();
+            table.processes[i].as_ref().map(|p| ProcessInfo {
+                pid: p.pid,
+                parent: p.parent,
+                thread: p.thread,
+                state: p.state,
+                background: p.background,
+                name: p.name,
+                name_len: p.name_len,
+            })
+        };
+        if let Some(info) = info {
+            f(&info);
+        }
+    }
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+    use crate::timer;
+    use crate::usermode;
+
+    // jmp $ - spins in ring 3 until something stops it
+    static SPIN: [u8; 2] = [0xEB, 0xFE];
+
+    pub fn wait_collects_exit_code() {
+        let child = spawn(b"test-exit", false, || 7).expect("spawn failed");
+        assert_eq!(wait(child), Ok(7));
+        assert_eq!(wait(child), Err("No such process"));
+        assert_eq!(wait(KERNEL_PID), Err("Not a child of this process"));
+    }
+
+    pub fn children_inherit_current_directory() {
+        set_current_dir(3);
+        let child = spawn(b"test-cwd", false, || current_dir() as i32).expect("spawn failed");
+        set_current_dir(0);
+        assert_eq!(wait(child), Ok(3));
+        assert_eq!(current_dir(), 0);
+    }
+
+    pub fn kill_stops_running_program() {
+        let child = spawn(b"test-spin", true, || {
+            usermode::run(&SPIN, &[b"spin"], &[]).unwrap_or(-1)
+        })
+        .expect("spawn failed");
+        // Let it get into ring 3 first
+        timer::sleep_ms(20);
+        assert_eq!(try_wait(), None);
+
+        assert_eq!(kill(child), Ok(()));
+        assert_eq!(wait(child), Ok(usermode::EXIT_TERMINATED));
+        assert_eq!(kill(child), Err("No such process"));
+        assert_eq!(kill(KERNEL_PID), Err("The kernel process cannot be killed"));
+    }
+
+    /// Raises `signal` from another thread once the caller is waiting
+    fn raise_later(signal: Signal) {
+        thread::spawn("test-keyboard", move || {
+            timer::sleep_ms(20);
+            raise(signal);
+        })
+        .expect("spawn failed");
+    }
+
+    pub fn interrupt_ends_foreground_program() {
+        let child = spawn(b"test-spin", false, || {
+            usermode::run(&SPIN, &[b"spin"], &[]).unwrap_or(-1)
+        })
+        .expect("spawn failed");
+        raise_later(Signal::Interrupt);
+        assert_eq!(wait_foreground(child), Ok(Some(usermode::EXIT_INTERRUPTED)));
+        assert_eq!(foreground(), current().unwrap());
+    }
+
+    pub fn suspend_moves_program_to_background() {
+        let child = spawn(b"test-spin", false, || {
+            usermode::run(&SPIN, &[b"spin"], &[]).unwrap_or(-1)
+        })
+        .expect("spawn failed");
+        raise_later(Signal::Suspend);
+        assert_eq!(wait_foreground(child), Ok(None));
+        assert_eq!(foreground(), current().unwrap());
+
+        assert_eq!(kill(child), Ok(()));
+        assert_eq!(wait(child), Ok(usermode::EXIT_TERMINATED));
+    }
+}
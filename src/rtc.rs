@@ -0,0 +1,353 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::idt;
+use crate::io::{inb, outb};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Where most BIOSes keep the century; ACPI's FADT can name another one
+pub const DEFAULT_CENTURY_REGISTER: u8 = 0x32;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_SET: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const HOUR_PM: u8 = 0x80;
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    pub fn is_valid(&self) -> bool {
+        self.year >= 1970
+            && (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Seconds since 1970-01-01 00:00:00 UTC
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let mut days: u64 = 0;
+        for year in 1970..self.year {
+            days += if is_leap_year(year) { 366 } else { 365 };
+        }
+        for month in 1..self.month {
+            days += days_in_month(self.year, month) as u64;
+        }
+        days += (self.day - 1) as u64;
+
+        days * SECONDS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let mut days = timestamp / SECONDS_PER_DAY;
+        let secs = timestamp % SECONDS_PER_DAY;
+
+        let mut year = 1970;
+        loop {
+            let year_days = if is_leap_year(year) { 366 } else { 365 };
+            if days < year_days {
+                break;
+            }
+            days -= year_days;
+            year += 1;
+        }
+
+        let mut month = 1;
+        loop {
+            let month_days = days_in_month(year, month) as u64;
+            if days < month_days {
+                break;
+            }
+            days -= month_days;
+            month += 1;
+        }
+
+        Self {
+            year,
+            month,
+            day: days as u8 + 1,
+            hour: (secs / 3600) as u8,
+            minute: ((secs % 3600) / 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+
+    /// 0 = Sunday
+    pub fn day_of_week(&self) -> u8 {
+        // 1970-01-01 was a Thursday
+        ((self.to_unix_timestamp() / SECONDS_PER_DAY + 4) % 7) as u8
+    }
+
+    /// Parses `YYYY-MM-DD HH:MM:SS`
+    pub fn parse(s: &[u8]) -> Option<Self> {
+        if s.len() != 19 || s[4] != b'-' || s[7] != b'-' || s[10] != b' ' || s[13] != b':' || s[16] != b':' {
+            return None;
+        }
+
+        let datetime = Self {
+            year: parse_number(&s[0..4])? as u16,
+            month: parse_number(&s[5..7])? as u8,
+            day: parse_number(&s[8..10])? as u8,
+            hour: parse_number(&s[11..13])? as u8,
+            minute: parse_number(&s[14..16])? as u8,
+            second: parse_number(&s[17..19])? as u8,
+        };
+
+        if datetime.is_valid() {
+            Some(datetime)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+pub fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        _ => 0,
+    }
+}
+
+pub fn weekday_name(day: u8) -> &'static str {
+    match day {
+        0 => "Sun",
+        1 => "Mon",
+        2 => "Tue",
+        3 => "Wed",
+        4 => "Thu",
+        5 => "Fri",
+        _ => "Sat",
+    }
+}
+
+fn parse_number(digits: &[u8]) -> Option<u32> {
+    let mut value = 0u32;
+    for &d in digits {
+        if !d.is_ascii_digit() {
+            return None;
+        }
+        value = value * 10 + (d - b'0') as u32;
+    }
+    Some(value)
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+fn binary_to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(DEFAULT_CENTURY_REGISTER);
+
+/// Lets ACPI point the driver at the right century register (0 = none)
+pub fn set_century_register(register: u8) {
+    CENTURY_REGISTER.store(register, Ordering::SeqCst);
+}
+
+unsafe fn read_register(register: u8) -> u8 {
+    outb(CMOS_ADDRESS, register);
+    inb(CMOS_DATA)
+}
+
+unsafe fn write_register(register: u8, value: u8) {
+    outb(CMOS_ADDRESS, register);
+    outb(CMOS_DATA, value);
+}
+
+fn update_in_progress() -> bool {
+    unsafe { read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 }
+}
+
+#[derive(PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw() -> RawTime {
+    while update_in_progress() {}
+
+    let century_register = CENTURY_REGISTER.load(Ordering::SeqCst);
+    unsafe {
+        RawTime {
+            second: read_register(REG_SECONDS),
+            minute: read_register(REG_MINUTES),
+            hour: read_register(REG_HOURS),
+            day: read_register(REG_DAY),
+            month: read_register(REG_MONTH),
+            year: read_register(REG_YEAR),
+            century: if century_register != 0 { read_register(century_register) } else { 0 },
+        }
+    }
+}
+
+/// Reads the CMOS clock, which QEMU and most PCs keep in UTC
+pub fn read() -> DateTime {
+    // An update can still land between the UIP check and the reads, so keep
+    // reading until two passes agree
+    let raw = idt::without_interrupts(|| {
+        let mut last = read_raw();
+        loop {
+            let current = read_raw();
+            if current == last {
+                break current;
+            }
+            last = current;
+        }
+    });
+
+    let status_b = unsafe { read_register(REG_STATUS_B) };
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let decode = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = decode(raw.hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let year = decode(raw.year) as u16;
+    let year = if raw.century != 0 {
+        decode(raw.century) as u16 * 100 + year
+    } else if year < 70 {
+        2000 + year
+    } else {
+        1900 + year
+    };
+
+    DateTime {
+        year,
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour,
+        minute: decode(raw.minute),
+        second: decode(raw.second),
+    }
+}
+
+/// Writes a new time into the CMOS clock, in whatever format it uses
+pub fn write(datetime: &DateTime) -> Result<(), &'static str> {
+    if !datetime.is_valid() || datetime.year > 2999 {
+        return Err("Invalid date or time");
+    }
+
+    idt::without_interrupts(|| unsafe {
+        let status_b = read_register(REG_STATUS_B);
+        let binary = status_b & STATUS_B_BINARY != 0;
+        let encode = |value: u8| if binary { value } else { binary_to_bcd(value) };
+
+        let hour = if status_b & STATUS_B_24_HOUR == 0 {
+            let twelve = match datetime.hour % 12 {
+                0 => 12,
+                h => h,
+            };
+            encode(twelve) | if datetime.hour >= 12 { HOUR_PM } else { 0 }
+        } else {
+            encode(datetime.hour)
+        };
+
+        // Freeze updates while the registers are inconsistent
+        write_register(REG_STATUS_B, status_b | STATUS_B_SET);
+        write_register(REG_SECONDS, encode(datetime.second));
+        write_register(REG_MINUTES, encode(datetime.minute));
+        write_register(REG_HOURS, hour);
+        write_register(REG_DAY, encode(datetime.day));
+        write_register(REG_MONTH, encode(datetime.month));
+        write_register(REG_YEAR, encode((datetime.year % 100) as u8));
+        let century_register = CENTURY_REGISTER.load(Ordering::SeqCst);
+        if century_register != 0 {
+            write_register(century_register, encode((datetime.year / 100) as u8));
+        }
+        write_register(REG_STATUS_B, status_b & !STATUS_B_SET);
+    });
+
+    Ok(())
+}
+
+pub fn unix_time() -> u64 {
+    read().to_unix_timestamp()
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+
+    pub fn unix_timestamp_round_trip() {
+        let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+        assert_eq!(epoch.to_unix_timestamp(), 0);
+        assert_eq!(epoch.day_of_week(), 4);
+
+        let leap_day = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 37, second: 42 };
+        assert_eq!(leap_day.to_unix_timestamp(), 1709213862);
+        assert_eq!(DateTime::from_unix_timestamp(1709213862), leap_day);
+    }
+
+    pub fn parse_and_validate() {
+        let parsed = DateTime::parse(b"2026-10-17 08:05:09").expect("valid date rejected");
+        assert_eq!(parsed, DateTime { year: 2026, month: 10, day: 17, hour: 8, minute: 5, second: 9 });
+
+        assert_eq!(DateTime::parse(b"2023-02-29 00:00:00"), None);
+        assert_eq!(DateTime::parse(b"2026-13-01 00:00:00"), None);
+        assert_eq!(DateTime::parse(b"2026-10-17"), None);
+    }
+
+    pub fn bcd_conversion() {
+        assert_eq!(bcd_to_binary(0x59), 59);
+        assert_eq!(binary_to_bcd(59), 0x59);
+        assert_eq!(bcd_to_binary(binary_to_bcd(7)), 7);
+    }
+}
@@ -0,0 +1,161 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::io::{inb, outb};
+
+pub const COM1: u16 = 0x3F8;
+pub const DEFAULT_BAUD: u32 = 115200;
+
+const UART_CLOCK: u32 = 115200;
+
+// Register offsets from the port base
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_STATUS_DATA_READY: u8 = 0x01;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 0x20;
+
+// Give up on a stuck transmitter rather than hanging the kernel
+const TRANSMIT_SPIN_LIMIT: u32 = 100_000;
+
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> Self {
+        Self { base }
+    }
+
+    /// Programs the UART for 8N1 at `baud` and checks it is really there
+    pub fn init(&self, baud: u32) -> Result<(), &'static str> {
+        if baud == 0 || baud > UART_CLOCK {
+            return Err("Unsupported baud rate");
+        }
+        let divisor = (UART_CLOCK / baud) as u16;
+
+        unsafe {
+            outb(self.base + INTERRUPT_ENABLE, 0x00);
+            outb(self.base + LINE_CONTROL, 0x80);
+            outb(self.base + DATA, (divisor & 0xFF) as u8);
+            outb(self.base + INTERRUPT_ENABLE, ((divisor >> 8) & 0xFF) as u8);
+            outb(self.base + LINE_CONTROL, 0x03);
+            outb(self.base + FIFO_CONTROL, 0xC7);
+            outb(self.base + MODEM_CONTROL, 0x0B);
+
+            // Loopback self-test
+            outb(self.base + MODEM_CONTROL, 0x1E);
+            outb(self.base + DATA, 0xAE);
+            if inb(self.base + DATA) != 0xAE {
+                return Err("Serial port not responding");
+            }
+
+            outb(self.base + MODEM_CONTROL, 0x0F);
+        }
+        Ok(())
+    }
+
+    pub fn write_byte(&self, byte: u8) {
+        unsafe {
+            let mut spins = 0;
+            while inb(self.base + LINE_STATUS) & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+                spins += 1;
+                if spins > TRANSMIT_SPIN_LIMIT {
+                    return;
+                }
+            }
+            outb(self.base + DATA, byte);
+        }
+    }
+
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        for &byte in bytes {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+    }
+
+    /// Returns the next received byte without blocking
+    pub fn read_byte(&self) -> Option<u8> {
+        unsafe {
+            if inb(self.base + LINE_STATUS) & LINE_STATUS_DATA_READY != 0 {
+                Some(inb(self.base + DATA))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static MIRROR_CONSOLE: AtomicBool = AtomicBool::new(false);
+
+/// Brings up COM1 and, if it answers, mirrors the VGA console onto it
+pub fn init() -> Result<(), &'static str> {
+    SerialPort::new(COM1).init(DEFAULT_BAUD)?;
+    INITIALIZED.store(true, Ordering::SeqCst);
+    MIRROR_CONSOLE.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::SeqCst)
+}
+
+pub fn set_mirror(enabled: bool) {
+    MIRROR_CONSOLE.store(enabled && is_initialized(), Ordering::SeqCst);
+}
+
+pub fn is_mirroring() -> bool {
+    MIRROR_CONSOLE.load(Ordering::SeqCst)
+}
+
+pub fn write_bytes(bytes: &[u8]) {
+    if is_initialized() {
+        SerialPort::new(COM1).write_bytes(bytes);
+    }
+}
+
+pub fn write_str(s: &str) {
+    write_bytes(s.as_bytes());
+}
+
+pub fn read_byte() -> Option<u8> {
+    if is_initialized() {
+        SerialPort::new(COM1).read_byte()
+    } else {
+        None
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    if is_initialized() {
+        let _ = SerialPort::new(COM1).write_fmt(args);
+    }
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => (serial_print!("\n"));
+    ($($arg:tt)*) => (serial_print!("{}\n", format_args!($($arg)*)));
+}
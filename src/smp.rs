@@ -0,0 +1,341 @@
+use core::arch::{asm, global_asm};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+use crate::acpi;
+use crate::apic;
+use crate::clock::{self, Duration, Instant};
+use crate::gdt;
+use crate::idt;
+use crate::irq;
+use crate::timer;
+
+pub const MAX_CPUS: usize = acpi::MAX_CPUS;
+
+/// Physical page the APs start executing from; must sit below 1 MB and
+/// clear of the multiboot structures QEMU leaves at 0x9000
+const TRAMPOLINE_BASE: u32 = 0x8000;
+/// Parameters for the AP being started, at the end of the trampoline page
+const TRAMPOLINE_DATA: u32 = TRAMPOLINE_BASE + 0xF00;
+
+const AP_STACK_SIZE: usize = 16384;
+const AP_STARTUP_TIMEOUT_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CpuState {
+    Offline = 0,
+    Starting = 1,
+    Online = 2,
+    Failed = 3,
+}
+
+impl CpuState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => CpuState::Starting,
+            2 => CpuState::Online,
+            3 => CpuState::Failed,
+            _ => CpuState::Offline,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CpuState::Offline => "offline",
+            CpuState::Starting => "starting",
+            CpuState::Online => "online",
+            CpuState::Failed => "failed",
+        }
+    }
+}
+
+/// Per-CPU data, indexed by the order the MADT lists the CPUs in
+pub struct Cpu {
+    apic_id: AtomicU8,
+    is_bsp: AtomicBool,
+    state: AtomicU8,
+    stack_top: AtomicU32,
+    // The CPU's TSS in the GDT; the BSP keeps slot 0 from `gdt::init`
+    tss_slot: AtomicUsize,
+}
+
+impl Cpu {
+    const fn empty() -> Self {
+        Self {
+            apic_id: AtomicU8::new(0),
+            is_bsp: AtomicBool::new(false),
+            state: AtomicU8::new(CpuState::Offline as u8),
+            stack_top: AtomicU32::new(0),
+            tss_slot: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id.load(Ordering::Acquire)
+    }
+
+    pub fn is_bsp(&self) -> bool {
+        self.is_bsp.load(Ordering::Acquire)
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn set_state(&self, state: CpuState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+}
+
+#[repr(C, packed)]
+struct TrampolineData {
+    gdt_limit: u16,
+    gdt_base: u32,
+    cr3: u32,
+    stack_top: u32,
+    entry: u32,
+    cpu_index: u32,
+}
+
+#[repr(C, align(16))]
+struct ApStack(UnsafeCell<[u8; AP_STACK_SIZE]>);
+
+// Only the AP given the stack ever touches it
+unsafe impl Sync for ApStack {}
+
+impl ApStack {
+    fn top(&self) -> u32 {
+        self.0.get() as u32 + AP_STACK_SIZE as u32
+    }
+}
+
+// Until `init` reads the MADT, the boot CPU is the only one known
+static CPUS: [Cpu; MAX_CPUS] = {
+    let mut cpus = [const { Cpu::empty() }; MAX_CPUS];
+    cpus[0].is_bsp = AtomicBool::new(true);
+    cpus[0].state = AtomicU8::new(CpuState::Online as u8);
+    cpus
+};
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+static AP_STACKS: [ApStack; MAX_CPUS - 1] = [const { ApStack(UnsafeCell::new([0; AP_STACK_SIZE])) }; MAX_CPUS - 1];
+
+// Real-mode entry for the APs. It is copied to TRAMPOLINE_BASE, so every
+// absolute address is computed relative to that, and it reads its
+// parameters from TRAMPOLINE_DATA
+global_asm!(
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "xor ax, ax",
+    "mov ds, ax",
+    // o32 prefix so lgdt takes a full 32-bit base
+    ".byte 0x66",
+    "lgdt [{data}]",
+    "mov eax, cr0",
+    "or eax, 1",
+    "mov cr0, eax",
+    // ljmp {code}:ap_protected, with a 32-bit offset
+    ".byte 0x66, 0xEA",
+    ".long {base} + (ap_protected - ap_trampoline_start)",
+    ".word {code}",
+    ".code32",
+    "ap_protected:",
+    "mov ax, {kdata}",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov ss, ax",
+    "mov eax, [{data} + 6]",
+    "test eax, eax",
+    "jz 2f",
+    "mov cr3, eax",
+    "mov eax, cr0",
+    "or eax, 0x80010000",
+    "mov cr0, eax",
+    "2:",
+    "mov esp, [{data} + 10]",
+    "xor ebp, ebp",
+    "push dword ptr [{data} + 18]",
+    "call dword ptr [{data} + 14]",
+    "3:",
+    "cli",
+    "hlt",
+    "jmp 3b",
+    "ap_trampoline_end:",
+    base = const TRAMPOLINE_BASE,
+    data = const TRAMPOLINE_DATA,
+    code = const gdt::KERNEL_CODE_SELECTOR,
+    kdata = const gdt::KERNEL_DATA_SELECTOR,
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// First Rust code an AP runs, on its own stack with paging already on and
+/// the BSP's GDT loaded by the trampoline
+extern "C" fn ap_main(cpu_index: u32) -> ! {
+    let cpu = &CPUS[cpu_index as usize];
+    gdt::load_tss(cpu.tss_slot.load(Ordering::Acquire));
+    idt::load();
+    apic::enable_local();
+
+    cpu.set_state(CpuState::Online);
+
+    // Nothing is scheduled on the APs yet, though with a TSS of their own
+    // they could take interrupts from ring 3
+    loop {
+        unsafe {
+            asm!("sti", "hlt", options(nostack));
+        }
+    }
+}
+
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+pub fn cpus() -> &'static [Cpu] {
+    &CPUS[..cpu_count()]
+}
+
+pub fn online_count() -> usize {
+    cpus().iter().filter(|c| c.state() == CpuState::Online).count()
+}
+
+/// Index of the calling CPU in `cpus()`
+pub fn current_cpu() -> usize {
+    if irq::controller() != irq::Controller::Apic {
+        return 0;
+    }
+    let id = apic::local_id();
+    cpus().iter().position(|c| c.apic_id() == id).unwrap_or(0)
+}
+
+/// Starts every enabled CPU in the MADT with INIT-SIPI-SIPI, one at a time
+pub fn init() -> Result<(), &'static str> {
+    if irq::controller() != irq::Controller::Apic {
+        return Err("SMP needs the local APIC");
+    }
+    let madt = acpi::madt().ok_or("No MADT")?;
+    let bsp_id = apic::local_id();
+
+    let mut count = 0;
+    for entry in madt.cpus().filter(|c| c.enabled).take(MAX_CPUS) {
+        let cpu = &CPUS[count];
+        let is_bsp = entry.apic_id == bsp_id;
+        cpu.apic_id.store(entry.apic_id, Ordering::Release);
+        cpu.is_bsp.store(is_bsp, Ordering::Release);
+        cpu.set_state(if is_bsp { CpuState::Online } else { CpuState::Offline });
+        count += 1;
+    }
+    if count == 0 {
+        return Err("MADT lists no usable CPUs");
+    }
+    CPU_COUNT.store(count, Ordering::Release);
+
+    unsafe {
+        install_trampoline();
+    }
+
+    // Each AP gets the next stack and, one past it, the next TSS
+    let mut slot = 0;
+    for (index, cpu) in CPUS[..count].iter().enumerate() {
+        if cpu.is_bsp() {
+            continue;
+        }
+        cpu.stack_top.store(AP_STACKS[slot].top(), Ordering::Release);
+        cpu.tss_slot.store(slot + 1, Ordering::Release);
+        slot += 1;
+
+        match unsafe { start_ap(index) } {
+            Ok(()) => info!("CPU {} (APIC ID {}) online", index, cpu.apic_id()),
+            Err(e) => {
+                cpu.set_state(CpuState::Failed);
+                warn!("CPU {} (APIC ID {}): {}", index, cpu.apic_id(), e);
+            }
+        }
+    }
+    Ok(())
+}
+
+unsafe fn install_trampoline() {
+    let start = &ap_trampoline_start as *const u8;
+    let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+    core::ptr::copy_nonoverlapping(start, TRAMPOLINE_BASE as *mut u8, len);
+}
+
+unsafe fn start_ap(index: usize) -> Result<(), &'static str> {
+    let cpu = &CPUS[index];
+
+    let data = &mut *(TRAMPOLINE_DATA as *mut TrampolineData);
+    asm!("sgdt [{}]", in(reg) TRAMPOLINE_DATA, options(nostack));
+    let cr3: u32;
+    asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack));
+    data.cr3 = if crate::paging::is_enabled() { cr3 } else { 0 };
+    data.stack_top = cpu.stack_top.load(Ordering::Acquire);
+    data.entry = ap_main as u32;
+    data.cpu_index = index as u32;
+
+    cpu.set_state(CpuState::Starting);
+    let page = (TRAMPOLINE_BASE >> 12) as u8;
+
+    apic::send_init(cpu.apic_id());
+    timer::sleep_ms(10);
+    // The second SIPI covers CPUs that missed the first
+    for _ in 0..2 {
+        apic::send_startup(cpu.apic_id(), page);
+        clock::busy_wait(Duration::from_micros(200));
+        if cpu.state() == CpuState::Online {
+            return Ok(());
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(AP_STARTUP_TIMEOUT_MS);
+    while Instant::now() < deadline {
+        if cpu.state() == CpuState::Online {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    // Park it in wait-for-SIPI again, so a late start cannot pick up the
+    // next CPU's stack and index from the trampoline data
+    apic::send_init(cpu.apic_id());
+    Err("did not respond to SIPI")
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+
+    pub fn boot_cpu_is_online() {
+        let cpus = cpus();
+        assert!(!cpus.is_empty());
+        assert!(online_count() >= 1);
+        assert_eq!(cpus.iter().filter(|c| c.is_bsp()).count(), 1);
+
+        let current = &cpus[current_cpu()];
+        assert!(current.is_bsp());
+        assert_eq!(current.state(), CpuState::Online);
+    }
+
+    pub fn cpus_get_distinct_tss_slots() {
+        let cpus = cpus();
+        for (i, cpu) in cpus.iter().enumerate() {
+            let slot = cpu.tss_slot.load(Ordering::Acquire);
+            assert!(cpus[i + 1..].iter().all(|other| other.tss_slot.load(Ordering::Acquire) != slot));
+        }
+    }
+
+    pub fn state_round_trips() {
+        for state in [CpuState::Offline, CpuState::Starting, CpuState::Online, CpuState::Failed] {
+            assert_eq!(CpuState::from_u8(state as u8), state);
+        }
+    }
+}
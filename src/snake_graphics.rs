@@ -4,6 +4,12 @@ use crate::graphics::graphics;
 use crate::writer::Writer;
 use crate::vga_colors::Color;
 use crate::idt;
+use crate::process;
+use crate::thread;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::audio::{self, Notes};
+use crate::timer;
 
 const VGA_BUFFER: usize = 0xb8000;
 const GAME_WIDTH: usize = 40;
@@ -11,6 +17,17 @@ const GAME_HEIGHT: usize = 20;
 const GAME_OFFSET_X: usize = 20;
 const GAME_OFFSET_Y: usize = 16;
 const MAX_SNAKE_LEN: usize = 100;
+const STEP_MS: u64 = 100;
+
+const START_JINGLE: [(u16, u32); 4] = [(Notes::C4, 80), (Notes::E4, 80), (Notes::G4, 80), (Notes::C5, 160)];
+const GAME_OVER_TUNE: [(u16, u32); 4] = [(Notes::B4, 150), (Notes::G4, 150), (Notes::F4, 150), (Notes::D4, 300)];
+
+// Set by a periodic timer each time the snake is due to move
+static STEP_DUE: AtomicBool = AtomicBool::new(false);
+
+fn step_due() {
+    STEP_DUE.store(true, Ordering::Release);
+}
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum Direction {
@@ -262,6 +279,7 @@ pub fn new() -> Self {
         let ate_food = new_head.x == self.food.x && new_head.y == self.food.y;
 
         if ate_food {
+            audio::beep(Notes::A4, 50);
             if self.snake_len < MAX_SNAKE_LEN {
                 self.snake_len += 1;
             }
@@ -302,11 +320,28 @@ pub fn new() -> Self {
             }
         }
         
-        let mut last_tick: u32 = idt::get_ticks();
-        let game_speed: u32 = 10;
-        
+        audio::play_melody(&START_JINGLE);
+
+        STEP_DUE.store(false, Ordering::Release);
+        let stepper = match timer::add_periodic(STEP_MS, step_due) {
+            Ok(id) => id,
+            Err(e) => {
+                warn!("snake: {}", e);
+                return;
+            }
+        };
+        self.play();
+        let _ = timer::cancel(stepper);
+    }
+
+    /// Reads keys and moves the snake whenever the step timer has fired,
+    /// until the player quits
+    fn play(&mut self) {
         loop {
-            idt::wait_for_interrupt();
+            thread::idle();
+            if process::interrupted() {
+                return;
+            }
             
             while let Some(scancode) = idt::get_scancode() {
                 if scancode & 0x80 != 0 {
@@ -349,15 +384,14 @@ pub fn new() -> Self {
                 continue;
             }
             
-            let current_tick = idt::get_ticks();
-            if current_tick.wrapping_sub(last_tick) >= game_speed {
-                last_tick = current_tick;
+            if STEP_DUE.swap(false, Ordering::AcqRel) {
                 
                 self.clear_game_area();
                 self.draw_box();
                 
                 if !self.update() {
                     self.draw();
+                    audio::play_melody(&GAME_OVER_TUNE);
                     unsafe {
                         let vga = VGA_BUFFER as *mut u8;
                         let game_over_str = b"GAME OVER! Press Q to exit";
@@ -369,7 +403,10 @@ pub fn new() -> Self {
                     }
                     
                     loop {
-                        idt::wait_for_interrupt();
+                        thread::idle();
+                        if process::interrupted() {
+                            return;
+                        }
                         if let Some(scancode) = idt::get_scancode() {
                             if scancode == 0x10 { // Q
                                 return;
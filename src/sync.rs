@@ -0,0 +1,242 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::idt;
+use crate::thread;
+
+/// Busy-waiting mutual exclusion. The holder cannot be preempted, so it
+/// must not block or yield. Must not be taken from an interrupt handler;
+/// use `IrqSpinLock` for data an IRQ also touches
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T: 'a> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        thread::preempt_disable();
+        self.acquire();
+        SpinLockGuard { lock: self }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        thread::preempt_disable();
+        if self.try_acquire() {
+            Some(SpinLockGuard { lock: self })
+        } else {
+            thread::preempt_enable();
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn acquire(&self) {
+        while !self.try_acquire() {
+            // Spin on a plain load so waiters do not hammer the bus with writes
+            while self.is_locked() {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    fn release(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+        thread::preempt_enable();
+    }
+}
+
+/// A spinlock that keeps interrupts off on the holding CPU, so an IRQ
+/// handler taking the same lock can never spin on its own CPU's holder
+pub struct IrqSpinLock<T> {
+    inner: SpinLock<T>,
+}
+
+pub struct IrqSpinLockGuard<'a, T: 'a> {
+    lock: &'a SpinLock<T>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: SpinLock::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> IrqSpinLockGuard<T> {
+        let interrupts_were_enabled = idt::disable_interrupts();
+        self.inner.acquire();
+        IrqSpinLockGuard {
+            lock: &self.inner,
+            interrupts_were_enabled,
+        }
+    }
+
+    /// The data without taking the lock, for crash handlers that must not
+    /// wait on a holder that will never run again
+    pub fn force_get(&self) -> *mut T {
+        self.inner.data.get()
+    }
+}
+
+impl<'a, T> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+        idt::restore_interrupts(self.interrupts_were_enabled);
+    }
+}
+
+const ONCE_EMPTY: u8 = 0;
+const ONCE_RUNNING: u8 = 1;
+const ONCE_READY: u8 = 2;
+
+/// A value written exactly once, typically during boot, and shared
+/// read-only afterwards
+pub struct Once<T> {
+    state: AtomicU8,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(ONCE_EMPTY),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Runs `init` if nothing has been stored yet; every caller gets the
+    /// value from whichever call won
+    pub fn call_once(&self, init: impl FnOnce() -> T) -> &T {
+        if self
+            .state
+            .compare_exchange(ONCE_EMPTY, ONCE_RUNNING, Ordering::Acquire, Ordering::Acquire)
+            .is_ok()
+        {
+            unsafe {
+                (*self.data.get()).write(init());
+            }
+            self.state.store(ONCE_READY, Ordering::Release);
+        }
+        while self.state.load(Ordering::Acquire) != ONCE_READY {
+            core::hint::spin_loop();
+        }
+        unsafe { (*self.data.get()).assume_init_ref() }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == ONCE_READY {
+            Some(unsafe { (*self.data.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == ONCE_READY
+    }
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+
+    pub fn spinlock_excludes_second_locker() {
+        let lock = SpinLock::new(5u32);
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(lock.is_locked());
+            assert!(lock.try_lock().is_none());
+        }
+        assert!(!lock.is_locked());
+        assert_eq!(*lock.try_lock().expect("lock still held"), 6);
+    }
+
+    pub fn irq_lock_restores_interrupt_flag() {
+        let lock = IrqSpinLock::new(0u32);
+        assert!(idt::interrupts_enabled());
+        {
+            let _outer = lock.lock();
+            assert!(!idt::interrupts_enabled());
+        }
+        assert!(idt::interrupts_enabled());
+
+        // Taken with interrupts already off, the guard must leave them off
+        idt::without_interrupts(|| {
+            drop(lock.lock());
+            assert!(!idt::interrupts_enabled());
+        });
+    }
+
+    pub fn once_runs_initializer_once() {
+        let once = Once::new();
+        assert!(once.get().is_none());
+        assert_eq!(*once.call_once(|| 1u32), 1);
+        assert_eq!(*once.call_once(|| 2u32), 1);
+        assert_eq!(once.get(), Some(&1));
+        assert!(once.is_completed());
+    }
+}
@@ -0,0 +1,372 @@
+use core::arch::naked_asm;
+use core::slice;
+
+use crate::filesystem::get_filesystem;
+use crate::idt;
+use crate::keyboard::Keyboard;
+use crate::process;
+use crate::timer;
+use crate::usermode;
+use crate::writer;
+use crate::vga_colors::Color;
+
+pub const SYSCALL_VECTOR: usize = 0x80;
+
+// The system call table. The number goes in eax and up to three arguments
+// in ebx, ecx and edx; the result comes back in eax. Numbers are part of
+// the user ABI, so new calls get new numbers and old ones are never reused
+pub const SYS_EXIT: u32 = 0;
+pub const SYS_WRITE: u32 = 1;
+pub const SYS_READ: u32 = 2;
+pub const SYS_OPEN: u32 = 3;
+pub const SYS_CLOSE: u32 = 4;
+pub const SYS_SLEEP: u32 = 5;
+pub const SYS_GETTICKS: u32 = 6;
+pub const SYS_BRK: u32 = 7;
+pub const SYS_GETPID: u32 = 8;
+
+// Failures return the negated error number
+pub const EPERM: i32 = 1;
+pub const ENOENT: i32 = 2;
+pub const EBADF: i32 = 9;
+pub const EFAULT: i32 = 14;
+pub const EINVAL: i32 = 22;
+pub const EMFILE: i32 = 24;
+pub const ENOSPC: i32 = 28;
+pub const ENAMETOOLONG: i32 = 36;
+pub const ENOSYS: i32 = 38;
+
+// `open` flags
+pub const OPEN_READ: u32 = 0;
+/// Creates the file, or empties it if it exists; writes append
+pub const OPEN_WRITE: u32 = 1;
+
+pub const STDIN: u32 = 0;
+pub const STDOUT: u32 = 1;
+pub const STDERR: u32 = 2;
+
+pub const MAX_OPEN_FILES: usize = 8;
+// Descriptors below this are the console
+const FIRST_FILE_FD: u32 = 3;
+const MAX_NAME_LEN: usize = 32;
+// `sleep` checks this often whether the process was killed or signalled
+const SLEEP_STEP_MS: u64 = 10;
+
+/// Registers as `pusha` left them, then what the CPU pushed when `int
+/// 0x80` switched from ring 3 to the kernel stack
+#[repr(C)]
+pub struct SyscallFrame {
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    pub esp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+    pub user_esp: u32,
+    pub user_ss: u32,
+}
+
+#[derive(Clone, Copy)]
+struct OpenFile {
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    offset: usize,
+    writable: bool,
+}
+
+impl OpenFile {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+/// A process's open files, indexed by descriptor minus `FIRST_FILE_FD`
+pub struct FileTable {
+    files: [Option<OpenFile>; MAX_OPEN_FILES],
+}
+
+impl FileTable {
+    pub const fn new() -> Self {
+        Self {
+            files: [const { None }; MAX_OPEN_FILES],
+        }
+    }
+
+    fn insert(&mut self, file: OpenFile) -> Result<u32, i32> {
+        let slot = self.files.iter().position(|f| f.is_none()).ok_or(EMFILE)?;
+        self.files[slot] = Some(file);
+        Ok(slot as u32 + FIRST_FILE_FD)
+    }
+
+    fn get(&mut self, fd: u32) -> Result<&mut OpenFile, i32> {
+        let slot = fd.checked_sub(FIRST_FILE_FD).ok_or(EBADF)? as usize;
+        self.files.get_mut(slot).and_then(|f| f.as_mut()).ok_or(EBADF)
+    }
+
+    fn remove(&mut self, fd: u32) -> Result<(), i32> {
+        self.get(fd)?;
+        self.files[(fd - FIRST_FILE_FD) as usize] = None;
+        Ok(())
+    }
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn syscall_entry() {
+    naked_asm!(
+        "pusha",
+        "push esp",
+        "call syscall_handler_inner",
+        "add esp, 4",
+        "popa",
+        "iretd",
+    );
+}
+
+/// Installs the `int 0x80` gate, callable from ring 3
+pub fn init() {
+    idt::set_user_handler(SYSCALL_VECTOR, syscall_entry as u32);
+}
+
+#[no_mangle]
+pub extern "C" fn syscall_handler_inner(frame: &mut SyscallFrame) {
+    // The gate cleared IF, but blocking calls need the timer and keyboard
+    idt::restore_interrupts(true);
+
+    let from_program = frame.cs & 3 == 3 && usermode::is_running();
+    if from_program {
+        usermode::handle_signals();
+    }
+    let result = if from_program {
+        dispatch(frame.eax, frame.ebx, frame.ecx, frame.edx)
+    } else {
+        Err(EPERM)
+    };
+    frame.eax = match result {
+        Ok(value) => value,
+        Err(errno) => (-errno) as u32,
+    };
+    if from_program {
+        usermode::handle_signals();
+    }
+}
+
+fn dispatch(number: u32, arg1: u32, arg2: u32, arg3: u32) -> Result<u32, i32> {
+    match number {
+        SYS_EXIT => usermode::exit(arg1 as i32),
+        SYS_WRITE => sys_write(arg1, arg2, arg3),
+        SYS_READ => sys_read(arg1, arg2, arg3),
+        SYS_OPEN => sys_open(arg1, arg2, arg3),
+        SYS_CLOSE => process::with_files(|files| files.remove(arg1)).unwrap_or(Err(EBADF)).map(|_| 0),
+        SYS_SLEEP => sys_sleep(arg1),
+        SYS_GETTICKS => Ok(timer::ticks() as u32),
+        SYS_BRK => Ok(usermode::set_break(arg1)),
+        SYS_GETPID => process::current().map(|pid| pid.as_u32()).ok_or(EPERM),
+        _ => Err(ENOSYS),
+    }
+}
+
+/// Borrows user memory after checking the program owns all of it
+fn user_slice<'a>(ptr: u32, len: u32) -> Result<&'a [u8], i32> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if !usermode::is_user_range(ptr, len) {
+        return Err(EFAULT);
+    }
+    Ok(unsafe { slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+fn user_slice_mut<'a>(ptr: u32, len: u32) -> Result<&'a mut [u8], i32> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    if !usermode::is_user_range(ptr, len) {
+        return Err(EFAULT);
+    }
+    Ok(unsafe { slice::from_raw_parts_mut(ptr as *mut u8, len as usize) })
+}
+
+/// A copy of what `fd` refers to, so the file system is never locked
+/// while the process table is
+fn open_file(fd: u32) -> Result<OpenFile, i32> {
+    process::with_files(|files| files.get(fd).map(|file| *file)).unwrap_or(Err(EBADF))
+}
+
+fn sys_write(fd: u32, buf: u32, len: u32) -> Result<u32, i32> {
+    let bytes = user_slice(buf, len)?;
+    match fd {
+        STDIN => Err(EBADF),
+        STDOUT => {
+            writer::console().write_bytes(bytes);
+            Ok(len)
+        }
+        STDERR => {
+            let mut console = writer::console();
+            console.set_color(Color::LightRed, Color::Black);
+            console.write_bytes(bytes);
+            console.set_color(Color::White, Color::Black);
+            Ok(len)
+        }
+        _ => {
+            let file = open_file(fd)?;
+            if !file.writable {
+                return Err(EBADF);
+            }
+            get_filesystem().append_file(file.name(), bytes).map_err(|_| ENOSPC)?;
+            Ok(len)
+        }
+    }
+}
+
+fn sys_read(fd: u32, buf: u32, len: u32) -> Result<u32, i32> {
+    let buffer = user_slice_mut(buf, len)?;
+    match fd {
+        // The keyboard belongs to the foreground
+        STDIN if process::is_background() => Ok(0),
+        STDIN => Ok(read_line(buffer) as u32),
+        STDOUT | STDERR => Err(EBADF),
+        _ => {
+            let file = open_file(fd)?;
+            let count = {
+                let fs = get_filesystem();
+                let content = fs.read_file(file.name()).ok_or(ENOENT)?;
+                let start = file.offset.min(content.len());
+                let count = (content.len() - start).min(buffer.len());
+                buffer[..count].copy_from_slice(&content[start..start + count]);
+                count
+            };
+            let offset = file.offset + count;
+            process::with_files(|files| files.get(fd).map(|file| file.offset = offset));
+            Ok(count as u32)
+        }
+    }
+}
+
+/// Sleeps in short steps so a killed or signalled process does not sleep
+/// on; the signal is handled on the way out
+fn sys_sleep(ms: u32) -> Result<u32, i32> {
+    let end = timer::uptime_ms() + ms as u64;
+    while !process::kill_requested() && !process::signal_pending() {
+        let now = timer::uptime_ms();
+        if now >= end {
+            break;
+        }
+        timer::sleep_ms((end - now).min(SLEEP_STEP_MS));
+    }
+    Ok(0)
+}
+
+/// `open(path, path_len, flags)`; paths are relative to the process's
+/// current directory
+fn sys_open(path: u32, path_len: u32, flags: u32) -> Result<u32, i32> {
+    let name = user_slice(path, path_len)?;
+    if name.is_empty() {
+        return Err(ENOENT);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(ENAMETOOLONG);
+    }
+    let writable = match flags {
+        OPEN_READ => false,
+        OPEN_WRITE => true,
+        _ => return Err(EINVAL),
+    };
+
+    {
+        let mut fs = get_filesystem();
+        if writable {
+            fs.write_file(name, b"").map_err(|_| ENOSPC)?;
+        } else if !fs.file_exists(name) {
+            return Err(ENOENT);
+        }
+    }
+
+    let mut file = OpenFile {
+        name: [0; MAX_NAME_LEN],
+        name_len: name.len(),
+        offset: 0,
+        writable,
+    };
+    file.name[..name.len()].copy_from_slice(name);
+    process::with_files(|files| files.insert(file)).unwrap_or(Err(EBADF))
+}
+
+/// Reads keys into `buffer` until Enter, echoing them as a terminal in
+/// line mode would. The newline is kept if it fits. A kill or signal
+/// ends the line early with what was typed so far
+fn read_line(buffer: &mut [u8]) -> usize {
+    let mut len = 0;
+    let mut shift = false;
+    while len < buffer.len() {
+        let scancode = idt::read_scancode_unless(|| process::kill_requested() || process::signal_pending());
+        match scancode {
+            None => break,
+            Some(scancode) => match scancode {
+                0x2A | 0x36 => shift = true,
+                0xAA | 0xB6 => shift = false,
+                0x1C => {
+                    writer::console().write_byte(b'\n');
+                    buffer[len] = b'\n';
+                    return len + 1;
+                }
+                0x0E => {
+                    if len > 0 {
+                        len -= 1;
+                        let mut console = writer::console();
+                        let (col, row) = (console.get_col(), console.get_row());
+                        if col > 0 {
+                            console.set_position(col - 1, row);
+                            console.write_byte(b' ');
+                            console.set_position(col - 1, row);
+                        }
+                    }
+                }
+                scancode => {
+                    if let Some(c) = Keyboard::scancode_to_char(scancode, shift) {
+                        buffer[len] = c as u8;
+                        len += 1;
+                        writer::console().write_byte(c as u8);
+                    }
+                }
+            },
+        }
+    }
+    len
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+
+    pub fn unknown_and_kernel_calls_are_refused() {
+        assert_eq!(dispatch(0xFFFF, 0, 0, 0), Err(ENOSYS));
+        // Without a program nothing counts as user memory
+        assert_eq!(sys_write(STDOUT, 0x0010_0000, 4), Err(EFAULT));
+        assert!(dispatch(SYS_GETTICKS, 0, 0, 0).is_ok());
+    }
+
+    pub fn file_table_hands_out_lowest_descriptor() {
+        let mut table = FileTable::new();
+        let file = || OpenFile {
+            name: [0; MAX_NAME_LEN],
+            name_len: 0,
+            offset: 0,
+            writable: false,
+        };
+        assert_eq!(table.insert(file()), Ok(FIRST_FILE_FD));
+        assert_eq!(table.insert(file()), Ok(FIRST_FILE_FD + 1));
+        assert_eq!(table.remove(FIRST_FILE_FD), Ok(()));
+        assert_eq!(table.remove(FIRST_FILE_FD), Err(EBADF));
+        assert_eq!(table.remove(STDOUT), Err(EBADF));
+        assert_eq!(table.insert(file()), Ok(FIRST_FILE_FD));
+        for _ in 2..MAX_OPEN_FILES {
+            table.insert(file()).expect("table full early");
+        }
+        assert_eq!(table.insert(file()), Err(EMFILE));
+    }
+}
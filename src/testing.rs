@@ -0,0 +1,135 @@
+use core::arch::asm;
+use core::panic::PanicInfo;
+
+use crate::io::outl;
+use crate::serial;
+use crate::{acpi, clock, editor, elf, filesystem, frame_allocator, heap, irq, keyboard, log, process, rtc, smp, sync, syscall, thread, timer, usermode, writer};
+
+/// QEMU's `isa-debug-exit` device, see the `test` target in the Makefile
+const DEBUG_EXIT_PORT: u16 = 0xF4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub struct TestCase {
+    pub name: &'static str,
+    pub run: fn(),
+}
+
+macro_rules! test_case {
+    ($path:path) => {
+        TestCase {
+            name: stringify!($path),
+            run: $path,
+        }
+    };
+}
+
+const TESTS: &[TestCase] = &[
+    test_case!(filesystem::tests::create_and_read_file),
+    test_case!(filesystem::tests::writes_record_modification_time),
+    test_case!(filesystem::tests::duplicate_file_is_rejected),
+    test_case!(filesystem::tests::write_append_and_delete),
+    test_case!(filesystem::tests::directories_scope_files),
+    test_case!(filesystem::tests::remove_non_empty_directory_fails),
+    test_case!(editor::tests::load_splits_lines),
+    test_case!(editor::tests::insert_newline_and_backspace),
+    test_case!(editor::tests::save_round_trips_content),
+    test_case!(writer::tests::write_byte_updates_vga_and_cursor),
+    test_case!(writer::tests::newline_and_scroll),
+    test_case!(writer::tests::console_handles_share_one_cursor),
+    test_case!(keyboard::tests::letters_respect_shift),
+    test_case!(keyboard::tests::unknown_scancodes_are_ignored),
+    test_case!(keyboard::tests::ctrl_c_and_ctrl_z_raise_signals),
+    test_case!(frame_allocator::tests::allocate_and_free_frame),
+    test_case!(frame_allocator::tests::cannot_free_reserved_frames),
+    test_case!(frame_allocator::tests::boot_data_is_reserved),
+    test_case!(heap::tests::alloc_collections),
+    test_case!(log::tests::ring_buffer_keeps_newest_entries),
+    test_case!(log::tests::max_level_filters_records),
+    test_case!(rtc::tests::unix_timestamp_round_trip),
+    test_case!(rtc::tests::parse_and_validate),
+    test_case!(rtc::tests::bcd_conversion),
+    test_case!(irq::tests::register_updates_mask),
+    test_case!(irq::tests::cascade_cannot_be_claimed),
+    test_case!(timer::tests::conversions_round_up),
+    test_case!(timer::tests::sleep_waits_at_least_requested_time),
+    test_case!(timer::tests::oneshot_and_periodic_callbacks),
+    test_case!(clock::tests::cycles_convert_without_overflow),
+    test_case!(clock::tests::instant_is_monotonic),
+    test_case!(acpi::tests::parse_s5_package),
+    test_case!(acpi::tests::parse_madt_entries),
+    test_case!(acpi::tests::short_fadt_reads_as_zero),
+    test_case!(sync::tests::spinlock_excludes_second_locker),
+    test_case!(sync::tests::irq_lock_restores_interrupt_flag),
+    test_case!(sync::tests::once_runs_initializer_once),
+    test_case!(thread::tests::spawned_threads_interleave_and_join),
+    test_case!(thread::tests::cannot_join_self),
+    test_case!(thread::tests::busy_thread_is_preempted),
+    test_case!(thread::tests::preempt_count_is_per_thread),
+    test_case!(thread::tests::busy_thread_does_not_starve_lower_priority),
+    test_case!(thread::tests::lowering_own_priority_yields),
+    test_case!(thread::tests::wait_queue_wakes_blocked_thread),
+    test_case!(thread::tests::sleeping_thread_wakes_on_time),
+    test_case!(smp::tests::boot_cpu_is_online),
+    test_case!(smp::tests::cpus_get_distinct_tss_slots),
+    test_case!(smp::tests::state_round_trips),
+    test_case!(syscall::tests::unknown_and_kernel_calls_are_refused),
+    test_case!(syscall::tests::file_table_hands_out_lowest_descriptor),
+    test_case!(usermode::tests::hello_runs_and_exits_cleanly),
+    test_case!(usermode::tests::fault_kills_only_the_program),
+    test_case!(usermode::tests::programs_get_separate_address_spaces),
+    test_case!(usermode::tests::initial_stack_holds_argv_and_envp),
+    test_case!(usermode::tests::exec_loads_elf_with_arguments),
+    test_case!(usermode::tests::boot_module_runs),
+    test_case!(elf::tests::parses_minimal_executable),
+    test_case!(elf::tests::rejects_malformed_files),
+    test_case!(process::tests::wait_collects_exit_code),
+    test_case!(process::tests::children_inherit_current_directory),
+    test_case!(process::tests::kill_stops_running_program),
+    test_case!(process::tests::interrupt_ends_foreground_program),
+    test_case!(process::tests::suspend_moves_program_to_background),
+];
+
+/// Runs every registered test and exits QEMU with the overall result
+pub fn run() -> ! {
+    // Tests draw on the VGA buffer; keep that noise off the serial log
+    serial::set_mirror(false);
+
+    serial_println!("Running {} kernel tests", TESTS.len());
+    for test in TESTS {
+        serial_print!("{}... ", test.name);
+        (test.run)();
+        serial_println!("[ok]");
+    }
+    serial_println!("All {} tests passed", TESTS.len());
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// A failed assertion panics; report it and stop the whole run
+pub fn test_panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]");
+    serial_println!("{}", info.message());
+    if let Some(location) = info.location() {
+        serial_println!("at {}:{}", location.file(), location.line());
+    }
+    exit_qemu(QemuExitCode::Failed);
+}
+
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    unsafe {
+        outl(DEBUG_EXIT_PORT, code as u32);
+    }
+
+    // Only reached when the debug-exit device is missing
+    loop {
+        unsafe {
+            asm!("cli", "hlt", options(nostack));
+        }
+    }
+}
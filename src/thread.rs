@@ -0,0 +1,833 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use core::arch::{asm, naked_asm};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::gdt;
+use crate::heap;
+use crate::idt;
+use crate::paging;
+use crate::sync::IrqSpinLock;
+use crate::timer;
+
+pub const MAX_THREADS: usize = 16;
+pub const STACK_SIZE: usize = 16 * 1024;
+
+/// How long a thread runs before the timer hands the CPU to the next
+/// ready thread of the same priority
+const TIME_SLICE_MS: u64 = 20;
+/// How long a ready thread waits before it competes one priority level
+/// higher, so busy threads cannot starve lower ones
+const AGING_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadId(u32);
+
+impl ThreadId {
+    pub fn from_u32(id: u32) -> Self {
+        ThreadId(id)
+    }
+
+    pub fn as_u32(&self) -> u32 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    // Until the given tick
+    Sleeping(u64),
+    // On a `WaitQueue`
+    Blocked,
+    Finished,
+}
+
+impl ThreadState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThreadState::Ready => "ready",
+            ThreadState::Running => "running",
+            ThreadState::Sleeping(_) => "sleeping",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Finished => "finished",
+        }
+    }
+}
+
+/// A ready thread runs ahead of lower-priority ones, though those creep
+/// up a level for every `AGING_MS` they wait. `Idle` is reserved for the
+/// thread that halts the CPU and never ages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Idle,
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Priority::Idle => "idle",
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+
+    /// The priority `name` gives, leaving out the reserved `Idle`
+    pub fn from_name(name: &[u8]) -> Option<Priority> {
+        match name {
+            b"low" => Some(Priority::Low),
+            b"normal" => Some(Priority::Normal),
+            b"high" => Some(Priority::High),
+            _ => None,
+        }
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: ThreadState,
+    priority: Priority,
+    // Saved stack pointer while switched out
+    esp: u32,
+    // None for the boot thread, which runs on the stack boot.asm set up
+    stack: Option<Box<[u8]>>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    // Where the CPU switches to on an interrupt from ring 3, or 0 if the
+    // thread never leaves ring 0
+    kernel_stack: u32,
+    // Loaded into CR3 whenever the thread runs; 0 for the kernel's own
+    page_directory: u32,
+    // `PREEMPT_DISABLED` while switched out
+    preempt_count: u32,
+    // Ticks spent ready since the thread last ran
+    waiting: u64,
+    switches: u64,
+    ticks: u64,
+}
+
+struct Scheduler {
+    threads: [Option<Thread>; MAX_THREADS],
+    current: usize,
+    next_id: u32,
+    // Ticks left in the current thread's time slice
+    slice_left: u64,
+}
+
+impl Scheduler {
+    fn is_ready(&self, index: usize) -> bool {
+        matches!(&self.threads[index], Some(t) if t.state == ThreadState::Ready)
+    }
+
+    fn priority(&self, index: usize) -> Priority {
+        self.threads[index].as_ref().map_or(Priority::Idle, |t| t.priority)
+    }
+
+    /// The priority a thread competes at: its own, raised a level for
+    /// every `AGING_MS` it has waited, up to `High`
+    fn effective_priority(&self, index: usize) -> Priority {
+        match &self.threads[index] {
+            Some(t) if t.priority != Priority::Idle => {
+                let mut priority = t.priority;
+                for _ in 0..t.waiting / aging_ticks() {
+                    priority = match priority {
+                        Priority::Low => Priority::Normal,
+                        _ => Priority::High,
+                    };
+                }
+                priority
+            }
+            _ => self.priority(index),
+        }
+    }
+
+    /// Ready thread other than the current one with the highest effective
+    /// priority, taking the first after it in slot order among equals
+    fn next_ready(&self) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for offset in 1..MAX_THREADS {
+            let i = (self.current + offset) % MAX_THREADS;
+            if self.is_ready(i) && best.map_or(true, |b| self.effective_priority(i) > self.effective_priority(b)) {
+                best = Some(i);
+            }
+        }
+        best
+    }
+
+    fn find(&self, id: ThreadId) -> Option<usize> {
+        self.threads.iter().position(|t| matches!(t, Some(t) if t.id == id))
+    }
+
+    /// Makes a sleeping or blocked thread runnable, asking for a switch if
+    /// it outranks the running one
+    fn wake(&mut self, index: usize) {
+        let current_priority = self.priority(self.current);
+        if let Some(thread) = &mut self.threads[index] {
+            match thread.state {
+                ThreadState::Sleeping(_) | ThreadState::Blocked => {
+                    thread.state = ThreadState::Ready;
+                    if thread.priority > current_priority {
+                        NEED_RESCHED.store(true, Ordering::Release);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+static SCHEDULER: IrqSpinLock<Scheduler> = IrqSpinLock::new(Scheduler {
+    threads: [const { None }; MAX_THREADS],
+    current: 0,
+    next_id: 0,
+    slice_left: 0,
+});
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+// Set from interrupt context, acted on by `preempt` once the IRQ is acked
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+// Nonzero while the running thread holds a `SpinLock`; the holder must not
+// be switched out or every other thread would spin on it. Each thread has
+// its own count, swapped in and out of its `Thread` by `switch_away`
+static PREEMPT_DISABLED: AtomicU32 = AtomicU32::new(0);
+
+static EXITED: WaitQueue = WaitQueue::new();
+
+/// A snapshot of one thread for `ps`
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: ThreadState,
+    pub priority: Priority,
+    pub stack_size: usize,
+    pub switches: u64,
+    pub ticks: u64,
+}
+
+/// Saves callee-saved registers on the current stack, stores the stack
+/// pointer through `old_esp` and resumes whatever `new_esp` was saved by
+#[unsafe(naked)]
+unsafe extern "C" fn switch_context(old_esp: *mut u32, new_esp: u32) {
+    naked_asm!(
+        "mov eax, [esp + 4]",
+        "mov edx, [esp + 8]",
+        "push ebp",
+        "push ebx",
+        "push esi",
+        "push edi",
+        "mov [eax], esp",
+        "mov esp, edx",
+        "pop edi",
+        "pop esi",
+        "pop ebx",
+        "pop ebp",
+        "ret",
+    );
+}
+
+/// Turns the code running since boot into thread 0 and starts the idle
+/// thread the scheduler falls back on when everything else waits
+pub fn init() -> Result<(), &'static str> {
+    if heap::stats().total == 0 {
+        return Err("No kernel heap for thread stacks");
+    }
+    {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.threads[0] = Some(Thread {
+            id: ThreadId(0),
+            name: "kernel",
+            state: ThreadState::Running,
+            priority: Priority::Normal,
+            esp: 0,
+            stack: None,
+            entry: None,
+            kernel_stack: 0,
+            page_directory: 0,
+            preempt_count: 0,
+            waiting: 0,
+            switches: 0,
+            ticks: 0,
+        });
+        scheduler.current = 0;
+        scheduler.next_id = 1;
+        scheduler.slice_left = time_slice_ticks();
+    }
+
+    spawn_with_priority("idle", Priority::Idle, || loop {
+        unsafe {
+            asm!("sti", "hlt", options(nostack));
+        }
+    })?;
+    // Blocking and preemption need the idle thread to fall back on
+    INITIALIZED.store(true, Ordering::Release);
+    Ok(())
+}
+
+fn time_slice_ticks() -> u64 {
+    (TIME_SLICE_MS * timer::frequency() as u64 / 1000).max(1)
+}
+
+fn aging_ticks() -> u64 {
+    (AGING_MS * timer::frequency() as u64 / 1000).max(1)
+}
+
+/// Starts `f` at normal priority on a new thread with its own stack
+pub fn spawn<F>(name: &'static str, f: F) -> Result<ThreadId, &'static str>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_with_priority(name, Priority::Normal, f)
+}
+
+pub fn spawn_with_priority<F>(name: &'static str, priority: Priority, f: F) -> Result<ThreadId, &'static str>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+
+    // Initial frame for switch_context to pop: edi, esi, ebx, ebp, then
+    // thread_start as the return address and a null return for it
+    let top = (stack.as_mut_ptr() as usize + STACK_SIZE) & !0xF;
+    let esp = top - 4 * 6;
+    unsafe {
+        let frame = esp as *mut u32;
+        for i in 0..4 {
+            *frame.add(i) = 0;
+        }
+        *frame.add(4) = thread_start as u32;
+        *frame.add(5) = 0;
+    }
+
+    let mut scheduler = SCHEDULER.lock();
+    if scheduler.threads[0].is_none() {
+        return Err("Threads not initialized");
+    }
+    // Finished threads nobody joined give up their slot to new ones
+    let slot = scheduler
+        .threads
+        .iter()
+        .position(|t| t.is_none())
+        .or_else(|| {
+            scheduler
+                .threads
+                .iter()
+                .position(|t| matches!(t, Some(t) if t.state == ThreadState::Finished && t.stack.is_none()))
+        })
+        .ok_or("Too many threads")?;
+
+    let id = ThreadId(scheduler.next_id);
+    scheduler.next_id += 1;
+    scheduler.threads[slot] = Some(Thread {
+        id,
+        name,
+        state: ThreadState::Ready,
+        priority,
+        esp: esp as u32,
+        stack: Some(stack),
+        entry: Some(Box::new(f)),
+        kernel_stack: 0,
+        page_directory: 0,
+        preempt_count: 0,
+        waiting: 0,
+        switches: 0,
+        ticks: 0,
+    });
+    Ok(id)
+}
+
+/// First code a new thread runs, reached by `switch_context`'s `ret`
+extern "C" fn thread_start() -> ! {
+    reap();
+    let entry = {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        scheduler.threads[current].as_mut().and_then(|t| t.entry.take())
+    };
+    // The switch into this thread happened with interrupts off
+    idt::restore_interrupts(true);
+
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+/// Ends the calling thread. Its stack is freed by the next thread to run
+pub fn exit() -> ! {
+    idt::disable_interrupts();
+    EXITED.wake_all();
+    switch_away(ThreadState::Finished, Some(Priority::Idle));
+    unreachable!("finished thread was rescheduled");
+}
+
+/// Lets the next ready thread of at least the same priority run; returns
+/// at once if there is none
+pub fn yield_now() {
+    switch_away(ThreadState::Ready, None);
+}
+
+/// Lets any lower-priority thread run too, since the caller has nothing
+/// to do, and halts until the next interrupt if none can. Meant for
+/// polling loops
+pub fn idle() {
+    if !switch_away(ThreadState::Ready, Some(Priority::Low)) {
+        idt::wait_for_interrupt();
+    }
+}
+
+/// Puts the calling thread to sleep until timer tick `tick`. Does nothing
+/// before `init`, so early boot code can share callers with threads
+pub fn sleep_until(tick: u64) {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+    let interrupts_were_enabled = idt::disable_interrupts();
+    if timer::ticks() < tick {
+        switch_away(ThreadState::Sleeping(tick), Some(Priority::Idle));
+    }
+    idt::restore_interrupts(interrupts_were_enabled);
+}
+
+/// Waits for thread `id` to finish and releases its slot
+pub fn join(id: ThreadId) -> Result<(), &'static str> {
+    let mut result = Ok(());
+    EXITED.wait_until(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let index = match scheduler.find(id) {
+            Some(index) => index,
+            None => {
+                result = Err("No such thread");
+                return true;
+            }
+        };
+        if index == scheduler.current {
+            result = Err("A thread cannot join itself");
+            return true;
+        }
+        let reaped = matches!(&scheduler.threads[index], Some(t) if t.state == ThreadState::Finished && t.stack.is_none());
+        if reaped {
+            scheduler.threads[index] = None;
+        }
+        reaped
+    });
+    result
+}
+
+/// Changes thread `id`'s priority, switching at once if that lets a ready
+/// thread outrank the running one
+pub fn set_priority(id: ThreadId, priority: Priority) -> Result<(), &'static str> {
+    if priority == Priority::Idle {
+        return Err("Idle priority is reserved");
+    }
+    let is_current = {
+        let mut scheduler = SCHEDULER.lock();
+        let index = scheduler.find(id).ok_or("No such thread")?;
+        let current = scheduler.current;
+        if index != current && scheduler.is_ready(index) && priority > scheduler.priority(current) {
+            NEED_RESCHED.store(true, Ordering::Release);
+        }
+        if let Some(thread) = &mut scheduler.threads[index] {
+            thread.priority = priority;
+        }
+        index == current
+    };
+    // A thread that lowered itself gives way to anything it no longer
+    // outranks
+    if is_current {
+        yield_now();
+    }
+    Ok(())
+}
+
+/// Sets the stack the calling thread enters the kernel on from ring 3,
+/// kept in the TSS whenever the thread is switched back in. 0 clears it
+pub fn set_kernel_stack(top: u32) {
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        if let Some(thread) = &mut scheduler.threads[current] {
+            thread.kernel_stack = top;
+        }
+    }
+    if top != 0 {
+        gdt::set_kernel_stack(top);
+    }
+}
+
+/// Switches the calling thread to the address space with page directory
+/// `directory`, kept whenever the thread is switched back in. 0 returns
+/// it to the kernel's
+pub fn set_address_space(directory: u32) {
+    let interrupts_were_enabled = idt::disable_interrupts();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        if let Some(thread) = &mut scheduler.threads[current] {
+            thread.page_directory = directory;
+        }
+    }
+    paging::switch_directory(directory);
+    idt::restore_interrupts(interrupts_were_enabled);
+}
+
+/// Keeps the calling thread on the CPU until the matching
+/// `preempt_enable`; used by `SpinLock`
+pub fn preempt_disable() {
+    PREEMPT_DISABLED.fetch_add(1, Ordering::Acquire);
+}
+
+pub fn preempt_enable() {
+    PREEMPT_DISABLED.fetch_sub(1, Ordering::Release);
+}
+
+/// The calling thread's `preempt_disable` nesting depth
+pub fn preempt_count() -> u32 {
+    PREEMPT_DISABLED.load(Ordering::Acquire)
+}
+
+/// Puts the calling thread's nesting depth back to `count`, for code that
+/// unwinds past `SpinLock` guards without dropping them
+pub fn reset_preempt_count(count: u32) {
+    PREEMPT_DISABLED.store(count, Ordering::Release);
+}
+
+/// Timer interrupt hook: wakes sleepers and charges the running thread
+/// for the tick, asking for a switch once its slice is used up
+pub fn tick(now: u64) {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+    let mut scheduler = SCHEDULER.lock();
+    for i in 0..MAX_THREADS {
+        if let Some(Thread { state: ThreadState::Sleeping(until), .. }) = scheduler.threads[i] {
+            if until <= now {
+                scheduler.wake(i);
+            }
+        }
+    }
+    for thread in scheduler.threads.iter_mut().flatten() {
+        if thread.state == ThreadState::Ready {
+            thread.waiting += 1;
+        }
+    }
+
+    let current = scheduler.current;
+    if let Some(thread) = &mut scheduler.threads[current] {
+        thread.ticks += 1;
+    }
+    scheduler.slice_left = scheduler.slice_left.saturating_sub(1);
+    if scheduler.slice_left == 0 {
+        NEED_RESCHED.store(true, Ordering::Release);
+    }
+}
+
+/// Called by the IRQ dispatcher after the EOI. Switching here leaves the
+/// interrupted thread's registers on its own stack until it is resumed
+pub fn preempt() {
+    if PREEMPT_DISABLED.load(Ordering::Acquire) != 0 {
+        return;
+    }
+    if NEED_RESCHED.swap(false, Ordering::AcqRel) {
+        switch_away(ThreadState::Ready, None);
+    }
+}
+
+/// Marks the current thread `state` and switches to the best ready one of
+/// at least `min_priority`, or of the current thread's priority if None.
+/// Returns whether another thread ran in between
+fn switch_away(state: ThreadState, min_priority: Option<Priority>) -> bool {
+    let interrupts_were_enabled = idt::disable_interrupts();
+
+    let switch = {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        let next = match scheduler.threads[current] {
+            None => None,
+            Some(_) => {
+                let min_priority = min_priority.unwrap_or(scheduler.priority(current));
+                scheduler
+                    .next_ready()
+                    .filter(|&next| scheduler.effective_priority(next) >= min_priority)
+            }
+        };
+        if next.is_none() && scheduler.threads[current].is_some() {
+            // Staying put still starts a fresh slice
+            scheduler.slice_left = time_slice_ticks();
+        }
+        next.map(|next| {
+            let old = scheduler.threads[current].as_mut().unwrap();
+            old.state = state;
+            old.preempt_count = PREEMPT_DISABLED.load(Ordering::Acquire);
+            let old_esp = &mut old.esp as *mut u32;
+
+            let new = scheduler.threads[next].as_mut().unwrap();
+            new.state = ThreadState::Running;
+            new.waiting = 0;
+            new.switches += 1;
+            PREEMPT_DISABLED.store(new.preempt_count, Ordering::Release);
+            let new_esp = new.esp;
+            if new.kernel_stack != 0 {
+                gdt::set_kernel_stack(new.kernel_stack);
+            }
+            paging::switch_directory(new.page_directory);
+
+            scheduler.current = next;
+            scheduler.slice_left = time_slice_ticks();
+            (old_esp, new_esp)
+        })
+    };
+
+    let switched = match switch {
+        Some((old_esp, new_esp)) => {
+            // A switched-out thread cannot be joined, so its slot and saved
+            // esp stay put until it runs again
+            unsafe {
+                switch_context(old_esp, new_esp);
+            }
+            reap();
+            true
+        }
+        None => {
+            // The idle thread is always ready, so only a missing `init` or
+            // idle thread gets here with a thread that cannot continue
+            assert!(state == ThreadState::Ready, "no thread left to run");
+            false
+        }
+    };
+
+    idt::restore_interrupts(interrupts_were_enabled);
+    switched
+}
+
+/// Frees the stacks of finished threads, which is only safe from another
+/// thread's stack
+fn reap() {
+    let mut stacks: [Option<Box<[u8]>>; MAX_THREADS] = [const { None }; MAX_THREADS];
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        for (i, (slot, stack)) in scheduler.threads.iter_mut().zip(stacks.iter_mut()).enumerate() {
+            if let Some(thread) = slot {
+                if i != current && thread.state == ThreadState::Finished {
+                    *stack = thread.stack.take();
+                }
+            }
+        }
+    }
+    drop(stacks);
+}
+
+pub fn current() -> ThreadId {
+    let scheduler = SCHEDULER.lock();
+    match &scheduler.threads[scheduler.current] {
+        Some(thread) => thread.id,
+        None => ThreadId(0),
+    }
+}
+
+/// Calls `f` with every live or unjoined thread, in slot order
+pub fn for_each(mut f: impl FnMut(&ThreadInfo)) {
+    for i in 0..MAX_THREADS {
+        let info = {
+            let scheduler = SCHEDULER.lock();
+            scheduler.threads[i].as_ref().map(|t| ThreadInfo {
+                id: t.id,
+                name: t.name,
+                state: t.state,
+                priority: t.priority,
+                stack_size: t.stack.as_ref().map_or(0, |s| s.len()),
+                switches: t.switches,
+                ticks: t.ticks,
+            })
+        };
+        if let Some(info) = info {
+            f(&info);
+        }
+    }
+}
+
+/// Threads blocked until some event, such as a key press, wakes them
+pub struct WaitQueue {
+    waiters: IrqSpinLock<[Option<ThreadId>; MAX_THREADS]>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqSpinLock::new([None; MAX_THREADS]),
+        }
+    }
+
+    /// Blocks until `condition` holds. It is checked with interrupts off,
+    /// so a wakeup from an IRQ cannot slip in between check and sleep.
+    /// Before `init` this polls instead
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        let interrupts_were_enabled = idt::disable_interrupts();
+        while !condition() {
+            if !INITIALIZED.load(Ordering::Acquire) {
+                idt::restore_interrupts(true);
+                idt::wait_for_interrupt();
+                idt::disable_interrupts();
+                continue;
+            }
+            {
+                let id = current();
+                let mut waiters = self.waiters.lock();
+                if let Some(slot) = waiters.iter_mut().find(|w| w.is_none()) {
+                    *slot = Some(id);
+                }
+            }
+            switch_away(ThreadState::Blocked, Some(Priority::Idle));
+        }
+        idt::restore_interrupts(interrupts_were_enabled);
+    }
+
+    pub fn wake_all(&self) {
+        let mut waiters = self.waiters.lock();
+        let mut scheduler = SCHEDULER.lock();
+        for waiter in waiters.iter_mut() {
+            if let Some(index) = waiter.take().and_then(|id| scheduler.find(id)) {
+                scheduler.wake(index);
+            }
+        }
+    }
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    static STEPS: AtomicU32 = AtomicU32::new(0);
+    static SPINNING: AtomicBool = AtomicBool::new(false);
+
+    pub fn spawned_threads_interleave_and_join() {
+        STEPS.store(0, Ordering::SeqCst);
+        let worker = spawn("test-worker", || {
+            for _ in 0..3 {
+                STEPS.fetch_add(1, Ordering::SeqCst);
+                yield_now();
+            }
+        })
+        .expect("spawn failed");
+
+        // Spawning alone does not run the new thread
+        assert_eq!(STEPS.load(Ordering::SeqCst), 0);
+        join(worker).expect("join failed");
+        assert_eq!(STEPS.load(Ordering::SeqCst), 3);
+        assert_eq!(join(worker), Err("No such thread"));
+    }
+
+    pub fn cannot_join_self() {
+        assert_eq!(join(current()), Err("A thread cannot join itself"));
+    }
+
+    pub fn busy_thread_is_preempted() {
+        SPINNING.store(true, Ordering::SeqCst);
+        STEPS.store(0, Ordering::SeqCst);
+        // Never yields; only the timer can take the CPU back from it
+        let spinner = spawn("test-spinner", || {
+            while SPINNING.load(Ordering::SeqCst) {
+                STEPS.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .expect("spawn failed");
+
+        let start = timer::ticks();
+        while timer::ticks() < start + 10 {
+            core::hint::spin_loop();
+        }
+        assert!(STEPS.load(Ordering::Relaxed) > 0, "spinner never ran");
+
+        SPINNING.store(false, Ordering::SeqCst);
+        join(spinner).expect("join failed");
+    }
+
+    pub fn preempt_count_is_per_thread() {
+        static SEEN: AtomicU32 = AtomicU32::new(u32::MAX);
+
+        let before = preempt_count();
+        preempt_disable();
+        let counter = spawn("test-counter", || SEEN.store(preempt_count(), Ordering::SeqCst))
+            .expect("spawn failed");
+        join(counter).expect("join failed");
+        let after = preempt_count();
+        preempt_enable();
+
+        assert_eq!(SEEN.load(Ordering::SeqCst), 0);
+        assert_eq!(after, before + 1);
+    }
+
+    pub fn busy_thread_does_not_starve_lower_priority() {
+        STEPS.store(0, Ordering::SeqCst);
+        let low = spawn_with_priority("test-low", Priority::Low, || {
+            STEPS.store(1, Ordering::SeqCst);
+        })
+        .expect("spawn failed");
+
+        // Never yields, so only aging lets the low thread in
+        let deadline = timer::ticks() + 3 * aging_ticks();
+        while STEPS.load(Ordering::SeqCst) == 0 && timer::ticks() < deadline {
+            core::hint::spin_loop();
+        }
+        assert_eq!(STEPS.load(Ordering::SeqCst), 1, "low-priority thread starved");
+        join(low).expect("join failed");
+    }
+
+    pub fn lowering_own_priority_yields() {
+        STEPS.store(0, Ordering::SeqCst);
+        let worker = spawn("test-worker", || {
+            STEPS.store(1, Ordering::SeqCst);
+        })
+        .expect("spawn failed");
+
+        set_priority(current(), Priority::Low).expect("set_priority failed");
+        let ran = STEPS.load(Ordering::SeqCst);
+        set_priority(current(), Priority::Normal).expect("set_priority failed");
+        assert_eq!(ran, 1);
+        join(worker).expect("join failed");
+        assert_eq!(set_priority(current(), Priority::Idle), Err("Idle priority is reserved"));
+    }
+
+    pub fn wait_queue_wakes_blocked_thread() {
+        static QUEUE: WaitQueue = WaitQueue::new();
+        static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+        SIGNALLED.store(false, Ordering::SeqCst);
+        STEPS.store(0, Ordering::SeqCst);
+        let waiter = spawn("test-waiter", || {
+            QUEUE.wait_until(|| SIGNALLED.load(Ordering::SeqCst));
+            STEPS.store(1, Ordering::SeqCst);
+        })
+        .expect("spawn failed");
+
+        // Let the waiter block, then check it stays blocked
+        timer::sleep_ms(20);
+        assert_eq!(STEPS.load(Ordering::SeqCst), 0);
+
+        SIGNALLED.store(true, Ordering::SeqCst);
+        QUEUE.wake_all();
+        join(waiter).expect("join failed");
+        assert_eq!(STEPS.load(Ordering::SeqCst), 1);
+    }
+
+    pub fn sleeping_thread_wakes_on_time() {
+        STEPS.store(0, Ordering::SeqCst);
+        let sleeper = spawn("test-sleeper", || {
+            timer::sleep_ms(30);
+            STEPS.store(1, Ordering::SeqCst);
+        })
+        .expect("spawn failed");
+
+        let start = timer::uptime_ms();
+        join(sleeper).expect("join failed");
+        assert_eq!(STEPS.load(Ordering::SeqCst), 1);
+        assert!(timer::uptime_ms() - start >= 30);
+    }
+}
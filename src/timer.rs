@@ -0,0 +1,252 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::apic;
+use crate::io::outb;
+use crate::irq;
+use crate::sync::IrqSpinLock;
+use crate::thread;
+
+/// Input clock of the 8253/8254 PIT
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+pub const DEFAULT_FREQUENCY_HZ: u32 = 100;
+
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+// Channel 0, lobyte/hibyte access, mode 2 (rate generator)
+const PIT_MODE_RATE_GENERATOR: u8 = 0x34;
+
+const MAX_TIMERS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u32);
+
+#[derive(Clone, Copy)]
+struct Timer {
+    id: TimerId,
+    deadline: u64,
+    // 0 for one-shot timers
+    period: u64,
+    callback: fn(),
+}
+
+struct TimerState {
+    frequency_hz: u32,
+    // Uptime and tick count at the last frequency change
+    base_ms: u64,
+    base_ticks: u64,
+    timers: [Option<Timer>; MAX_TIMERS],
+    next_id: u32,
+}
+
+// Written only by the timer IRQ, so readers never need the state lock
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static STATE: IrqSpinLock<TimerState> = IrqSpinLock::new(TimerState {
+    frequency_hz: DEFAULT_FREQUENCY_HZ,
+    base_ms: 0,
+    base_ticks: 0,
+    timers: [None; MAX_TIMERS],
+    next_id: 1,
+});
+
+/// Programs PIT channel 0 and takes over IRQ 0, which the LAPIC timer
+/// drives instead once the APIC is enabled
+pub fn init(frequency_hz: u32) -> Result<(), &'static str> {
+    set_frequency(frequency_hz)?;
+    irq::register(irq::IRQ_TIMER, "timer", tick)
+}
+
+/// Changes the tick rate without disturbing uptime or pending timers
+pub fn set_frequency(frequency_hz: u32) -> Result<(), &'static str> {
+    if frequency_hz == 0 || frequency_hz > PIT_FREQUENCY {
+        return Err("Unsupported timer frequency");
+    }
+    let divisor = PIT_FREQUENCY / frequency_hz;
+    if divisor > 0xFFFF {
+        return Err("Unsupported timer frequency");
+    }
+
+    {
+        let mut state = STATE.lock();
+        let now = ticks();
+
+        // Pending timers were scheduled in ticks of the old rate
+        let old = state.frequency_hz as u64;
+        for timer in state.timers.iter_mut().flatten() {
+            let remaining = timer.deadline.saturating_sub(now);
+            timer.deadline = now + remaining * frequency_hz as u64 / old;
+            timer.period = (timer.period * frequency_hz as u64 / old).max(1);
+        }
+
+        state.base_ms += ticks_to_ms(now - state.base_ticks, state.frequency_hz);
+        state.base_ticks = now;
+        state.frequency_hz = frequency_hz;
+        unsafe {
+            outb(PIT_COMMAND, PIT_MODE_RATE_GENERATOR);
+            outb(PIT_CHANNEL_0, (divisor & 0xFF) as u8);
+            outb(PIT_CHANNEL_0, ((divisor >> 8) & 0xFF) as u8);
+        }
+    }
+    // Not under STATE: `irq::enable_apic` reaches `frequency()` while
+    // holding the IRQ lines lock that `irq::controller` takes
+    if irq::controller() == irq::Controller::Apic {
+        apic::set_timer_frequency(frequency_hz);
+    }
+    Ok(())
+}
+
+pub fn frequency() -> u32 {
+    STATE.lock().frequency_hz
+}
+
+/// Ticks since boot; each lasts `1 / frequency()` seconds
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Acquire)
+}
+
+pub fn uptime_ms() -> u64 {
+    let state = STATE.lock();
+    state.base_ms + ticks_to_ms(ticks() - state.base_ticks, state.frequency_hz)
+}
+
+fn ticks_to_ms(ticks: u64, frequency_hz: u32) -> u64 {
+    ticks * 1000 / frequency_hz as u64
+}
+
+/// Rounds up so a wait never ends early
+fn ms_to_ticks(ms: u64, frequency_hz: u32) -> u64 {
+    (ms * frequency_hz as u64).div_ceil(1000)
+}
+
+/// Waits at least `ms` milliseconds, running other threads or halting
+/// the CPU meanwhile
+pub fn sleep_ms(ms: u64) {
+    let timeout = Timeout::after_ms(ms);
+    thread::sleep_until(timeout.deadline);
+    while !timeout.expired() {
+        thread::idle();
+    }
+}
+
+/// A deadline to poll from loops that cannot block
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    deadline: u64,
+}
+
+impl Timeout {
+    pub fn after_ms(ms: u64) -> Self {
+        Self {
+            deadline: ticks() + ms_to_ticks(ms, frequency()),
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        ticks() >= self.deadline
+    }
+}
+
+/// Runs `callback` once, from the timer interrupt, after `ms` milliseconds
+pub fn add_oneshot(ms: u64, callback: fn()) -> Result<TimerId, &'static str> {
+    add_timer(ms, 0, callback)
+}
+
+/// Runs `callback` from the timer interrupt every `ms` milliseconds
+pub fn add_periodic(ms: u64, callback: fn()) -> Result<TimerId, &'static str> {
+    let period = ms_to_ticks(ms, frequency()).max(1);
+    add_timer(ms, period, callback)
+}
+
+fn add_timer(ms: u64, period: u64, callback: fn()) -> Result<TimerId, &'static str> {
+    let mut state = STATE.lock();
+    let id = TimerId(state.next_id);
+    let deadline = ticks() + ms_to_ticks(ms, state.frequency_hz).max(1);
+    let slot = state.timers.iter_mut().find(|t| t.is_none()).ok_or("Too many timers")?;
+    *slot = Some(Timer {
+        id,
+        deadline,
+        period,
+        callback,
+    });
+    state.next_id = state.next_id.wrapping_add(1);
+    Ok(id)
+}
+
+pub fn cancel(id: TimerId) -> Result<(), &'static str> {
+    let mut state = STATE.lock();
+    let slot = state
+        .timers
+        .iter_mut()
+        .find(|t| t.map(|t| t.id) == Some(id))
+        .ok_or("No such timer")?;
+    *slot = None;
+    Ok(())
+}
+
+fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::AcqRel) + 1;
+    thread::tick(now);
+
+    // Collect due callbacks first so they may add or cancel timers
+    let mut due: [Option<fn()>; MAX_TIMERS] = [None; MAX_TIMERS];
+    {
+        let mut state = STATE.lock();
+        for (slot, pending) in state.timers.iter_mut().zip(due.iter_mut()) {
+            let timer = match *slot {
+                Some(timer) if timer.deadline <= now => timer,
+                _ => continue,
+            };
+            *slot = if timer.period != 0 {
+                Some(Timer { deadline: now + timer.period, ..timer })
+            } else {
+                None
+            };
+            *pending = Some(timer.callback);
+        }
+    }
+
+    // Callbacks run with interrupts off and must stay short
+    for callback in due.iter().flatten() {
+        callback();
+    }
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicU32;
+
+    static FIRED: AtomicU32 = AtomicU32::new(0);
+
+    fn count_fire() {
+        FIRED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn conversions_round_up() {
+        assert_eq!(ms_to_ticks(10, 100), 1);
+        assert_eq!(ms_to_ticks(11, 100), 2);
+        assert_eq!(ms_to_ticks(0, 100), 0);
+        assert_eq!(ticks_to_ms(150, 100), 1500);
+        assert_eq!(ms_to_ticks(1, 1000), 1);
+    }
+
+    pub fn sleep_waits_at_least_requested_time() {
+        let start = uptime_ms();
+        sleep_ms(50);
+        assert!(uptime_ms() - start >= 50);
+    }
+
+    pub fn oneshot_and_periodic_callbacks() {
+        FIRED.store(0, Ordering::Relaxed);
+        add_oneshot(20, count_fire).expect("timer queue full");
+        sleep_ms(60);
+        assert_eq!(FIRED.load(Ordering::Relaxed), 1);
+
+        FIRED.store(0, Ordering::Relaxed);
+        let id = add_periodic(10, count_fire).expect("timer queue full");
+        sleep_ms(60);
+        cancel(id).expect("periodic timer vanished");
+        let fired = FIRED.load(Ordering::Relaxed);
+        assert!(fired >= 4, "periodic timer fired {} times", fired);
+        assert_eq!(cancel(id), Err("No such timer"));
+    }
+}
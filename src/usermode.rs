@@ -0,0 +1,591 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use core::arch::{global_asm, naked_asm};
+use core::fmt::Write;
+use core::slice;
+
+use crate::elf::Elf;
+use crate::exceptions::{self, ExceptionFrame};
+use crate::frame_allocator::{get_frame_allocator, FRAME_SIZE};
+use crate::gdt;
+use crate::idt;
+use crate::paging::{self, AddressSpace, PageFlags};
+use crate::process;
+use crate::syscall;
+use crate::thread;
+use crate::vga_colors::Color;
+use crate::writer;
+
+/// Where program images are loaded and start executing
+pub const USER_BASE: u32 = 0x4000_0000;
+/// Highest address `brk` may grow the program's heap to
+pub const USER_HEAP_LIMIT: u32 = 0x5000_0000;
+pub const USER_STACK_TOP: u32 = 0x8000_0000;
+pub const USER_STACK_SIZE: u32 = 64 * 1024;
+
+/// Exit code of a program killed by CPU exception `n` is this plus `n`,
+/// as shells report death by signal
+pub const EXIT_KILLED: i32 = 128;
+/// Exit code of a program ended by `process::kill`, as for SIGKILL
+pub const EXIT_TERMINATED: i32 = EXIT_KILLED + 9;
+/// Exit code of a program ended by Ctrl+C, as for SIGINT
+pub const EXIT_INTERRUPTED: i32 = EXIT_KILLED + 2;
+
+const KERNEL_STACK_SIZE: usize = 16 * 1024;
+/// Most bytes of argument and environment strings a program can be given
+const MAX_ARGS_SIZE: usize = 4096;
+
+/// The user part of a process, kept in its process table entry while
+/// the program runs
+pub struct Program {
+    // End of the loaded image, below which `brk` cannot shrink
+    image_end: u32,
+    brk: u32,
+    // Kernel stack pointer saved by `enter_user`, resumed when it ends
+    saved_esp: u32,
+    // Interrupts and system calls from ring 3 run on this stack
+    kernel_stack: Box<[u8]>,
+    space: AddressSpace,
+}
+
+// Built-in demo programs. They are copied to USER_BASE before they run,
+// so they only use relative jumps and find their data with call/pop
+global_asm!(
+    ".global user_hello_start",
+    ".global user_hello_end",
+    "user_hello_start:",
+    "call user_hello_base",
+    "user_hello_base:",
+    "pop esi",
+    "mov eax, {write}",
+    "mov ebx, {stdout}",
+    "lea ecx, [esi + user_hello_msg - user_hello_base]",
+    "mov edx, user_hello_msg_end - user_hello_msg",
+    "int 0x80",
+    "mov eax, {exit}",
+    "xor ebx, ebx",
+    "int 0x80",
+    "user_hello_msg:",
+    ".ascii \"Hello from ring 3!\\n\"",
+    "user_hello_msg_end:",
+    "user_hello_end:",
+
+    ".global user_echo_start",
+    ".global user_echo_end",
+    "user_echo_start:",
+    "sub esp, 128",
+    "mov eax, {read}",
+    "mov ebx, {stdin}",
+    "mov ecx, esp",
+    "mov edx, 128",
+    "int 0x80",
+    "mov edx, eax",
+    "mov eax, {write}",
+    "mov ebx, {stdout}",
+    "mov ecx, esp",
+    "int 0x80",
+    "mov eax, {exit}",
+    "xor ebx, ebx",
+    "int 0x80",
+    "user_echo_end:",
+
+    // Scribbles on the VGA buffer, which only the kernel may touch
+    ".global user_fault_start",
+    ".global user_fault_end",
+    "user_fault_start:",
+    "mov eax, 0xB8000",
+    "mov dword ptr [eax], 0x4F214F21",
+    "mov eax, {exit}",
+    "xor ebx, ebx",
+    "int 0x80",
+    "user_fault_end:",
+    write = const syscall::SYS_WRITE,
+    read = const syscall::SYS_READ,
+    exit = const syscall::SYS_EXIT,
+    stdin = const syscall::STDIN,
+    stdout = const syscall::STDOUT,
+);
+
+extern "C" {
+    static user_hello_start: u8;
+    static user_hello_end: u8;
+    static user_echo_start: u8;
+    static user_echo_end: u8;
+    static user_fault_start: u8;
+    static user_fault_end: u8;
+}
+
+/// Names and descriptions of the programs `builtin` knows
+pub const BUILTINS: [(&str, &str); 3] = [
+    ("hello", "print a greeting"),
+    ("echo", "read a line and print it back"),
+    ("fault", "write to kernel memory and get killed"),
+];
+
+pub fn builtin(name: &[u8]) -> Option<&'static [u8]> {
+    unsafe {
+        let (start, end) = match name {
+            b"hello" => (&user_hello_start, &user_hello_end),
+            b"echo" => (&user_echo_start, &user_echo_end),
+            b"fault" => (&user_fault_start, &user_fault_end),
+            _ => return None,
+        };
+        let start = start as *const u8;
+        Some(slice::from_raw_parts(start, end as *const u8 as usize - start as usize))
+    }
+}
+
+/// Saves the callee-saved registers and the stack pointer through
+/// `saved_esp`, then drops to ring 3 at `entry`. Returns the exit code
+/// once `leave_user` resumes the saved stack
+#[unsafe(naked)]
+unsafe extern "C" fn enter_user(entry: u32, user_stack: u32, saved_esp: *mut u32) -> i32 {
+    naked_asm!(
+        "push ebp",
+        "push ebx",
+        "push esi",
+        "push edi",
+        "mov ecx, [esp + 20]",
+        "mov edx, [esp + 24]",
+        "mov eax, [esp + 28]",
+        "mov [eax], esp",
+        // The kernel keeps these flat selectors after the program ends:
+        // ring 0 may use DPL 3 data segments, while an iret to ring 3
+        // would null kernel ones
+        "mov ax, {udata}",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov fs, ax",
+        "mov gs, ax",
+        "push {udata}",
+        "push edx",
+        // IF set, IOPL 0 so port I/O faults
+        "push 0x202",
+        "push {ucode}",
+        "push ecx",
+        "iretd",
+        udata = const gdt::USER_DATA_SELECTOR as u32,
+        ucode = const gdt::USER_CODE_SELECTOR as u32,
+    );
+}
+
+/// Returns `code` from the `enter_user` call that saved `saved_esp`,
+/// abandoning the kernel stack the program entered on
+#[unsafe(naked)]
+unsafe extern "C" fn leave_user(saved_esp: u32, code: i32) -> ! {
+    naked_asm!(
+        "mov eax, [esp + 8]",
+        "mov esp, [esp + 4]",
+        "pop edi",
+        "pop esi",
+        "pop ebx",
+        "pop ebp",
+        "ret",
+    );
+}
+
+fn page_align_up(addr: u32) -> u32 {
+    (addr + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)
+}
+
+/// Backs [start, end) of the active address space with fresh zeroed
+/// frames the program can use
+fn map_range(start: u32, end: u32) -> Result<(), &'static str> {
+    let mut page = start;
+    while page < end {
+        let frame = get_frame_allocator().allocate_frame().ok_or("Out of physical memory")?;
+        if let Err(e) = paging::map_page(page, frame, PageFlags::WRITABLE | PageFlags::USER) {
+            let _ = get_frame_allocator().free_frame(frame);
+            return Err(e);
+        }
+        unsafe {
+            core::ptr::write_bytes(page as *mut u8, 0, FRAME_SIZE as usize);
+        }
+        page += FRAME_SIZE;
+    }
+    Ok(())
+}
+
+/// Unmaps whatever part of [start, end) is mapped and frees its frames
+fn unmap_range(start: u32, end: u32) {
+    let mut page = start;
+    while page < end {
+        if let Ok(frame) = paging::unmap_page(page) {
+            let _ = get_frame_allocator().free_frame(frame);
+        }
+        page += FRAME_SIZE;
+    }
+}
+
+/// What to put in the program's part of the address space
+enum Image<'a> {
+    /// Position-independent code copied to `USER_BASE` and entered there
+    Flat(&'a [u8]),
+    Elf(Elf<'a>),
+}
+
+impl<'a> Image<'a> {
+    fn entry(&self) -> u32 {
+        match self {
+            Image::Flat(_) => USER_BASE,
+            Image::Elf(elf) => elf.entry,
+        }
+    }
+
+    /// End of the highest byte the image occupies, checking everything it
+    /// loads falls inside the program's area
+    fn end(&self) -> Result<u32, &'static str> {
+        let end = match self {
+            Image::Flat(code) => {
+                if code.is_empty() || code.len() as u32 > USER_HEAP_LIMIT - USER_BASE {
+                    return Err("Bad program size");
+                }
+                USER_BASE + code.len() as u32
+            }
+            Image::Elf(elf) => {
+                let mut end = USER_BASE;
+                for segment in elf.segments() {
+                    if segment.address < USER_BASE || segment.end() > USER_HEAP_LIMIT {
+                        return Err("Segment outside the user program area");
+                    }
+                    end = end.max(segment.end());
+                }
+                end
+            }
+        };
+        let entry = self.entry();
+        if entry < USER_BASE || entry >= end {
+            return Err("Entry point outside the program");
+        }
+        Ok(end)
+    }
+
+    /// Copies the image into pages mapped, and zeroed, over its extent
+    unsafe fn copy_in(&self) {
+        match self {
+            Image::Flat(code) => {
+                core::ptr::copy_nonoverlapping(code.as_ptr(), USER_BASE as *mut u8, code.len());
+            }
+            Image::Elf(elf) => {
+                for segment in elf.segments() {
+                    let data = elf.file_data(&segment);
+                    core::ptr::copy_nonoverlapping(data.as_ptr(), segment.address as *mut u8, data.len());
+                }
+            }
+        }
+    }
+}
+
+/// Runs position-independent `code` from `USER_BASE`, like a built-in
+pub fn run(code: &[u8], argv: &[&[u8]], envp: &[&[u8]]) -> Result<i32, &'static str> {
+    start(Image::Flat(code), argv, envp)
+}
+
+/// Loads a statically linked ELF executable and runs it
+pub fn exec(file: &[u8], argv: &[&[u8]], envp: &[&[u8]]) -> Result<i32, &'static str> {
+    start(Image::Elf(Elf::parse(file)?), argv, envp)
+}
+
+/// Maps `image` and a stack holding `argv` and `envp` into a fresh
+/// address space, then runs the program in ring 3 on the calling thread
+/// until it exits or faults. The program belongs to the calling process,
+/// which runs one at a time
+fn start(image: Image, argv: &[&[u8]], envp: &[&[u8]]) -> Result<i32, &'static str> {
+    if !paging::is_enabled() {
+        return Err("User programs need paging");
+    }
+    let image_end = page_align_up(image.end()?);
+    let program = Program {
+        image_end,
+        brk: image_end,
+        saved_esp: 0,
+        kernel_stack: vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice(),
+        space: AddressSpace::new()?,
+    };
+    let directory = program.space.directory();
+    process::with_current(|process| {
+        if process.program.is_some() {
+            return Err("The process is already running a program");
+        }
+        process.program = Some(program);
+        Ok(())
+    })
+    .unwrap_or(Err("User programs must run in a process"))?;
+    thread::set_address_space(directory);
+
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    let loaded = map_range(USER_BASE, image_end)
+        .and_then(|_| map_range(stack_bottom, USER_STACK_TOP))
+        .and_then(|_| unsafe {
+            image.copy_in();
+            let stack = slice::from_raw_parts_mut(stack_bottom as *mut u8, USER_STACK_SIZE as usize);
+            build_stack(stack, USER_STACK_TOP, argv, envp)
+        });
+    let user_esp = match loaded {
+        Ok(esp) => esp,
+        Err(e) => {
+            unload();
+            return Err(e);
+        }
+    };
+
+    // The program stays in the process table, which nothing frees while
+    // this thread is still running it
+    let (saved_esp, kernel_stack_top) = with_program(|program| {
+        let top = (program.kernel_stack.as_ptr() as u32 + KERNEL_STACK_SIZE as u32) & !0xF;
+        (&mut program.saved_esp as *mut u32, top)
+    })
+    .unwrap();
+    thread::set_kernel_stack(kernel_stack_top);
+    let preempt_count = thread::preempt_count();
+    let code = unsafe { enter_user(image.entry(), user_esp, saved_esp) };
+    // A fault ends the program with interrupts still off, and `leave_user`
+    // skips the drop of any guard the kernel held on the abandoned stack
+    idt::restore_interrupts(true);
+    thread::reset_preempt_count(preempt_count);
+    thread::set_kernel_stack(0);
+
+    unload();
+    Ok(code)
+}
+
+fn write_word(stack: &mut [u8], at: &mut usize, value: u32) {
+    stack[*at..*at + 4].copy_from_slice(&value.to_le_bytes());
+    *at += 4;
+}
+
+/// Lays out the System V i386 initial stack in `stack`, the memory just
+/// below `stack_top`: argc, the argv pointers and a null, the envp
+/// pointers and a null, then an empty auxiliary vector, with the strings
+/// themselves above. Returns the program's initial esp
+fn build_stack(stack: &mut [u8], stack_top: u32, argv: &[&[u8]], envp: &[&[u8]]) -> Result<u32, &'static str> {
+    let strings: usize = argv.iter().chain(envp.iter()).map(|s| s.len() + 1).sum();
+    if strings > MAX_ARGS_SIZE {
+        return Err("Argument list too long");
+    }
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2;
+    let strings_start = stack.len().checked_sub(strings).ok_or("Argument list too long")?;
+    let table_start = strings_start
+        .checked_sub(words * 4)
+        .ok_or("Argument list too long")?
+        & !0xF;
+
+    let base = stack_top - stack.len() as u32;
+    let mut string_at = strings_start;
+    let mut word_at = table_start;
+    write_word(stack, &mut word_at, argv.len() as u32);
+    for list in [argv, envp].iter() {
+        for s in list.iter() {
+            write_word(stack, &mut word_at, base + string_at as u32);
+            stack[string_at..string_at + s.len()].copy_from_slice(s);
+            stack[string_at + s.len()] = 0;
+            string_at += s.len() + 1;
+        }
+        write_word(stack, &mut word_at, 0);
+    }
+    // AT_NULL type and value end the auxiliary vector
+    write_word(stack, &mut word_at, 0);
+    write_word(stack, &mut word_at, 0);
+
+    Ok(base + table_start as u32)
+}
+
+/// Frees the program's pages and page tables, switching the thread back
+/// to the kernel's address space
+fn unload() {
+    let program = process::with_current(|process| process.program.take()).and_then(|p| p);
+    if let Some(program) = program {
+        unmap_range(USER_BASE, page_align_up(program.brk));
+        unmap_range(USER_STACK_TOP - USER_STACK_SIZE, USER_STACK_TOP);
+        thread::set_address_space(0);
+    }
+}
+
+fn with_program<R>(f: impl FnOnce(&mut Program) -> R) -> Option<R> {
+    process::with_current(|process| process.program.as_mut().map(f)).and_then(|r| r)
+}
+
+/// Whether the calling thread is running a user program
+pub fn is_running() -> bool {
+    with_program(|_| ()).is_some()
+}
+
+/// Ends the running program from its system call or fault handler; `run`
+/// returns `code`. Must be called with no locks held
+pub fn exit(code: i32) -> ! {
+    match with_program(|program| program.saved_esp) {
+        Some(esp) => unsafe { leave_user(esp, code) },
+        None => panic!("exit with no program running"),
+    }
+}
+
+/// Acts on a `kill` or keyboard signal sent to the program's process:
+/// Ctrl+C ends it and Ctrl+Z lets it run on in the background. Called
+/// where the kernel is about to return to it, with no locks held
+pub fn handle_signals() {
+    if !is_running() {
+        return;
+    }
+    if process::kill_requested() {
+        exit(EXIT_TERMINATED);
+    }
+    match process::take_signal() {
+        Some(process::Signal::Interrupt) => {
+            writer::console().write_str("^C\n");
+            exit(EXIT_INTERRUPTED);
+        }
+        Some(process::Signal::Suspend) => {
+            writer::console().write_str("^Z\n");
+            process::move_to_background();
+        }
+        None => {}
+    }
+}
+
+/// Ends a program that raised a CPU exception instead of taking the
+/// kernel down with it
+pub fn kill(frame: &ExceptionFrame) -> ! {
+    let name = exceptions::exception_name(frame.vector);
+    warn!("User program killed: {} at {:#010x}", name, frame.eip);
+    {
+        let mut console = writer::console();
+        console.set_color(Color::LightRed, Color::Black);
+        let _ = writeln!(console, "{} at {:#010x}, program killed", name, frame.eip);
+        console.set_color(Color::White, Color::Black);
+    }
+    exit(EXIT_KILLED + frame.vector as i32);
+}
+
+/// Whether the running program owns all of [ptr, ptr + len)
+pub fn is_user_range(ptr: u32, len: u32) -> bool {
+    let end = match ptr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    let brk = match with_program(|program| program.brk) {
+        Some(brk) => brk,
+        None => return false,
+    };
+    (ptr >= USER_BASE && end <= brk) || (ptr >= USER_STACK_TOP - USER_STACK_SIZE && end <= USER_STACK_TOP)
+}
+
+/// Moves the program break to `addr`, mapping or freeing whole pages, and
+/// returns the break in effect afterwards; an unusable `addr` such as 0
+/// leaves it where it was
+pub fn set_break(addr: u32) -> u32 {
+    with_program(|program| {
+        if addr < program.image_end || addr > USER_HEAP_LIMIT {
+            return program.brk;
+        }
+
+        let old_end = page_align_up(program.brk);
+        let new_end = page_align_up(addr);
+        if new_end > old_end {
+            if map_range(old_end, new_end).is_err() {
+                unmap_range(old_end, new_end);
+                return program.brk;
+            }
+        } else {
+            unmap_range(new_end, old_end);
+        }
+        program.brk = addr;
+        addr
+    })
+    .unwrap_or(0)
+}
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+    use crate::elf;
+    use crate::multiboot;
+    use crate::timer;
+
+    pub fn hello_runs_and_exits_cleanly() {
+        let image = builtin(b"hello").expect("no hello program");
+        assert_eq!(run(image, &[b"hello"], &[]), Ok(0));
+        assert!(!is_running());
+        assert_eq!(paging::translate(USER_BASE), None);
+        assert_eq!(paging::translate(USER_STACK_TOP - FRAME_SIZE), None);
+    }
+
+    pub fn fault_kills_only_the_program() {
+        let image = builtin(b"fault").expect("no fault program");
+        let preempt_count = thread::preempt_count();
+        assert_eq!(run(image, &[b"fault"], &[]), Ok(EXIT_KILLED + 14));
+        assert!(idt::interrupts_enabled());
+        assert_eq!(thread::preempt_count(), preempt_count);
+
+        // The first run may have allocated page tables that stay around
+        let free_before = get_frame_allocator().stats().free;
+        assert_eq!(run(image, &[b"fault"], &[]), Ok(EXIT_KILLED + 14));
+        assert_eq!(get_frame_allocator().stats().free, free_before);
+    }
+
+    pub fn programs_get_separate_address_spaces() {
+        // jmp $ at USER_BASE, in a process of its own
+        let spinner = process::spawn(b"test-spin", true, || run(&[0xEB, 0xFE], &[b"spin"], &[]).unwrap_or(-1))
+            .expect("spawn failed");
+        timer::sleep_ms(20);
+
+        // The same addresses, loaded again while the spinner still runs
+        let image = builtin(b"hello").expect("no hello program");
+        assert_eq!(run(image, &[b"hello"], &[]), Ok(0));
+
+        process::kill(spinner).expect("kill failed");
+        assert_eq!(process::wait(spinner), Ok(EXIT_TERMINATED));
+    }
+
+    pub fn initial_stack_holds_argv_and_envp() {
+        const TOP: u32 = 0x1000_0000;
+        let mut stack = vec![0u8; 1024];
+        let esp = build_stack(&mut stack, TOP, &[b"prog", b"-v"], &[b"PWD=/"]).expect("no room");
+        assert_eq!(esp % 16, 0);
+
+        let base = TOP - stack.len() as u32;
+        let word = |addr: u32| {
+            let at = (addr - base) as usize;
+            u32::from_le_bytes([stack[at], stack[at + 1], stack[at + 2], stack[at + 3]])
+        };
+        let string = |addr: u32| {
+            let at = (addr - base) as usize;
+            let len = stack[at..].iter().position(|&b| b == 0).unwrap();
+            &stack[at..at + len]
+        };
+
+        assert_eq!(word(esp), 2);
+        assert_eq!(string(word(esp + 4)), b"prog");
+        assert_eq!(string(word(esp + 8)), b"-v");
+        assert_eq!(word(esp + 12), 0);
+        assert_eq!(string(word(esp + 16)), b"PWD=/");
+        assert_eq!(word(esp + 20), 0);
+
+        let huge = vec![b'x'; MAX_ARGS_SIZE];
+        assert!(build_stack(&mut stack, TOP, &[&huge], &[]).is_err());
+    }
+
+    pub fn exec_loads_elf_with_arguments() {
+        // mov ebx, [esp]; mov eax, SYS_EXIT; int 0x80 - exits with argc
+        let code = [0x8B, 0x1C, 0x24, 0xB8, syscall::SYS_EXIT as u8, 0, 0, 0, 0xCD, 0x80];
+        let file = elf::tests::build(USER_BASE, &code, 64);
+        assert_eq!(exec(&file, &[b"argc", b"a", b"b"], &[b"PWD=/"]), Ok(3));
+
+        let outside = elf::tests::build(0x0010_0000, &code, 0);
+        assert_eq!(
+            exec(&outside, &[b"argc"], &[]),
+            Err("Segment outside the user program area")
+        );
+        assert!(!is_running());
+    }
+
+    /// `make test` boots with hello.elf as a module; its name and contents
+    /// must survive the allocator handing out frames during boot
+    pub fn boot_module_runs() {
+        let info = multiboot::boot_info().expect("no boot info");
+        let module = info
+            .modules()
+            .find(|m| m.file_name() == b"hello.elf")
+            .expect("hello.elf module missing");
+        assert_eq!(exec(module.data(), &[b"hello.elf", b"test"], &[]), Ok(0));
+    }
+}
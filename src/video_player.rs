@@ -1,6 +1,9 @@
 use crate::writer::Writer;
 use crate::vga_colors::Color;
 use crate::idt;
+use crate::process;
+use crate::thread;
+use crate::clock::{Duration, Instant};
 
 const VGA_BUFFER: usize = 0xb8000;
 const VGA_WIDTH: usize = 80;
@@ -122,10 +125,13 @@ impl VideoPlayer {
         idt::flush_buffer();
         self.clear_screen();
         
-        let ticks_per_frame = 100 / self.target_fps;
-        let mut last_tick = idt::get_ticks();
+        let frame_time = Duration::from_secs(1) / self.target_fps;
+        let mut next_frame = Instant::now();
         
         loop {
+            if process::interrupted() {
+                return;
+            }
             while let Some(scancode) = idt::get_scancode() {
                 if scancode & 0x80 != 0 {
                     continue; 
@@ -149,9 +155,14 @@ impl VideoPlayer {
                 }
             }
             
-            let current_tick = idt::get_ticks();
-            if self.playing && current_tick.wrapping_sub(last_tick) >= ticks_per_frame {
-                last_tick = current_tick;
+            let now = Instant::now();
+            if self.playing && now >= next_frame {
+                // Advance from the schedule, not from `now`, so wake-up
+                // jitter does not add up into drift
+                next_frame = next_frame + frame_time;
+                if next_frame < now {
+                    next_frame = now + frame_time;
+                }
                 
                 if let Some(frame_data) = self.get_frame_data(self.current_frame) {
                     self.draw_frame(frame_data);
@@ -164,7 +175,7 @@ impl VideoPlayer {
                 }
             }
             
-            idt::wait_for_interrupt();
+            thread::idle();
         }
     }
 }
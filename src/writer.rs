@@ -1,5 +1,8 @@
+use crate::serial;
+use crate::sync::{SpinLock, SpinLockGuard};
 use crate::vga_colors::{Color, color_code};
 use core::arch::asm;
+use core::fmt;
 
 const VGA_BUFFER: usize = 0xb8000;
 const VGA_WIDTH: usize = 80;
@@ -42,6 +45,10 @@ impl Writer {
     }
 
     pub fn write_byte(&mut self, byte: u8) {
+        if serial::is_mirroring() {
+            serial::write_bytes(&[byte]);
+        }
+
         match byte {
             b'\n' => self.newline(),
             byte => {
@@ -117,6 +124,10 @@ impl Writer {
         }
         self.col = 0;
         self.row = 0;
+
+        if serial::is_mirroring() {
+            serial::write_str("\x1b[2J\x1b[H");
+        }
     }
 
     pub fn set_color(&mut self, fg: Color, bg: Color) {
@@ -138,6 +149,72 @@ impl Writer {
     }
 }
 
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        Writer::write_str(self, s);
+        Ok(())
+    }
+}
+
+/// The text screen the shell and every process print to
+static CONSOLE: SpinLock<Writer> = SpinLock::new(Writer::new(color_code(Color::White, Color::Black)));
+
+/// Locks the console, for output that must not be split up, such as a
+/// colour change and the text it applies to. Must not block while held
+pub fn console() -> SpinLockGuard<'static, Writer> {
+    CONSOLE.lock()
+}
+
+/// A handle on the console for code that keeps printing over a long time
+/// and may block in between, like the shell. Each call locks the console
+/// only for itself, so other threads' output can land between calls
+pub struct Console;
+
+impl Console {
+    pub fn enable_cursor(&self) {
+        console().enable_cursor();
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        console().write_byte(byte);
+    }
+
+    pub fn write_str(&mut self, s: &str) {
+        console().write_str(s);
+    }
+
+    pub fn write_bytes(&mut self, s: &[u8]) {
+        console().write_bytes(s);
+    }
+
+    pub fn clear(&mut self) {
+        console().clear();
+    }
+
+    pub fn set_color(&mut self, fg: Color, bg: Color) {
+        console().set_color(fg, bg);
+    }
+
+    pub fn get_col(&self) -> usize {
+        console().get_col()
+    }
+
+    pub fn get_row(&self) -> usize {
+        console().get_row()
+    }
+
+    pub fn set_position(&mut self, col: usize, row: usize) {
+        console().set_position(col, row);
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        Console::write_str(self, s);
+        Ok(())
+    }
+}
+
 unsafe fn outb(port: u16, value: u8) {
     asm!(
         "out dx, al",
@@ -146,3 +223,53 @@ unsafe fn outb(port: u16, value: u8) {
         options(nostack)
     );
 }
+
+#[cfg(kernel_test)]
+pub mod tests {
+    use super::*;
+
+    fn vga_cell(col: usize, row: usize) -> (u8, u8) {
+        let offset = (row * VGA_WIDTH + col) * 2;
+        unsafe {
+            let vga = VGA_BUFFER as *const u8;
+            (*vga.add(offset), *vga.add(offset + 1))
+        }
+    }
+
+    pub fn write_byte_updates_vga_and_cursor() {
+        let color = color_code(Color::Yellow, Color::Blue);
+        let mut writer = Writer::new(color);
+        writer.set_position(10, 5);
+        writer.write_str("Hi");
+
+        assert_eq!(vga_cell(10, 5), (b'H', color));
+        assert_eq!(vga_cell(11, 5), (b'i', color));
+        assert_eq!((writer.get_col(), writer.get_row()), (12, 5));
+
+        // Non-printable bytes are shown as a block
+        writer.write_bytes(&[0x01]);
+        assert_eq!(vga_cell(12, 5).0, 0xfe);
+    }
+
+    pub fn newline_and_scroll() {
+        let mut writer = Writer::new(color_code(Color::White, Color::Black));
+        writer.set_position(0, VGA_HEIGHT - 1);
+        writer.write_str("X\n");
+
+        assert_eq!(writer.get_row(), VGA_HEIGHT - 1);
+        assert_eq!(writer.get_col(), 0);
+        assert_eq!(vga_cell(0, VGA_HEIGHT - 2).0, b'X');
+        assert_eq!(vga_cell(0, VGA_HEIGHT - 1).0, b' ');
+    }
+
+    pub fn console_handles_share_one_cursor() {
+        let (col, row) = (console().get_col(), console().get_row());
+        let mut first = Console;
+        let mut second = Console;
+        first.set_position(0, 3);
+        second.write_str("ab");
+        assert_eq!(first.get_col(), 2);
+        assert_eq!(vga_cell(1, 3).0, b'b');
+        first.set_position(col, row);
+    }
+}
@@ -0,0 +1,63 @@
+# A statically linked user program, loaded with `exec hello.elf [args]`.
+# System calls are `int $0x80` with the number in %eax, arguments in %ebx,
+# %ecx and %edx, and the result in %eax; see syscall.rs for the table
+
+.set SYS_EXIT, 0
+.set SYS_WRITE, 1
+.set STDOUT, 1
+
+.section .rodata
+greeting:
+	.ascii "Hello from an ELF program!\nArguments:"
+.set GREETING_LEN, . - greeting
+space:
+	.ascii " "
+newline:
+	.ascii "\n"
+
+.section .text
+.global _start
+.type _start, @function
+_start:
+	mov $SYS_WRITE, %eax
+	mov $STDOUT, %ebx
+	mov $greeting, %ecx
+	mov $GREETING_LEN, %edx
+	int $0x80
+
+	# The kernel leaves argc at the top of the stack, the argv pointers
+	# above it
+	mov (%esp), %esi
+	lea 4(%esp), %edi
+1:	test %esi, %esi
+	jz 3f
+
+	mov $SYS_WRITE, %eax
+	mov $space, %ecx
+	mov $1, %edx
+	int $0x80
+
+	mov (%edi), %ecx
+	xor %edx, %edx
+2:	cmpb $0, (%ecx,%edx)
+	je 4f
+	inc %edx
+	jmp 2b
+4:	mov $SYS_WRITE, %eax
+	int $0x80
+
+	add $4, %edi
+	dec %esi
+	jmp 1b
+
+3:	mov $SYS_WRITE, %eax
+	mov $newline, %ecx
+	mov $1, %edx
+	int $0x80
+
+	mov $SYS_EXIT, %eax
+	xor %ebx, %ebx
+	int $0x80
+.size _start, . - _start
+
+.section .note.GNU-stack, "", @progbits